@@ -0,0 +1,496 @@
+// Headless tranzit client for servers, SSH sessions and scripts.
+// Talks the same protocol as the GUI, so both can send to each other.
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tranzit_lib::events::{ChannelSink, Event, EventSink};
+use tranzit_lib::history::{HistoryQuery, Outcome};
+use tranzit_lib::http_requests::{
+    self, DeviceInfo, FileUploadInfo, TransferResponse, TransferStatus,
+};
+use tranzit_lib::mdns::Peer;
+use tranzit_lib::messages::Message;
+use tranzit_lib::network;
+use tranzit_lib::progress::Direction;
+use tranzit_lib::{Tranzit, TranzitConfig, DEFAULT_HTTPS_PORT};
+use uuid::Uuid;
+
+const USAGE: &str = "Usage:
+  tranzit-cli discover
+  tranzit-cli send <peer> <files or folders...>
+  tranzit-cli send-text <peer> <text or link>
+  tranzit-cli receive [--dir <path>] [--auto-accept]
+  tranzit-cli history [<search>]
+
+<peer> can be an ip address, hostname or device id of a discovered device.";
+
+// files listed per past transfer when not searching
+const HISTORY_FILES_SHOWN: usize = 5;
+
+// Same identifier as in tauri.conf.json, so the CLI and the GUI installed
+// on the same machine share one identity
+const APP_IDENTIFIER: &str = "org.tranzit.app";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("discover") => discover().await,
+        Some("send") if args.len() >= 3 => send(&args[1], &args[2..]).await,
+        Some("send-text") if args.len() >= 3 => send_text(&args[1], &args[2..].join(" ")).await,
+        Some("receive") => receive(&args[1..]).await,
+        Some("history") if args.len() <= 2 => history(args.get(1).cloned()),
+        _ => Err(USAGE.to_string()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn config() -> Result<TranzitConfig, String> {
+    let data_dir = dirs_next::data_dir()
+        .ok_or("Could not locate the data directory")?
+        .join(APP_IDENTIFIER);
+
+    Ok(TranzitConfig::new(data_dir))
+}
+
+// Set up the instance a command runs on, unreadable settings are pointed out
+// since defaults are used instead
+fn instance(config: TranzitConfig, events: Arc<dyn EventSink>) -> Result<Tranzit, String> {
+    let tranzit = Tranzit::new(config, events)?;
+    if let Some(e) = tranzit.settings().load_error() {
+        eprintln!("Warning: {}", e);
+    }
+
+    Ok(tranzit)
+}
+
+// Prints events and answers incoming requests on the terminal
+fn handle_event(tranzit: &Tranzit, event: Event, auto_accept: bool) {
+    let payload = event.payload;
+    match event.name.as_str() {
+        "upload-progress" | "download-progress" => {
+            let eta = match payload["eta"].as_f64() {
+                Some(eta) => format!("{:.0}s left", eta),
+                None => "-".to_string(),
+            };
+            print!(
+                "\r{} {:.0}% {:.1} MB/s {}\x1b[K",
+                payload["filename"].as_str().unwrap_or_default(),
+                payload["percent"].as_f64().unwrap_or_default(),
+                payload["speed"].as_f64().unwrap_or_default() / 1_000_000.0,
+                eta
+            );
+            let _ = std::io::stdout().flush();
+        }
+        "hashing-progress" => {
+            let percent = payload["percent"].as_f64().unwrap_or_default();
+            print!("\rHashing files {:.0}%\x1b[K", percent);
+            if percent >= 100.0 {
+                println!();
+            }
+            let _ = std::io::stdout().flush();
+        }
+        "file-transfer-request" => {
+            let id = payload["id"].as_str().unwrap_or_default().to_string();
+            let sender = payload["data"]["sys_info"]["hostname"]
+                .as_str()
+                .unwrap_or("unknown")
+                .to_string();
+
+            println!("Incoming files from {}:", sender);
+            for file in payload["data"]["files_info"]
+                .as_array()
+                .into_iter()
+                .flatten()
+            {
+                println!(
+                    "  {} ({} bytes)",
+                    file["name"].as_str().unwrap_or_default(),
+                    file["size"]
+                );
+            }
+
+            let tranzit = tranzit.clone();
+            tokio::spawn(async move {
+                let accepted = auto_accept || confirm("Accept these files?").await;
+                let answer = if accepted { "accepted" } else { "rejected" };
+                println!("Transfer request {}", answer);
+                tranzit.respond_to_request(&id, answer.to_string(), None, None);
+            });
+        }
+        "file-transfer-auto-answered" => {
+            println!(
+                "Transfer request of trusted device {} {}",
+                payload["data"]["sys_info"]["hostname"]
+                    .as_str()
+                    .unwrap_or("unknown"),
+                if payload["accepted"].as_bool() == Some(true) {
+                    "accepted"
+                } else {
+                    "rejected"
+                }
+            );
+        }
+        "message-request" => {
+            let id = payload["id"].as_str().unwrap_or_default().to_string();
+            let sender = payload["data"]["sys_info"]["hostname"]
+                .as_str()
+                .unwrap_or("unknown")
+                .to_string();
+            let message = payload["data"]["message"].clone();
+            println!("Incoming message from {}", sender);
+
+            // like files, messages are only shown once accepted
+            let tranzit = tranzit.clone();
+            tokio::spawn(async move {
+                let accepted = auto_accept || confirm("Show this message?").await;
+                let answer = if accepted { "accepted" } else { "rejected" };
+                println!("Message {}", answer);
+                if accepted {
+                    match message["kind"].as_str() {
+                        Some("image") => println!("It is an image, images can't be shown here"),
+                        _ => println!("{}", message["content"].as_str().unwrap_or_default()),
+                    }
+                }
+                tranzit.respond_to_request(&id, answer.to_string(), None, None);
+            });
+        }
+        "transfer-cancelled" => {
+            println!(
+                "\rTransfer {} cancelled",
+                payload["transfer_id"].as_str().unwrap_or_default()
+            );
+        }
+        "pair-request" => {
+            let id = payload["id"].as_str().unwrap_or_default().to_string();
+            println!(
+                "{} wants to pair, verification code: {}",
+                payload["hostname"].as_str().unwrap_or("unknown"),
+                payload["code"].as_str().unwrap_or_default()
+            );
+
+            // pairing always needs a human to compare the codes
+            let tranzit = tranzit.clone();
+            tokio::spawn(async move {
+                let accepted = confirm("Does the code match?").await;
+                let answer = if accepted { "accepted" } else { "rejected" };
+                tranzit.respond_to_request(&id, answer.to_string(), None, None);
+            });
+        }
+        _ => {}
+    }
+}
+
+// Ask a yes/no question on stdin without blocking the async runtime
+async fn confirm(question: &str) -> bool {
+    let question = question.to_string();
+    tokio::task::spawn_blocking(move || {
+        print!("{} [y/N] ", question);
+        let _ = std::io::stdout().flush();
+
+        let mut answer = String::new();
+        let _ = std::io::stdin().lock().read_line(&mut answer);
+        matches!(answer.trim(), "y" | "Y" | "yes")
+    })
+    .await
+    .unwrap_or(false)
+}
+
+async fn discover() -> Result<(), String> {
+    let tranzit = instance(config()?, Arc::new(ChannelSink::new().0))?;
+    let peers: Vec<Peer> = tranzit.discover_mdns_services().await?;
+
+    if peers.is_empty() {
+        println!("No devices found");
+    }
+
+    for peer in peers {
+        let addr = network::socket_addr(&peer.ip, peer.port)
+            .map(|addr| addr.to_string())
+            .unwrap_or(peer.ip);
+        println!("{}\t{}\t{}\t{}", peer.hostname, addr, peer.os, peer.id);
+    }
+
+    Ok(())
+}
+
+// Find a discovered device by ip, hostname or device id
+async fn resolve_peer(
+    tranzit: &Tranzit,
+    target: &str,
+) -> Result<(String, u16, Option<String>), String> {
+    let peers = tranzit.discover_mdns_services().await?;
+
+    let found = peers.into_iter().find(|peer| {
+        peer.hostname == target || peer.id == target || peer.addresses.iter().any(|ip| ip == target)
+    });
+
+    match (found, network::socket_addr(target, DEFAULT_HTTPS_PORT)) {
+        // the address asked for, it might not be the one that was picked
+        (Some(peer), Ok(_)) => Ok((target.to_string(), peer.port, Some(peer.id))),
+        (Some(peer), Err(_)) => Ok((peer.ip, peer.port, Some(peer.id))),
+        // not advertising itself over mDNS, but we can still try the default port
+        (None, Ok(_)) => Ok((target.to_string(), DEFAULT_HTTPS_PORT, None)),
+        (None, Err(_)) => Err(format!("Could not find device {}", target)),
+    }
+}
+
+async fn send(target: &str, paths: &[String]) -> Result<(), String> {
+    let (sink, events) = ChannelSink::new();
+    let tranzit = instance(config()?, Arc::new(sink))?;
+    spawn_event_handler(&tranzit, events, false);
+
+    // folders are sent as all the files in them, keeping their structure
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            for (file_path, relative_path) in http_requests::files_in_dir(path)? {
+                files.push(local_file(&file_path, Some(relative_path))?);
+            }
+        } else {
+            files.push(local_file(path, None)?);
+        }
+    }
+
+    let (ip, port, peer_id) = resolve_peer(&tranzit, target).await?;
+    let sys_info = tranzit.sys_info();
+
+    println!("Waiting for {} to accept the transfer...", target);
+
+    let response = tranzit
+        .file_transfer_request(
+            &ip,
+            port,
+            &files,
+            DeviceInfo {
+                hostname: sys_info.hostname.unwrap_or_else(|| "unknown".into()),
+                os_type: sys_info.os_type.unwrap_or_else(|| "unknown".into()),
+            },
+            peer_id.as_deref(),
+        )
+        .await?;
+
+    let TransferResponse::Accepted(transfer) = response else {
+        return Err("File transfer request rejected".into());
+    };
+
+    // Ctrl+C cancels the transfer on both ends
+    let transfer_id = transfer.transfer_id.clone();
+    let upload = tranzit.upload_files(files, ip, port, transfer, peer_id);
+    tokio::pin!(upload);
+
+    let result = tokio::select! {
+        result = &mut upload => result,
+        _ = tokio::signal::ctrl_c() => {
+            println!("\nCancelling...");
+            tranzit.cancel_transfer(&transfer_id).await?;
+            upload.await
+        }
+    };
+
+    for file in &result.files {
+        match &file.status {
+            TransferStatus::Completed => println!("\r{} sent", file.filename),
+            TransferStatus::Failed { reason } => {
+                println!("\rFailed to send {}: {}", file.filename, reason)
+            }
+            TransferStatus::Declined => println!("\r{} declined by the receiver", file.filename),
+            _ => {}
+        }
+    }
+
+    match result.status {
+        TransferStatus::Completed => Ok(()),
+        TransferStatus::Failed { reason } => Err(format!("Transfer failed: {}", reason)),
+        TransferStatus::Cancelled => Err("Transfer cancelled".into()),
+        status => Err(format!("Transfer ended as {:?}", status)),
+    }
+}
+
+async fn send_text(target: &str, text: &str) -> Result<(), String> {
+    let tranzit = instance(config()?, Arc::new(ChannelSink::new().0))?;
+    let (ip, port, peer_id) = resolve_peer(&tranzit, target).await?;
+    let sys_info = tranzit.sys_info();
+
+    let accepted = tranzit
+        .send_message(
+            &ip,
+            port,
+            Message::text(text, false),
+            DeviceInfo {
+                hostname: sys_info.hostname.unwrap_or_else(|| "unknown".into()),
+                os_type: sys_info.os_type.unwrap_or_else(|| "unknown".into()),
+            },
+            peer_id.as_deref(),
+        )
+        .await?;
+
+    if !accepted {
+        return Err("Message rejected".into());
+    }
+    println!("Message sent");
+
+    Ok(())
+}
+
+fn local_file(path: &Path, relative_path: Option<String>) -> Result<FileUploadInfo, String> {
+    let metadata = std::fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", path.display()));
+    }
+
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("Unknown")
+        .to_string();
+
+    Ok(FileUploadInfo {
+        file_path: path.to_string_lossy().to_string(),
+        file_uuid: Uuid::new_v4().to_string(),
+        name,
+        relative_path,
+    })
+}
+
+async fn receive(args: &[String]) -> Result<(), String> {
+    let mut config = config()?;
+    let mut auto_accept = false;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--auto-accept" => auto_accept = true,
+            "--dir" => {
+                let dir = args.next().ok_or("--dir needs a path")?;
+                std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+                config.download_dir = Some(PathBuf::from(dir));
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let (sink, events) = ChannelSink::new();
+    let tranzit = instance(config, Arc::new(sink))?;
+    spawn_event_handler(&tranzit, events, auto_accept);
+
+    tranzit.start_heartbeat_responder().await?;
+    tranzit.start_http_server().await?;
+    tranzit.start_mdns_responder();
+
+    println!("Waiting for files, press Ctrl+C to stop");
+
+    let result = tokio::signal::ctrl_c()
+        .await
+        .map_err(|e| format!("Failed to listen for Ctrl+C: {}", e));
+
+    tranzit.shutdown();
+
+    result
+}
+
+// List past transfers, newest first, e.g. to check whether a file was sent to someone already
+fn history(search: Option<String>) -> Result<(), String> {
+    let tranzit = instance(config()?, Arc::new(ChannelSink::new().0))?;
+    let search = search.map(|search| search.to_lowercase());
+    let entries = tranzit.transfer_history().query(&HistoryQuery {
+        search: search.clone(),
+        ..Default::default()
+    });
+
+    if entries.is_empty() {
+        println!("No transfers found");
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    for entry in entries {
+        let direction = match entry.direction {
+            Direction::Send => "sent to",
+            Direction::Receive => "received from",
+        };
+        let peer = entry
+            .peer
+            .hostname
+            .or(entry.peer.ip)
+            .or(entry.peer.device_id)
+            .unwrap_or_else(|| "unknown".into());
+        let outcome = match entry.outcome {
+            Outcome::InProgress => "in progress".to_string(),
+            Outcome::Completed => "completed".to_string(),
+            Outcome::Failed { reason } => format!("failed: {}", reason),
+            Outcome::Cancelled => "cancelled".to_string(),
+            Outcome::Declined => "declined".to_string(),
+        };
+        let size: u64 = entry.files.iter().map(|file| file.size).sum();
+
+        println!(
+            "{} ago\t{} {}\t{}\t{} file(s), {:.1} MB\t{}",
+            age(now.saturating_sub(entry.started_at) / 1000),
+            direction,
+            peer,
+            outcome,
+            entry.files.len(),
+            size as f64 / 1_000_000.0,
+            entry.transfer_id
+        );
+        // the files that matched, or the first few
+        let files: Vec<_> = match &search {
+            Some(search) => entry
+                .files
+                .into_iter()
+                .filter(|file| {
+                    file.name.to_lowercase().contains(search)
+                        || file
+                            .relative_path
+                            .as_ref()
+                            .is_some_and(|path| path.to_lowercase().contains(search))
+                        || file.hash.starts_with(search)
+                })
+                .collect(),
+            None => entry.files.into_iter().take(HISTORY_FILES_SHOWN).collect(),
+        };
+        for file in files {
+            let path = file
+                .path
+                .map(|path| path.display().to_string())
+                .unwrap_or_default();
+            println!(
+                "  {}\t{}\t{}",
+                file.relative_path.unwrap_or(file.name),
+                file.hash.chars().take(12).collect::<String>(),
+                path
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Rough age like "5m" or "3d"
+fn age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+fn spawn_event_handler(tranzit: &Tranzit, mut events: UnboundedReceiver<Event>, auto_accept: bool) {
+    let tranzit = tranzit.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            handle_event(&tranzit, event, auto_accept);
+        }
+    });
+}
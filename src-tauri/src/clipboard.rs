@@ -0,0 +1,111 @@
+use crate::messages::{Message, MessageKind, MAX_MESSAGE_SIZE};
+use base64::Engine as _;
+use image::{ImageFormat, ImageReader, RgbaImage};
+use std::io::Cursor;
+use tauri::{image::Image, AppHandle};
+use tauri_plugin_clipboard_manager::ClipboardExt;
+
+// What is on the clipboard as a message, text wins over an image
+#[tauri::command]
+pub fn read_clipboard(app: AppHandle) -> Result<Message, String> {
+    let clipboard = app.clipboard();
+    if let Ok(text) = clipboard.read_text() {
+        if !text.trim().is_empty() {
+            return Ok(Message::text(&text, true));
+        }
+    }
+
+    let image = clipboard
+        .read_image()
+        .map_err(|_| "There is nothing on the clipboard".to_string())?;
+    let rgba = RgbaImage::from_raw(image.width(), image.height(), image.rgba().to_vec())
+        .ok_or("Unsupported image on the clipboard")?;
+    let mut png = Vec::new();
+    rgba.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode the clipboard image: {}", e))?;
+
+    Ok(Message {
+        kind: MessageKind::Image,
+        content: base64::engine::general_purpose::STANDARD.encode(png),
+        clipboard: true,
+    })
+}
+
+// Put a received message on the clipboard
+#[tauri::command]
+pub fn write_clipboard(app: AppHandle, message: Message) -> Result<(), String> {
+    let clipboard = app.clipboard();
+    match message.kind {
+        MessageKind::Text | MessageKind::Url => clipboard
+            .write_text(message.content)
+            .map_err(|e| format!("Failed to copy: {}", e)),
+        MessageKind::Image => {
+            let rgba = decode_image(&message.content)?;
+            let (width, height) = rgba.dimensions();
+
+            clipboard
+                .write_image(&Image::new_owned(rgba.into_raw(), width, height))
+                .map_err(|e| format!("Failed to copy: {}", e))
+        }
+    }
+}
+
+// Pixels of a received base64 encoded png, refused when they would take more memory
+// than a message may
+fn decode_image(content: &str) -> Result<RgbaImage, String> {
+    let png = base64::engine::general_purpose::STANDARD
+        .decode(content)
+        .map_err(|e| format!("Invalid image: {}", e))?;
+    // the size comes from the peer, a small image can claim to be huge and
+    // decoding allocates for what it claims before anything fails
+    let (width, height) = ImageReader::with_format(Cursor::new(&png), ImageFormat::Png)
+        .into_dimensions()
+        .map_err(|e| format!("Invalid image: {}", e))?;
+    if u64::from(width) * u64::from(height) * 4 > MAX_MESSAGE_SIZE {
+        return Err(format!(
+            "Image of {}x{} is too large to copy",
+            width, height
+        ));
+    }
+
+    Ok(image::load_from_memory_with_format(&png, ImageFormat::Png)
+        .map_err(|e| format!("Invalid image: {}", e))?
+        .to_rgba8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayImage;
+
+    fn encoded_png(image: &GrayImage) -> String {
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        base64::engine::general_purpose::STANDARD.encode(png)
+    }
+
+    #[test]
+    fn received_image_is_decoded() {
+        let rgba = decode_image(&encoded_png(&GrayImage::new(3, 2))).unwrap();
+        assert_eq!(rgba.dimensions(), (3, 2));
+    }
+
+    #[test]
+    fn image_too_large_to_copy_is_refused() {
+        // compresses to a few kilobytes but takes just over the limit as rgba
+        let image = GrayImage::new(2049, 2048);
+        assert_eq!(
+            decode_image(&encoded_png(&image)),
+            Err("Image of 2049x2048 is too large to copy".to_string())
+        );
+    }
+
+    #[test]
+    fn anything_but_a_png_is_refused() {
+        assert!(decode_image("not base64!").is_err());
+        let text = base64::engine::general_purpose::STANDARD.encode("hello");
+        assert!(decode_image(&text).is_err());
+    }
+}
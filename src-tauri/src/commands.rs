@@ -0,0 +1,238 @@
+// Thin tauri command wrappers around the Tranzit instance managed by the app,
+// the actual work happens in the networking modules
+use crate::debug_log;
+use crate::events::EventSink;
+use crate::history::{HistoryEntry, HistoryQuery};
+use crate::http_requests::{
+    AcceptedTransfer, DeviceInfo, FileUploadInfo, TransferResponse, TransferResult,
+};
+use crate::http_server::FileChoice;
+use crate::mdns::Peer;
+use crate::messages::Message;
+use crate::network;
+use crate::pairing::PairedDevice;
+use crate::settings::Settings;
+use crate::sysinfo::SysInfo;
+use crate::tranzit::Tranzit;
+use std::path::PathBuf;
+use tauri::{AppHandle, Emitter, Runtime, State};
+
+// Forward events of the instance to the webview
+impl<R: Runtime> EventSink for AppHandle<R> {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        if let Err(e) = Emitter::emit(self, event, payload) {
+            debug_log!("Failed to emit {} event: {}", event, e);
+        }
+    }
+}
+
+#[tauri::command]
+pub fn get_sys_info(tranzit: State<'_, Tranzit>) -> SysInfo {
+    tranzit.sys_info()
+}
+
+#[tauri::command]
+pub fn start_mdns_responder(tranzit: State<'_, Tranzit>) {
+    tranzit.start_mdns_responder();
+}
+
+#[tauri::command]
+pub fn stop_mdns_responder(tranzit: State<'_, Tranzit>) {
+    tranzit.stop_mdns_responder();
+}
+
+#[tauri::command]
+pub fn restart_mdns_responder(tranzit: State<'_, Tranzit>) {
+    tranzit.restart_mdns_responder();
+}
+
+#[tauri::command]
+pub async fn discover_mdns_services(tranzit: State<'_, Tranzit>) -> Result<Vec<Peer>, String> {
+    tranzit.discover_mdns_services().await
+}
+
+#[tauri::command]
+pub async fn start_http_server(tranzit: State<'_, Tranzit>) -> Result<(), String> {
+    tranzit.start_http_server().await.map(|_| ())
+}
+
+#[tauri::command]
+pub fn respond_to_request(
+    tranzit: State<'_, Tranzit>,
+    id: String,
+    data: String,
+    receive_dir: Option<PathBuf>,
+    files: Option<Vec<FileChoice>>,
+) {
+    tranzit.respond_to_request(&id, data, receive_dir, files);
+}
+
+#[tauri::command]
+pub async fn add_device(tranzit: State<'_, Tranzit>, device: Peer) -> Result<(), String> {
+    tranzit.add_device(&device).await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn remove_device(tranzit: State<'_, Tranzit>, id: String) -> Result<(), String> {
+    tranzit.remove_device(&id).await;
+    Ok(())
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn assisted_discovery(
+    tranzit: State<'_, Tranzit>,
+    device_ip: String,
+    service_type: String,
+    hostname: String,
+    os_type: String,
+    port: u16,
+    id: String,
+    peer_id: Option<String>,
+) -> Result<String, String> {
+    // our address on the interface the device is reached through
+    let ip = network::local_ip_for(&network::socket_addr(&device_ip, port)?)
+        .ok_or_else(|| format!("No route to {}", device_ip))?;
+    let own_info = Peer {
+        name: hostname.clone(),
+        addresses: vec![ip.clone()],
+        ip,
+        port,
+        hostname,
+        service_type,
+        os: os_type,
+        id,
+    };
+
+    tranzit
+        .assisted_discovery(&device_ip, port, &own_info, peer_id.as_deref())
+        .await
+}
+
+#[tauri::command]
+pub async fn file_transfer_request(
+    tranzit: State<'_, Tranzit>,
+    ip: String,
+    port: u16,
+    selected_files: Vec<FileUploadInfo>,
+    sys_info: DeviceInfo,
+    peer_id: Option<String>,
+) -> Result<TransferResponse, String> {
+    tranzit
+        .file_transfer_request(&ip, port, &selected_files, sys_info, peer_id.as_deref())
+        .await
+}
+
+// Text typed by the user as the message it is sent as, links are told apart here
+#[tauri::command]
+pub fn text_message(text: String) -> Message {
+    Message::text(&text, false)
+}
+
+#[tauri::command]
+pub async fn send_message(
+    tranzit: State<'_, Tranzit>,
+    ip: String,
+    port: u16,
+    message: Message,
+    sys_info: DeviceInfo,
+    peer_id: Option<String>,
+) -> Result<bool, String> {
+    tranzit
+        .send_message(&ip, port, message, sys_info, peer_id.as_deref())
+        .await
+}
+
+#[tauri::command]
+pub async fn upload_files(
+    tranzit: State<'_, Tranzit>,
+    files: Vec<FileUploadInfo>,
+    ip: String,
+    port: u16,
+    transfer: AcceptedTransfer,
+    peer_id: Option<String>,
+) -> Result<TransferResult, String> {
+    Ok(tranzit
+        .upload_files(files, ip, port, transfer, peer_id)
+        .await)
+}
+
+#[tauri::command]
+pub async fn cancel_transfer(
+    tranzit: State<'_, Tranzit>,
+    transfer_id: String,
+) -> Result<(), String> {
+    tranzit.cancel_transfer(&transfer_id).await
+}
+
+#[tauri::command]
+pub fn pause_transfer(tranzit: State<'_, Tranzit>, transfer_id: String) -> Result<(), String> {
+    tranzit.pause_transfer(&transfer_id)
+}
+
+#[tauri::command]
+pub fn resume_transfer(tranzit: State<'_, Tranzit>, transfer_id: String) -> Result<(), String> {
+    tranzit.resume_transfer(&transfer_id)
+}
+
+#[tauri::command]
+pub fn set_transfer_rate_limit(
+    tranzit: State<'_, Tranzit>,
+    transfer_id: String,
+    limit: Option<u64>,
+) -> Result<(), String> {
+    tranzit.set_transfer_rate_limit(&transfer_id, limit)
+}
+
+#[tauri::command]
+pub async fn pair_device(tranzit: State<'_, Tranzit>, ip: String, port: u16) -> Result<(), String> {
+    tranzit.pair_device(&ip, port).await
+}
+
+#[tauri::command]
+pub fn get_paired_devices(tranzit: State<'_, Tranzit>) -> Vec<PairedDevice> {
+    tranzit.paired_devices().list()
+}
+
+#[tauri::command]
+pub fn unpair_device(tranzit: State<'_, Tranzit>, device_id: String) {
+    tranzit.paired_devices().unpair(&device_id);
+}
+
+#[tauri::command]
+pub fn get_settings(tranzit: State<'_, Tranzit>) -> Settings {
+    tranzit.settings().get()
+}
+
+// Why the saved settings couldn't be loaded, none when they were fine
+#[tauri::command]
+pub fn get_settings_load_error(tranzit: State<'_, Tranzit>) -> Option<String> {
+    tranzit.settings().load_error().map(String::from)
+}
+
+#[tauri::command]
+pub fn set_settings(tranzit: State<'_, Tranzit>, settings: Settings) -> Result<(), String> {
+    tranzit.settings().set(settings.clone())?;
+    // running transfers pick up changed speed limits right away
+    tranzit.rate_limits().apply(&settings);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_transfer_history(
+    tranzit: State<'_, Tranzit>,
+    query: Option<HistoryQuery>,
+) -> Vec<HistoryEntry> {
+    tranzit.transfer_history().query(&query.unwrap_or_default())
+}
+
+#[tauri::command]
+pub fn delete_transfer_history(tranzit: State<'_, Tranzit>, transfer_ids: Vec<String>) -> usize {
+    tranzit.transfer_history().delete(&transfer_ids)
+}
+
+#[tauri::command]
+pub fn clear_transfer_history(tranzit: State<'_, Tranzit>) {
+    tranzit.transfer_history().clear();
+}
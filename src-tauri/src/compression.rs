@@ -0,0 +1,318 @@
+use bytes::{Buf, Bytes};
+use flate2::write::DeflateEncoder;
+use flate2::{Decompress, FlushDecompress, Status};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+// Compression of the uploads of a transfer, the sender offers what it supports
+// in the transfer request and the receiver picks one when accepting it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Deflate,
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Deflate => "deflate",
+        }
+    }
+}
+
+// Everything we can compress and decompress, best first
+pub const SUPPORTED: &[Compression] = &[Compression::Deflate];
+
+// Level 1, anything higher can't keep up with a fast local network
+const LEVEL: flate2::Compression = flate2::Compression::fast();
+
+// Compressed uploads are unpacked in pieces of at most this size, so a few
+// bytes can't blow up into gigabytes in memory before the size checks see them
+const DECOMPRESSED_PIECE_SIZE: usize = 64 * 1024;
+
+// Pick the compression for a transfer out of the ones the sender offered.
+// They are plain names, so a newer sender can offer ones we don't know yet.
+pub fn negotiate(offered: &[String]) -> Option<Compression> {
+    SUPPORTED
+        .iter()
+        .copied()
+        .find(|compression| offered.iter().any(|name| name == compression.as_str()))
+}
+
+// Images, videos, archives and the like are compressed already,
+// compressing them again only costs time
+pub fn worth_compressing(mime_type: &str) -> bool {
+    let (kind, subtype) = mime_type.split_once('/').unwrap_or((mime_type, ""));
+
+    match kind {
+        // uncompressed formats still shrink
+        "image" => matches!(subtype, "bmp" | "svg+xml" | "tiff" | "x-icon"),
+        "audio" => matches!(subtype, "wav" | "x-wav" | "aiff" | "x-aiff"),
+        "video" => false,
+        "font" => !subtype.starts_with("woff"),
+        _ => {
+            !matches!(
+                subtype,
+                "zip"
+                    | "gzip"
+                    | "x-gzip"
+                    | "zstd"
+                    | "x-bzip2"
+                    | "x-xz"
+                    | "x-lzip"
+                    | "x-7z-compressed"
+                    | "vnd.rar"
+                    | "x-rar-compressed"
+                    | "epub+zip"
+                    | "java-archive"
+                    | "vnd.android.package-archive"
+                    | "pdf"
+            ) && !subtype.starts_with("vnd.openxmlformats")
+                && !subtype.starts_with("vnd.oasis.opendocument")
+        }
+    }
+}
+
+// Compress the bytes of an upload as they go out
+pub fn compress_stream<S>(
+    stream: S,
+    compression: Compression,
+) -> impl Stream<Item = io::Result<Bytes>> + Unpin
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    let encoder = match compression {
+        Compression::Deflate => DeflateEncoder::new(Vec::new(), LEVEL),
+    };
+
+    Box::pin(stream::unfold(
+        (stream, Some(encoder)),
+        |(mut stream, encoder)| async move {
+            let mut encoder = encoder?;
+            loop {
+                let compressed = match stream.next().await {
+                    Some(Ok(data)) => encoder
+                        .write_all(&data)
+                        .map(|_| std::mem::take(encoder.get_mut())),
+                    Some(Err(e)) => return Some((Err(e), (stream, None))),
+                    // the encoder holds on to the last bit until it is finished
+                    None => return Some((encoder.finish().map(Bytes::from), (stream, None))),
+                };
+
+                match compressed {
+                    // not enough input for the encoder to put anything out yet
+                    Ok(data) if data.is_empty() => continue,
+                    Ok(data) => return Some((Ok(Bytes::from(data)), (stream, Some(encoder)))),
+                    Err(e) => return Some((Err(e), (stream, None))),
+                }
+            }
+        },
+    ))
+}
+
+// Unpacks a compressed upload while it comes in
+struct Decompressor<S> {
+    body: S,
+    // not set when the upload isn't compressed, its bytes are passed on as they are
+    decompress: Option<Decompress>,
+    input: Bytes,
+    body_ended: bool,
+    done: bool,
+}
+
+impl<S, B, E> Decompressor<S>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Buf,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    async fn next_piece(&mut self) -> Option<io::Result<Bytes>> {
+        let Some(decompress) = self.decompress.as_mut() else {
+            return self.body.next().await.map(|data| {
+                data.map(|mut data| data.copy_to_bytes(data.remaining()))
+                    .map_err(io::Error::other)
+            });
+        };
+
+        while !self.done {
+            if self.input.is_empty() && !self.body_ended {
+                match self.body.next().await {
+                    Some(Ok(mut data)) => self.input = data.copy_to_bytes(data.remaining()),
+                    Some(Err(e)) => {
+                        self.done = true;
+                        return Some(Err(io::Error::other(e)));
+                    }
+                    None => self.body_ended = true,
+                }
+            }
+
+            let flush = if self.body_ended {
+                FlushDecompress::Finish
+            } else {
+                FlushDecompress::None
+            };
+            let mut output = Vec::with_capacity(DECOMPRESSED_PIECE_SIZE);
+            let total_in = decompress.total_in();
+            let status = decompress.decompress_vec(&self.input, &mut output, flush);
+            let consumed = (decompress.total_in() - total_in) as usize;
+            self.input.advance(consumed);
+
+            let stuck = consumed == 0 && output.is_empty() && !self.input.is_empty();
+            match status {
+                Ok(Status::StreamEnd) => self.done = true,
+                Ok(_) if stuck || (self.body_ended && output.is_empty()) => {
+                    self.done = true;
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "compressed data ended early",
+                    )));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+                }
+            }
+
+            if !output.is_empty() {
+                return Some(Ok(Bytes::from(output)));
+            }
+        }
+
+        None
+    }
+}
+
+// The bytes of an upload, unpacked when it was sent compressed
+pub fn decompress_stream<S, B, E>(
+    body: S,
+    compression: Option<Compression>,
+) -> impl Stream<Item = io::Result<Bytes>> + Unpin
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Buf,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let decompressor = Decompressor {
+        body,
+        decompress: compression.map(|compression| match compression {
+            Compression::Deflate => Decompress::new(false),
+        }),
+        input: Bytes::new(),
+        body_ended: false,
+        done: false,
+    };
+
+    Box::pin(stream::unfold(
+        decompressor,
+        |mut decompressor| async move {
+            let piece = decompressor.next_piece().await?;
+            Some((piece, decompressor))
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pieces(data: &[u8], size: usize) -> impl Stream<Item = io::Result<Bytes>> + Unpin {
+        let pieces: Vec<io::Result<Bytes>> = data
+            .chunks(size)
+            .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+            .collect();
+        stream::iter(pieces)
+    }
+
+    async fn compress(data: &[u8]) -> Vec<u8> {
+        let compressed: Vec<Bytes> = compress_stream(pieces(data, 1000), Compression::Deflate)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        compressed.concat()
+    }
+
+    // Unpacked bytes and the size of the largest piece they came in
+    async fn decompress(data: &[u8], piece_size: usize) -> io::Result<(Vec<u8>, usize)> {
+        let mut stream = decompress_stream(pieces(data, piece_size), Some(Compression::Deflate));
+        let mut output = Vec::new();
+        let mut largest = 0;
+        while let Some(piece) = stream.next().await {
+            let piece = piece?;
+            largest = largest.max(piece.len());
+            output.extend_from_slice(&piece);
+        }
+        Ok((output, largest))
+    }
+
+    #[tokio::test]
+    async fn compressed_uploads_unpack_to_the_same_bytes() {
+        let data: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("line {} of {}\n", i, i * 7919 % 1013).into_bytes())
+            .collect();
+        let compressed = compress(&data).await;
+        assert!(compressed.len() < data.len());
+
+        // however the compressed bytes come in
+        for piece_size in [1, 7, 4096, compressed.len()] {
+            let (unpacked, _) = decompress(&compressed, piece_size).await.unwrap();
+            assert_eq!(unpacked, data, "pieces of {} bytes", piece_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_upload_round_trips() {
+        let compressed = compress(&[]).await;
+        assert!(!compressed.is_empty());
+        let (unpacked, _) = decompress(&compressed, 10).await.unwrap();
+        assert!(unpacked.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unpacked_pieces_are_capped() {
+        // 32 MB of zeros compress to a few dozen KB
+        let data = vec![0u8; 32 * 1024 * 1024];
+        let compressed = compress(&data).await;
+        assert!(compressed.len() < 256 * 1024);
+
+        let (unpacked, largest) = decompress(&compressed, compressed.len()).await.unwrap();
+        assert!(largest <= DECOMPRESSED_PIECE_SIZE);
+        assert_eq!(unpacked, data);
+    }
+
+    #[tokio::test]
+    async fn truncated_upload_fails() {
+        let data: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let compressed = compress(&data).await;
+
+        let result = decompress(&compressed[..compressed.len() / 2], 1000).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn garbage_fails() {
+        let result = decompress(&[0xff; 100], 10).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn uncompressed_uploads_pass_through() {
+        let unpacked: Vec<Bytes> = decompress_stream(pieces(b"hello world", 3), None)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(unpacked.concat(), b"hello world");
+    }
+
+    #[test]
+    fn only_known_compressions_are_picked() {
+        assert_eq!(negotiate(&["deflate".into()]), Some(Compression::Deflate));
+        assert_eq!(
+            negotiate(&["zstd".into(), "deflate".into()]),
+            Some(Compression::Deflate)
+        );
+        assert_eq!(negotiate(&["zstd".into()]), None);
+        assert_eq!(negotiate(&[]), None);
+    }
+}
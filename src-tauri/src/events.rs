@@ -0,0 +1,34 @@
+use tokio::sync::mpsc;
+
+// Receives the events emitted by a tranzit instance. The GUI forwards
+// them to the webview, the CLI prints them or acts on them directly.
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: &str, payload: serde_json::Value);
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub name: String,
+    pub payload: serde_json::Value,
+}
+
+// Sink that forwards events into a channel, handy when whoever handles the
+// events also needs the instance emitting them, e.g. to answer requests
+pub struct ChannelSink(mpsc::UnboundedSender<Event>);
+
+impl ChannelSink {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Event>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(tx), rx)
+    }
+}
+
+impl EventSink for ChannelSink {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        // the receiver being gone just means nobody is interested anymore
+        let _ = self.0.send(Event {
+            name: event.to_string(),
+            payload,
+        });
+    }
+}
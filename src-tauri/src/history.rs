@@ -0,0 +1,605 @@
+use crate::debug_log;
+use crate::http_requests::{
+    FileInfo, FileUploadInfo, TransferResponse, TransferResult, TransferStatus,
+};
+use crate::progress::Direction;
+use crate::storage;
+use crate::tranzit::Tranzit;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Oldest transfers are forgotten once there are more than this
+const MAX_ENTRIES: usize = 1000;
+
+// How a transfer or one of its files ended up, e.g. {"status": "failed", "reason": "..."}
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    InProgress,
+    Completed,
+    Failed { reason: String },
+    Cancelled,
+    // the receiver rejected the transfer request or didn't pick the file
+    Declined,
+}
+
+impl From<&TransferStatus> for Outcome {
+    fn from(status: &TransferStatus) -> Self {
+        match status {
+            TransferStatus::Completed => Outcome::Completed,
+            TransferStatus::Failed { reason } => Outcome::Failed {
+                reason: reason.clone(),
+            },
+            TransferStatus::Cancelled => Outcome::Cancelled,
+            TransferStatus::Declined => Outcome::Declined,
+            _ => Outcome::InProgress,
+        }
+    }
+}
+
+// Device on the other end of a transfer, as far as we know it
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HistoryPeer {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryFile {
+    pub file_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub relative_path: Option<String>,
+    pub size: u64,
+    // SHA-256 as lowercase hex
+    pub hash: String,
+    // where the file was sent from or saved to, unknown until a received file is saved
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+// A transfer we sent or received
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub transfer_id: String,
+    pub direction: Direction,
+    pub peer: HistoryPeer,
+    pub files: Vec<HistoryFile>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    // unix time in milliseconds
+    pub started_at: u64,
+    #[serde(default)]
+    pub finished_at: Option<u64>,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+}
+
+impl HistoryEntry {
+    fn finish(&mut self, outcome: Outcome) {
+        let now = now_ms();
+        self.outcome = outcome;
+        self.finished_at = Some(now);
+        self.duration_ms = Some(now.saturating_sub(self.started_at));
+    }
+
+    fn matches(&self, query: &HistoryQuery) -> bool {
+        if query.direction.is_some_and(|d| d != self.direction) {
+            return false;
+        }
+        if query
+            .device_id
+            .as_ref()
+            .is_some_and(|id| self.peer.device_id.as_ref() != Some(id))
+        {
+            return false;
+        }
+        if query.since.is_some_and(|since| self.started_at < since) {
+            return false;
+        }
+
+        let Some(search) = query
+            .search
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        else {
+            return true;
+        };
+        let search = search.to_lowercase();
+        let contains = |text: &str| text.to_lowercase().contains(&search);
+
+        [&self.peer.hostname, &self.peer.device_id, &self.peer.ip]
+            .into_iter()
+            .flatten()
+            .any(|text| contains(text))
+            || self.files.iter().any(|file| {
+                contains(&file.name)
+                    || file.relative_path.as_deref().is_some_and(contains)
+                    || file.hash.starts_with(&search)
+            })
+    }
+}
+
+// Filters for looking up past transfers, everything matches when nothing is set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryQuery {
+    // part of a file name or path, the device name or id, or the start of a file hash
+    pub search: Option<String>,
+    pub direction: Option<Direction>,
+    pub device_id: Option<String>,
+    // unix time in milliseconds, only transfers started since then
+    pub since: Option<u64>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+// Every transfer sent or received, persisted to a json file in the data directory
+pub struct TransferHistory {
+    // oldest first
+    entries: Mutex<Vec<HistoryEntry>>,
+    // the file is written on its own thread, which gets the json to write
+    writer: Option<(mpsc::Sender<String>, JoinHandle<()>)>,
+}
+
+impl TransferHistory {
+    // Load the history from disk, transfers that were still running when the
+    // app stopped can't go on and are marked as failed
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join("history.json");
+
+        let mut entries: Vec<HistoryEntry> = storage::load_json(&path)
+            .unwrap_or_else(|e| {
+                debug_log!("{}", e);
+                None
+            })
+            .unwrap_or_default();
+        for entry in &mut entries {
+            if entry.outcome == Outcome::InProgress {
+                entry.outcome = Outcome::Failed {
+                    reason: "Interrupted".into(),
+                };
+                for file in &mut entry.files {
+                    if file.outcome == Outcome::InProgress {
+                        file.outcome = entry.outcome.clone();
+                    }
+                }
+            }
+        }
+
+        debug_log!("📜 Loaded {} past transfer(s)", entries.len());
+
+        let (tx, rx) = mpsc::channel::<String>();
+        let writer = std::thread::spawn(move || {
+            while let Ok(mut json) = rx.recv() {
+                // only the latest history is worth writing when several changes queued up
+                while let Ok(newer) = rx.try_recv() {
+                    json = newer;
+                }
+                if let Err(e) = storage::write_atomic(&path, json.as_bytes()) {
+                    debug_log!("Failed to persist transfer history: {}", e);
+                }
+            }
+        });
+
+        Self {
+            entries: Mutex::new(entries),
+            writer: Some((tx, writer)),
+        }
+    }
+
+    // Queue the entries to be written, called with the lock held so they are
+    // written in the order they changed
+    fn persist(&self, entries: &[HistoryEntry]) {
+        let Some((tx, _)) = &self.writer else {
+            return;
+        };
+        match serde_json::to_string(entries) {
+            Ok(json) => {
+                let _ = tx.send(json);
+            }
+            Err(e) => debug_log!("Failed to serialize transfer history: {}", e),
+        }
+    }
+
+    // Add a transfer, replacing an earlier entry with the same id
+    fn record(&self, entry: HistoryEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.transfer_id != entry.transfer_id);
+        entries.push(entry);
+        if entries.len() > MAX_ENTRIES {
+            let excess = entries.len() - MAX_ENTRIES;
+            entries.drain(..excess);
+        }
+        self.persist(&entries);
+    }
+
+    // Change a transfer, it is written to disk when `f` returns true
+    fn update(&self, transfer_id: &str, f: impl FnOnce(&mut HistoryEntry) -> bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.iter_mut().find(|e| e.transfer_id == transfer_id) else {
+            return;
+        };
+        if f(entry) {
+            self.persist(&entries);
+        }
+    }
+
+    // End a transfer that is still going with the given outcome, along with its
+    // files that didn't make it
+    fn stop(&self, transfer_id: &str, outcome: Outcome) {
+        self.update(transfer_id, |entry| {
+            if entry.outcome != Outcome::InProgress {
+                return false;
+            }
+            for file in &mut entry.files {
+                if file.outcome == Outcome::InProgress {
+                    file.outcome = outcome.clone();
+                }
+            }
+            entry.finish(outcome);
+            true
+        });
+    }
+
+    // Transfers matching a query, newest first
+    pub fn query(&self, query: &HistoryQuery) -> Vec<HistoryEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|entry| entry.matches(query))
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    // Forget some transfers, returns how many there were
+    pub fn delete(&self, transfer_ids: &[String]) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|e| !transfer_ids.contains(&e.transfer_id));
+        let deleted = before - entries.len();
+        if deleted > 0 {
+            self.persist(&entries);
+            debug_log!("🗑️ Deleted {} past transfer(s)", deleted);
+        }
+
+        deleted
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        self.persist(&entries);
+        debug_log!("🗑️ Cleared the transfer history");
+    }
+}
+
+// Wait for the last changes to be written, e.g. when the CLI exits
+impl Drop for TransferHistory {
+    fn drop(&mut self) {
+        if let Some((tx, writer)) = self.writer.take() {
+            drop(tx);
+            let _ = writer.join();
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Tranzit {
+    // Remember a transfer request we sent, declined ones too
+    pub(crate) fn record_sending(
+        &self,
+        transfer_id: &str,
+        ip: &str,
+        peer_id: Option<&str>,
+        files: &[FileUploadInfo],
+        infos: &[FileInfo],
+        response: &TransferResponse,
+    ) {
+        let hostname = peer_id.and_then(|id| {
+            self.paired_devices()
+                .list()
+                .into_iter()
+                .find(|device| device.device_id == id)
+                .map(|device| device.hostname)
+        });
+        let accepted = matches!(response, TransferResponse::Accepted(_));
+        let outcome = if accepted {
+            Outcome::InProgress
+        } else {
+            Outcome::Declined
+        };
+        // the receiver may have picked only some of the files
+        let file_outcome = |file_id: &str| match response {
+            TransferResponse::Accepted(transfer) if transfer.accepts(file_id) => {
+                Outcome::InProgress
+            }
+            _ => Outcome::Declined,
+        };
+
+        let mut entry = HistoryEntry {
+            transfer_id: transfer_id.to_string(),
+            direction: Direction::Send,
+            peer: HistoryPeer {
+                device_id: peer_id.map(String::from),
+                hostname,
+                ip: Some(ip.to_string()),
+            },
+            files: files
+                .iter()
+                .zip(infos)
+                .map(|(file, info)| HistoryFile {
+                    file_id: info.file_id.clone(),
+                    name: info.name.clone(),
+                    relative_path: info.relative_path.clone(),
+                    size: info.size,
+                    hash: info.hash.clone(),
+                    path: Some(PathBuf::from(&file.file_path)),
+                    outcome: file_outcome(&info.file_id),
+                })
+                .collect(),
+            outcome: outcome.clone(),
+            started_at: now_ms(),
+            finished_at: None,
+            duration_ms: None,
+        };
+        if !accepted {
+            entry.finish(outcome);
+        }
+
+        self.transfer_history().record(entry);
+    }
+
+    // Remember how a transfer we sent ended
+    pub(crate) fn record_sent(&self, result: &TransferResult) {
+        self.transfer_history()
+            .update(&result.transfer_id, |entry| {
+                for file in &mut entry.files {
+                    if let Some(file_result) = result.files.iter().find(|r| r.uuid == file.file_id)
+                    {
+                        file.outcome = Outcome::from(&file_result.status);
+                    }
+                }
+                entry.finish(Outcome::from(&result.status));
+                true
+            });
+    }
+
+    // Remember a transfer request we received, declined ones too
+    pub(crate) fn record_receiving(
+        &self,
+        transfer_id: &str,
+        peer: HistoryPeer,
+        files: Vec<HistoryFile>,
+        accepted: bool,
+    ) {
+        let mut entry = HistoryEntry {
+            transfer_id: transfer_id.to_string(),
+            direction: Direction::Receive,
+            peer,
+            files,
+            outcome: Outcome::InProgress,
+            started_at: now_ms(),
+            finished_at: None,
+            duration_ms: None,
+        };
+        if !accepted {
+            for file in &mut entry.files {
+                file.outcome = Outcome::Declined;
+            }
+            entry.finish(Outcome::Declined);
+        }
+
+        self.transfer_history().record(entry);
+    }
+
+    // Remember where a received file was saved, the transfer is complete with its last file.
+    // Files are only written to disk with the whole transfer, folders can have thousands.
+    pub(crate) fn record_received(&self, transfer_id: &str, file_id: &str, path: PathBuf) {
+        self.transfer_history().update(transfer_id, |entry| {
+            if let Some(file) = entry.files.iter_mut().find(|f| f.file_id == file_id) {
+                file.path = Some(path);
+                file.outcome = Outcome::Completed;
+            }
+
+            // files the user didn't pick are declined from the start
+            let complete = entry.outcome == Outcome::InProgress
+                && entry.files.iter().all(|f| f.outcome != Outcome::InProgress);
+            if complete {
+                entry.finish(Outcome::Completed);
+            }
+            complete
+        });
+    }
+
+    // Remember that a transfer was cancelled, files that made it stay completed
+    pub(crate) fn record_cancelled(&self, transfer_id: &str) {
+        self.transfer_history()
+            .stop(transfer_id, Outcome::Cancelled);
+    }
+
+    // Remember that a transfer can't finish anymore, transfers that ended already are
+    // left as they are
+    pub(crate) fn record_failed(&self, transfer_id: &str, reason: &str) {
+        self.transfer_history().stop(
+            transfer_id,
+            Outcome::Failed {
+                reason: reason.to_string(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> HistoryEntry {
+        HistoryEntry {
+            transfer_id: "t1".into(),
+            direction: Direction::Receive,
+            peer: HistoryPeer {
+                device_id: Some("device-1".into()),
+                hostname: Some("Laptop".into()),
+                ip: Some("192.168.1.20".into()),
+            },
+            files: vec![HistoryFile {
+                file_id: "f1".into(),
+                name: "Holiday.JPG".into(),
+                relative_path: Some("Photos/2024/Holiday.JPG".into()),
+                size: 10,
+                hash: "abcdef0123".into(),
+                path: None,
+                outcome: Outcome::Completed,
+            }],
+            outcome: Outcome::Completed,
+            started_at: 1_000,
+            finished_at: Some(2_000),
+            duration_ms: Some(1_000),
+        }
+    }
+
+    fn search(text: &str) -> HistoryQuery {
+        HistoryQuery {
+            search: Some(text.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_filters() {
+        let entry = entry();
+        let cases = [
+            (HistoryQuery::default(), true),
+            (
+                HistoryQuery {
+                    direction: Some(Direction::Receive),
+                    ..Default::default()
+                },
+                true,
+            ),
+            (
+                HistoryQuery {
+                    direction: Some(Direction::Send),
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                HistoryQuery {
+                    device_id: Some("device-1".into()),
+                    ..Default::default()
+                },
+                true,
+            ),
+            (
+                HistoryQuery {
+                    device_id: Some("device-2".into()),
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                HistoryQuery {
+                    since: Some(1_000),
+                    ..Default::default()
+                },
+                true,
+            ),
+            (
+                HistoryQuery {
+                    since: Some(1_001),
+                    ..Default::default()
+                },
+                false,
+            ),
+        ];
+
+        for (query, expected) in cases {
+            assert_eq!(entry.matches(&query), expected, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn matches_search() {
+        let entry = entry();
+        let cases = [
+            // blank searches match everything
+            ("", true),
+            ("   ", true),
+            // file names and paths, in any case
+            ("holiday", true),
+            ("  HOLIDAY.jpg ", true),
+            ("photos/2024", true),
+            ("vacation", false),
+            // the device
+            ("laptop", true),
+            ("device-1", true),
+            ("192.168.1", true),
+            // the start of a hash only
+            ("abcdef", true),
+            ("ABCDEF", true),
+            ("0123", false),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(entry.matches(&search(text)), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn stopping_keeps_what_ended() {
+        let mut running = entry();
+        running.transfer_id = "t2".into();
+        running.outcome = Outcome::InProgress;
+        running.finished_at = None;
+        running.duration_ms = None;
+        running.files.push(HistoryFile {
+            file_id: "f2".into(),
+            outcome: Outcome::InProgress,
+            ..running.files[0].clone()
+        });
+        let history = TransferHistory {
+            entries: Mutex::new(vec![entry(), running]),
+            writer: None,
+        };
+        let failed = Outcome::Failed {
+            reason: "Sender stopped sending".into(),
+        };
+
+        history.stop("t1", failed.clone());
+        history.stop("t2", failed.clone());
+        // it failed already
+        history.stop("t2", Outcome::Cancelled);
+
+        let entries = history.entries.lock().unwrap();
+        assert_eq!(entries[0].outcome, Outcome::Completed);
+        assert_eq!(entries[0].finished_at, Some(2_000));
+        assert_eq!(entries[1].outcome, failed);
+        assert!(entries[1].finished_at.is_some());
+        assert_eq!(entries[1].files[0].outcome, Outcome::Completed);
+        assert_eq!(entries[1].files[1].outcome, failed);
+    }
+}
@@ -0,0 +1,295 @@
+use super::{
+    open_local_file, peer_url, AcceptedTransfer, BoxError, FileUploadInfo, TransferCancelled,
+    TransferStatus, MAX_UPLOAD_ATTEMPTS, UPLOAD_RETRY_DELAY,
+};
+use crate::compression;
+use crate::debug_log;
+use crate::progress::Direction;
+use crate::tranzit::Tranzit;
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+// Files smaller than this are sent in batches instead of one request each
+pub(super) const BATCH_FILE_SIZE_LIMIT: u64 = 1024 * 1024;
+// a batch is cut off at whichever of these comes first
+pub(super) const BATCH_MAX_FILES: usize = 1000;
+pub(super) const BATCH_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct BatchStatus {
+    received: Vec<String>,
+}
+
+// Files of a batch the receiver has and the ones that were left out of it
+struct BatchOutcome {
+    received: Vec<String>,
+    failed: HashMap<String, String>,
+}
+
+// Every file in a batch starts with its id and its size as a big endian u64,
+// followed by its contents
+fn batch_header(file_id: &str, size: u64) -> Bytes {
+    let mut header = Vec::with_capacity(file_id.len() + 8);
+    header.extend_from_slice(file_id.as_bytes());
+    header.extend_from_slice(&size.to_be_bytes());
+    Bytes::from(header)
+}
+
+// Header and contents of a file in a batch. A file that isn't the size it was
+// announced with anymore is left out, the receiver would read the next header
+// out of its contents. One that shrinks while it is read is padded, the receiver
+// then refuses it by its hash and it's checked again on the next attempt.
+async fn batch_entry(
+    file: &FileUploadInfo,
+    size: u64,
+) -> Result<(Bytes, impl AsyncRead + Unpin), String> {
+    let (reader, actual_size, _) = open_local_file(&file.file_path)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", file.name, e))?;
+    if actual_size != size {
+        return Err(format!(
+            "{} changed since the transfer was requested",
+            file.name
+        ));
+    }
+
+    let contents = reader.take(size).chain(tokio::io::repeat(0)).take(size);
+    Ok((batch_header(&file.file_uuid, size), contents))
+}
+
+impl Tranzit {
+    // Upload many small files as one stream over a single connection. Files the
+    // receiver doesn't have afterwards are sent again in a smaller batch.
+    // Returns the result of every file, in the same order.
+    pub(super) async fn upload_batch(
+        &self,
+        files: &[(&FileUploadInfo, u64)],
+        transfer: &AcceptedTransfer,
+        ip: &str,
+        port: u16,
+        peer_id: Option<&str>,
+    ) -> Vec<Result<(), BoxError>> {
+        let mut results: Vec<Option<Result<(), BoxError>>> = files.iter().map(|_| None).collect();
+        let mut attempt = 1;
+
+        loop {
+            let pending: Vec<usize> = (0..files.len()).filter(|&i| results[i].is_none()).collect();
+            if pending.is_empty() {
+                break;
+            }
+
+            let batch: Vec<(&FileUploadInfo, u64)> = pending.iter().map(|&i| files[i]).collect();
+            match self.send_batch(&batch, transfer, ip, port, peer_id).await {
+                Ok(outcome) => {
+                    for &i in &pending {
+                        let file = files[i].0;
+                        if outcome.received.contains(&file.file_uuid) {
+                            results[i] = Some(Ok(()));
+                        } else if let Some(reason) = outcome.failed.get(&file.file_uuid) {
+                            results[i] = Some(Err(reason.clone().into()));
+                        } else if attempt >= MAX_UPLOAD_ATTEMPTS {
+                            let reason = format!("{} was corrupted in transit", file.name);
+                            results[i] = Some(Err(reason.into()));
+                        }
+                    }
+                }
+                Err(err) if err.is::<TransferCancelled>() => {
+                    for &i in &pending {
+                        results[i] = Some(Err(TransferCancelled.into()));
+                    }
+                }
+                Err(err) if attempt >= MAX_UPLOAD_ATTEMPTS => {
+                    for &i in &pending {
+                        results[i] = Some(Err(err.to_string().into()));
+                    }
+                }
+                Err(err) => {
+                    debug_log!(
+                        "Batch of {} files interrupted (attempt {}/{}): {}",
+                        batch.len(),
+                        attempt,
+                        MAX_UPLOAD_ATTEMPTS,
+                        err
+                    );
+                }
+            }
+
+            if results.iter().any(Option::is_none) {
+                tokio::time::sleep(UPLOAD_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+        }
+
+        results.into_iter().flatten().collect()
+    }
+
+    // Send one batch, returns the ids of the files the receiver has
+    // and why the others were left out
+    async fn send_batch(
+        &self,
+        files: &[(&FileUploadInfo, u64)],
+        transfer: &AcceptedTransfer,
+        ip: &str,
+        port: u16,
+        peer_id: Option<&str>,
+    ) -> Result<BatchOutcome, BoxError> {
+        let client = self.build_client(ip, peer_id)?;
+
+        for (file, _) in files {
+            self.emit_file_status(&transfer.transfer_id, file, TransferStatus::Sending);
+        }
+
+        // files are opened one after the other while the body goes out
+        let entries: Vec<(FileUploadInfo, u64)> = files
+            .iter()
+            .map(|&(file, size)| (file.clone(), size))
+            .collect();
+        let failed: Arc<Mutex<HashMap<String, String>>> = Default::default();
+        let progress = self.transfer_progress(&transfer.transfer_id, Direction::Send);
+        let tranzit = self.clone();
+        let body = stream::iter(entries)
+            .then({
+                let failed = failed.clone();
+                move |(file, size)| {
+                    let tranzit = tranzit.clone();
+                    let progress = progress.clone();
+                    let failed = failed.clone();
+                    async move {
+                        let (header, reader) = match batch_entry(&file, size).await {
+                            Ok(entry) => entry,
+                            Err(reason) => {
+                                debug_log!("Leaving {} out of the batch: {}", file.name, reason);
+                                failed.lock().unwrap().insert(file.file_uuid, reason);
+                                return stream::empty().left_stream();
+                            }
+                        };
+                        progress.add_file(&file.file_uuid, &file.name, size);
+
+                        let file_id = file.file_uuid;
+                        let mut sent = 0;
+                        let contents = FramedRead::new(reader, BytesCodec::new())
+                            .map_ok(Bytes::from)
+                            .inspect_ok(move |data| {
+                                sent += data.len() as u64;
+                                progress.update(&tranzit, &file_id, sent);
+                            });
+
+                        stream::once(async move { Ok(header) })
+                            .chain(contents)
+                            .right_stream()
+                    }
+                }
+            })
+            .flatten();
+
+        // the whole batch is compressed unless all of its files are compressed already
+        let compression = transfer.compression.filter(|_| {
+            files.iter().any(|(file, _)| {
+                let mime_type = mime_guess::from_path(&file.name).first_or_octet_stream();
+                compression::worth_compressing(mime_type.essence_str())
+            })
+        });
+        let throttle = self.upload_throttle(&transfer.transfer_id, peer_id);
+        let body = match compression {
+            Some(compression) => reqwest::Body::wrap_stream(
+                throttle.limit(compression::compress_stream(Box::pin(body), compression)),
+            ),
+            None => reqwest::Body::wrap_stream(throttle.limit(Box::pin(body))),
+        };
+        let mut query = vec![
+            ("transfer_id", transfer.transfer_id.clone()),
+            ("token", transfer.token.clone()),
+        ];
+        if let Some(compression) = compression {
+            query.push(("compression", compression.as_str().to_string()));
+        }
+
+        let url = peer_url(ip, port, "/upload-batch");
+        let response = client
+            .post(&url)
+            .query(&query)
+            .header(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            )
+            .body(body)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::GONE {
+            return Err(TransferCancelled.into());
+        }
+        let status = response.error_for_status()?.json::<BatchStatus>().await?;
+
+        debug_log!(
+            "📦 Sent a batch of {} files, {} received",
+            files.len(),
+            status.received.len()
+        );
+
+        let failed = std::mem::take(&mut *failed.lock().unwrap());
+        Ok(BatchOutcome {
+            received: status.received,
+            failed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::path::Path;
+
+    fn upload_info(path: &Path) -> FileUploadInfo {
+        FileUploadInfo {
+            file_path: path.to_string_lossy().to_string(),
+            file_uuid: "0b0ee1c6-2d44-4a8a-9e8b-7f6d5c4b3a21".into(),
+            name: "a.txt".into(),
+            relative_path: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn entry_is_header_and_contents() {
+        let dir = TempDir::new();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, b"hello").unwrap();
+        let file = upload_info(&path);
+
+        let (header, mut contents) = batch_entry(&file, 5).await.unwrap();
+        let mut read = Vec::new();
+        contents.read_to_end(&mut read).await.unwrap();
+
+        assert_eq!(header, batch_header(&file.file_uuid, 5));
+        assert_eq!(read, b"hello");
+    }
+
+    #[tokio::test]
+    async fn file_of_another_size_is_left_out() {
+        let dir = TempDir::new();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, b"hello").unwrap();
+        let file = upload_info(&path);
+
+        let shrunk = batch_entry(&file, 10).await.is_err();
+        let grown = batch_entry(&file, 3).await.is_err();
+
+        assert!(shrunk);
+        assert!(grown);
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_left_out() {
+        let dir = TempDir::new();
+        let path = dir.join("a.txt");
+        assert!(batch_entry(&upload_info(&path), 5).await.is_err());
+    }
+}
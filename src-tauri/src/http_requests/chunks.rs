@@ -0,0 +1,382 @@
+use super::{
+    file_compression, file_part, open_local_file, peer_url, query_upload_status, AcceptedTransfer,
+    BoxError, FileUploadInfo, ProgressStream, TransferCancelled, TransferStatus,
+    MAX_UPLOAD_ATTEMPTS, UPLOAD_RETRY_DELAY,
+};
+use crate::compression::Compression;
+use crate::debug_log;
+use crate::progress::{Direction, TransferProgress};
+use crate::throttle::Throttle;
+use crate::tranzit::Tranzit;
+use bytes::Bytes;
+use futures_util::stream::{FuturesUnordered, StreamExt, TryStreamExt};
+use reqwest::multipart::Form;
+use reqwest::{Client, StatusCode};
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+// Files at least this big are split into chunks sent over several connections
+pub(super) const CHUNKED_UPLOAD_THRESHOLD: u64 = 32 * 1024 * 1024;
+
+const MIN_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_PARALLEL_CHUNKS: usize = 8;
+// chunks are sized so one takes about this long on a single connection
+const CHUNK_DURATION: Duration = Duration::from_secs(2);
+
+// Picks the size of the next chunk and how many connections send chunks at once,
+// based on the throughput measured so far
+struct ChunkPlanner {
+    chunk_size: u64,
+    parallelism: usize,
+    // bytes sent since every connection last finished a chunk
+    round_bytes: u64,
+    round_chunks: usize,
+    round_start: Instant,
+    // bytes per second of all connections together in the last round
+    throughput: f64,
+}
+
+impl ChunkPlanner {
+    fn new() -> Self {
+        Self {
+            chunk_size: 2 * MIN_CHUNK_SIZE,
+            parallelism: 2,
+            round_bytes: 0,
+            round_chunks: 0,
+            round_start: Instant::now(),
+            throughput: 0.0,
+        }
+    }
+
+    fn chunk_sent(&mut self, len: u64, elapsed: Duration) {
+        let per_connection = len as f64 / elapsed.as_secs_f64().max(0.001);
+        self.chunk_size = ((per_connection * CHUNK_DURATION.as_secs_f64()) as u64)
+            .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+
+        self.round_bytes += len;
+        self.round_chunks += 1;
+        if self.round_chunks < self.parallelism {
+            return;
+        }
+
+        // add connections while that makes the whole thing faster, drop them when it gets slower
+        let throughput = self.round_bytes as f64 / self.round_start.elapsed().as_secs_f64();
+        if throughput > self.throughput * 1.1 {
+            self.parallelism = (self.parallelism + 1).min(MAX_PARALLEL_CHUNKS);
+        } else if throughput < self.throughput * 0.9 {
+            self.parallelism = (self.parallelism - 1).max(1);
+        }
+        debug_log!(
+            "📦 {:.1} MB/s, sending {} MB chunks over {} connections",
+            throughput / 1_000_000.0,
+            self.chunk_size / (1024 * 1024),
+            self.parallelism
+        );
+
+        self.throughput = throughput;
+        self.start_round();
+    }
+
+    // back off when a chunk fails, the network or the receiver can't keep up
+    fn chunk_failed(&mut self) {
+        self.parallelism = (self.parallelism / 2).max(1);
+        self.start_round();
+    }
+
+    fn start_round(&mut self) {
+        self.round_bytes = 0;
+        self.round_chunks = 0;
+        self.round_start = Instant::now();
+    }
+}
+
+// Everything the connections sending chunks of the same file share
+struct ChunkedUpload<'a> {
+    tranzit: &'a Tranzit,
+    client: Client,
+    file: &'a FileUploadInfo,
+    transfer: &'a AcceptedTransfer,
+    url: String,
+    mime_type: String,
+    compression: Option<Compression>,
+    // shared by all connections, so together they stay under the speed limits
+    throttle: Throttle,
+    total_size: u64,
+    // bytes of the file that are out, across all connections
+    uploaded: Arc<AtomicU64>,
+    progress: Arc<TransferProgress>,
+}
+
+impl ChunkedUpload<'_> {
+    // Send the bytes start..end of the file
+    async fn send_chunk(&self, start: u64, end: u64) -> Result<(), BoxError> {
+        let (mut reader, _, _) = open_local_file(&self.file.file_path).await?;
+        reader.seek(SeekFrom::Start(start)).await?;
+        let stream = FramedRead::new(reader.take(end - start), BytesCodec::new())
+            .map_ok(Bytes::from)
+            .map_err(std::io::Error::other);
+
+        let sent = Arc::new(AtomicU64::new(0));
+        let progress_stream = ProgressStream {
+            inner: stream,
+            tranzit: self.tranzit.clone(),
+            progress: self.progress.clone(),
+            uploaded: self.uploaded.clone(),
+            sent: sent.clone(),
+            total: self.total_size,
+            transfer_id: self.transfer.transfer_id.clone(),
+            file: self.file.clone(),
+        };
+
+        let part = file_part(
+            progress_stream,
+            end - start,
+            self.compression,
+            self.throttle.clone(),
+        )
+        .file_name(self.file.name.clone())
+        .mime_str(&self.mime_type)?;
+
+        let mut query = vec![
+            ("transfer_id", self.transfer.transfer_id.clone()),
+            ("token", self.transfer.token.clone()),
+            ("file_id", self.file.file_uuid.clone()),
+            ("offset", start.to_string()),
+            ("length", (end - start).to_string()),
+            ("size", self.total_size.to_string()),
+        ];
+        if let Some(relative_path) = &self.file.relative_path {
+            query.push(("path", relative_path.clone()));
+        }
+        if let Some(compression) = self.compression {
+            query.push(("compression", compression.as_str().to_string()));
+        }
+
+        let result = self
+            .client
+            .post(&self.url)
+            .query(&query)
+            .multipart(Form::new().part("file", part))
+            .send()
+            .await;
+
+        let result = match result {
+            Ok(response) if response.status() == StatusCode::GONE => Err(TransferCancelled.into()),
+            Ok(response) if response.status() == StatusCode::UNPROCESSABLE_ENTITY => {
+                // the receiver dropped all chunks of the corrupted file
+                Err(format!("{} was corrupted in transit", self.file.name).into())
+            }
+            Ok(response) if !response.status().is_success() => Err(format!(
+                "Failed to upload a chunk of {}: {}",
+                self.file.name,
+                response.status()
+            )
+            .into()),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+
+        // the chunk is sent again from the start, so its bytes don't count
+        if result.is_err() {
+            self.uploaded
+                .fetch_sub(sent.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        result
+    }
+}
+
+// Byte ranges of a file that are not in the received ones yet, the receiver
+// reports those and isn't trusted to keep them in order
+fn missing_ranges(received: &[(u64, u64)], total_size: u64) -> VecDeque<(u64, u64)> {
+    let mut received = received.to_vec();
+    received.sort_unstable();
+
+    let mut missing = VecDeque::new();
+    let mut position = 0;
+
+    for (start, end) in received {
+        if start >= total_size {
+            break;
+        }
+        if start > position {
+            missing.push_back((position, start));
+        }
+        position = position.max(end);
+    }
+    if position < total_size {
+        missing.push_back((position, total_size));
+    }
+
+    missing
+}
+
+impl Tranzit {
+    // Upload a large file in chunks over several connections at once. Chunks the
+    // receiver already has are skipped and failed chunks are sent again on their own.
+    pub(super) async fn upload_file_in_chunks(
+        &self,
+        file: &FileUploadInfo,
+        transfer: &AcceptedTransfer,
+        ip: &str,
+        port: u16,
+        peer_id: Option<&str>,
+    ) -> Result<(), BoxError> {
+        let client = self.build_client(ip, peer_id)?;
+
+        let upload_status =
+            query_upload_status(&client, ip, port, transfer, &file.file_uuid).await?;
+        if upload_status.complete {
+            debug_log!("{} was already received", file.name);
+            return Ok(());
+        }
+
+        let (_, total_size, mime_type) = open_local_file(&file.file_path).await?;
+        let mut missing = missing_ranges(&upload_status.received, total_size);
+        let remaining: u64 = missing.iter().map(|(start, end)| end - start).sum();
+        if remaining < total_size {
+            debug_log!("Resuming upload of {}, {} bytes left", file.name, remaining);
+        }
+
+        let upload = ChunkedUpload {
+            tranzit: self,
+            client: client.clone(),
+            file,
+            transfer,
+            url: peer_url(ip, port, "/upload-chunk"),
+            compression: file_compression(transfer, &file.name, &mime_type),
+            throttle: self.upload_throttle(&transfer.transfer_id, peer_id),
+            mime_type,
+            total_size,
+            uploaded: Arc::new(AtomicU64::new(total_size - remaining)),
+            progress: self.transfer_progress(&transfer.transfer_id, Direction::Send),
+        };
+        upload
+            .progress
+            .add_file(&file.file_uuid, &file.name, total_size);
+        self.emit_file_status(&transfer.transfer_id, file, TransferStatus::Sending);
+
+        let mut planner = ChunkPlanner::new();
+        let mut retries = VecDeque::new();
+        let mut in_flight = FuturesUnordered::new();
+
+        loop {
+            while in_flight.len() < planner.parallelism {
+                let (start, end, attempt) = match retries.pop_front() {
+                    Some(retry) => retry,
+                    None => match missing.front_mut() {
+                        Some(range) => {
+                            let start = range.0;
+                            let end = (start + planner.chunk_size).min(range.1);
+                            range.0 = end;
+                            if range.0 == range.1 {
+                                missing.pop_front();
+                            }
+                            (start, end, 1)
+                        }
+                        None => break,
+                    },
+                };
+
+                let upload = &upload;
+                in_flight.push(async move {
+                    // a retried chunk waits a bit first, the wait doesn't count as sending time
+                    if attempt > 1 {
+                        tokio::time::sleep(UPLOAD_RETRY_DELAY * (attempt - 1)).await;
+                    }
+                    let started = Instant::now();
+                    let result = upload.send_chunk(start, end).await;
+                    (start, end, attempt, started.elapsed(), result)
+                });
+            }
+
+            let Some((start, end, attempt, elapsed, result)) = in_flight.next().await else {
+                break;
+            };
+
+            match result {
+                Ok(()) => planner.chunk_sent(end - start, elapsed),
+                Err(err) if attempt < MAX_UPLOAD_ATTEMPTS && !err.is::<TransferCancelled>() => {
+                    debug_log!(
+                        "Chunk {}..{} of {} failed (attempt {}/{}): {}",
+                        start,
+                        end,
+                        file.name,
+                        attempt,
+                        MAX_UPLOAD_ATTEMPTS,
+                        err
+                    );
+                    planner.chunk_failed();
+                    retries.push_back((start, end, attempt + 1));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        // a corrupted file is dropped by the receiver, which then misses chunks
+        // that went through earlier, those are sent on the next attempt
+        let upload_status =
+            query_upload_status(&client, ip, port, transfer, &file.file_uuid).await?;
+        if !upload_status.complete {
+            return Err(format!("{} was not received completely", file.name).into());
+        }
+
+        debug_log!("{} uploaded", file.name);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_received_misses_everything() {
+        assert_eq!(missing_ranges(&[], 100), [(0, 100)]);
+        assert_eq!(missing_ranges(&[], 0), []);
+    }
+
+    #[test]
+    fn full_coverage_misses_nothing() {
+        assert_eq!(missing_ranges(&[(0, 100)], 100), []);
+        assert_eq!(missing_ranges(&[(0, 50), (50, 100)], 100), []);
+        assert_eq!(missing_ranges(&[(0, 150)], 100), []);
+    }
+
+    #[test]
+    fn gaps_between_ranges_are_missing() {
+        assert_eq!(
+            missing_ranges(&[(10, 20), (30, 40)], 100),
+            [(0, 10), (20, 30), (40, 100)]
+        );
+        assert_eq!(missing_ranges(&[(0, 20), (20, 40)], 50), [(40, 50)]);
+    }
+
+    #[test]
+    fn overlapping_ranges_count_once() {
+        assert_eq!(
+            missing_ranges(&[(0, 30), (10, 20), (25, 50)], 100),
+            [(50, 100)]
+        );
+    }
+
+    #[test]
+    fn out_of_order_ranges_are_sorted() {
+        assert_eq!(
+            missing_ranges(&[(30, 40), (0, 10)], 50),
+            [(10, 30), (40, 50)]
+        );
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_ignored() {
+        assert_eq!(missing_ranges(&[(0, 10), (200, 300)], 100), [(10, 100)]);
+    }
+}
@@ -1,162 +1,1064 @@
+mod batch;
+mod chunks;
+mod tls;
+
+use crate::compression::{self, Compression};
 use crate::debug_log;
+use crate::http_server;
+use crate::identity;
+use crate::integrity;
+use crate::mdns::Peer;
+use crate::messages::{Message, MAX_MESSAGE_SIZE};
+use crate::network;
+use crate::progress::{Direction, HashingProgress, TransferProgress};
+use crate::settings::QueueOrder;
+use crate::throttle::Throttle;
+use crate::transfers::TransferPeer;
+use crate::tranzit::Tranzit;
 use bytes::Bytes;
-use futures_util::stream::Stream;
-use futures_util::stream::TryStreamExt;
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
 use futures_util::task::{Context, Poll};
 use pin_project_lite::pin_project;
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::StatusCode;
 use reqwest::{
     multipart::{Form, Part},
-    Client,
+    Client, ClientBuilder,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use tauri::{AppHandle, Emitter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(target_os = "android")]
 use tauri_plugin_android_fs::{AndroidFsExt, FileAccessMode, FileUri};
+use tokio::io::AsyncSeekExt;
 use tokio_util::codec::{BytesCodec, FramedRead};
-use tokio_util::io::ReaderStream;
-
-// If a device can discover others via mDNS but isn’t discoverable itself,
-// it can notify the devices it finds about its presence via assisted discovery
-#[tauri::command]
-pub async fn assisted_discovery(
-    device_ip: String,
-    service_type: String,
-    hostname: String,
-    os_type: String,
-    port: u16,
-    ipv4: String,
-    id: String,
-) -> Result<String, String> {
-    let body = json!({
-        "name": hostname,
-        "ip": ipv4,
-        "port": port,
-        "hostname": hostname,
-        "service_type": service_type,
-        "os": os_type,
-        "id": id
-    });
-
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    let client = reqwest::ClientBuilder::new()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+use uuid::Uuid;
+
+impl Tranzit {
+    // If a device can discover others via mDNS but isn’t discoverable itself,
+    // it can notify the devices it finds about its presence via assisted discovery
+    pub async fn assisted_discovery(
+        &self,
+        device_ip: &str,
+        port: u16,
+        own_info: &Peer,
+        peer_id: Option<&str>,
+    ) -> Result<String, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let client = self.build_client(device_ip, peer_id)?;
+
+        let url = peer_url(device_ip, port, "/assisted-discovery");
+
+        match client
+            .post(&url)
+            .headers(headers)
+            .json(own_info)
+            .send()
+            .await
+        {
+            Ok(response) => match response.text().await {
+                Ok(text) => Ok(text),
+                Err(e) => Err(format!("Failed to read response text: {}", e)),
+            },
+            Err(e) => Err(format!("Request failed: {}", e)),
+        }
+    }
+
+    // Build an HTTP client for talking to a peer. Paired peers must present the
+    // certificate that was pinned while pairing, unpaired ones aren't verified at all.
+    fn build_client(&self, ip: &str, peer_id: Option<&str>) -> Result<Client, String> {
+        let pinned = peer_id.and_then(|id| self.paired_devices().pinned_fingerprint(id));
+        let builder = match pinned {
+            Some(fingerprint) => {
+                client_builder(ip)?.use_preconfigured_tls(tls::pinned_tls_config(&fingerprint)?)
+            }
+            None => client_builder(ip)?.danger_accept_invalid_certs(true),
+        };
+
+        builder
+            .build()
+            .map_err(|e| format!("Failed to build HTTP client: {}", e))
+    }
 
-    let url = format!("https://{}:{}/assisted-discovery", device_ip, port);
+    // Check that the device at an address is the paired device it claims to be by
+    // connecting back to it, only the real one can present the pinned certificate.
+    // Device ids in requests are just claims, anyone could send one.
+    pub(crate) async fn verify_paired_device(&self, device_id: &str, addr: SocketAddr) -> bool {
+        if self
+            .paired_devices()
+            .pinned_fingerprint(device_id)
+            .is_none()
+        {
+            return false;
+        }
 
-    match client.post(&url).headers(headers).json(&body).send().await {
-        Ok(response) => match response.text().await {
-            Ok(text) => Ok(text),
-            Err(e) => Err(format!("Failed to read response text: {}", e)),
-        },
-        Err(e) => Err(format!("Request failed: {}", e)),
+        let ip = network::ip_string(&addr);
+        let identity = async {
+            let client = self.build_client(&ip, Some(device_id))?;
+            client
+                .get(peer_url(&ip, addr.port(), "/identity"))
+                .timeout(VERIFY_TIMEOUT)
+                .send()
+                .await
+                .map_err(|e| format!("Request failed: {}", e))?
+                .json::<DeviceIdentity>()
+                .await
+                .map_err(|e| format!("Failed to parse JSON: {}", e))
+        };
+
+        match identity.await {
+            Ok(identity) => identity.device_id == device_id,
+            Err(e) => {
+                debug_log!("Could not verify {} at {}: {}", device_id, addr, e);
+                false
+            }
+        }
+    }
+
+    // Pair with a peer by pinning the fingerprint of the certificate it presents.
+    // Both devices show the same verification code, which the users on both devices
+    // have to confirm before the pin is stored on either side.
+    pub async fn pair_device(&self, ip: &str, port: u16) -> Result<(), String> {
+        let peer = fetch_identity(ip, port).await?;
+        let own_identity = self.identity();
+
+        // send our identity over a connection that only trusts the certificate seen above
+        let client = client_builder(ip)?
+            .use_preconfigured_tls(tls::pinned_tls_config(&peer.fingerprint)?)
+            .build()
+            .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+
+        // the peer connects back to our server to check the certificate it is about to pin,
+        // our nonce is only revealed once we know the one of the peer
+        let nonce = Uuid::new_v4().simple().to_string();
+        let body = json!({
+            "device_id": own_identity.device_id,
+            "hostname": self.sys_info().hostname.unwrap_or_else(|| "unknown".into()),
+            "fingerprint": own_identity.fingerprint,
+            "port": self.https_port(),
+            "commitment": identity::commitment(&nonce),
+        });
+        let challenge = client
+            .post(peer_url(ip, port, "/pair-request"))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| format!("Request failed: {}", e))?
+            .error_for_status()
+            .map_err(|e| format!("Pairing request refused: {}", e))?
+            .json::<PairChallenge>()
+            .await
+            .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+
+        let code = identity::verification_code(
+            &own_identity.fingerprint,
+            &peer.fingerprint,
+            &nonce,
+            &challenge.nonce,
+        );
+
+        // both users compare the code, the peer answers once its user did
+        let req_id = Uuid::new_v4().to_string();
+        let payload = json!({
+            "id": req_id.clone(),
+            "device_id": peer.device_id,
+            "hostname": peer.hostname,
+            "code": code,
+            "outgoing": true,
+        });
+        let confirmed_here = async {
+            let response = self
+                .ask_user(
+                    &req_id,
+                    "pair-request",
+                    payload,
+                    http_server::PAIRING_TIMEOUT,
+                )
+                .await
+                .map_err(|_| "The code wasn't confirmed in time".to_string())?;
+            match response.answer.as_str() {
+                "accepted" => Ok(()),
+                _ => Err("Pairing rejected".to_string()),
+            }
+        };
+        let confirmed_there = async {
+            let reveal = json!({ "session": challenge.session, "nonce": nonce });
+            let answer = client
+                .post(peer_url(ip, port, "/pair-confirm"))
+                .json(&reveal)
+                .send()
+                .await
+                .map_err(|e| format!("Request failed: {}", e))?
+                .json::<String>()
+                .await
+                .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+            match answer.as_str() {
+                "accepted" => Ok(()),
+                _ => Err(format!("Pairing rejected by {}", peer.hostname)),
+            }
+        };
+
+        // whichever side says no first ends the pairing
+        tokio::try_join!(confirmed_here, confirmed_there)?;
+
+        self.paired_devices()
+            .pin(&peer.device_id, &peer.hostname, &peer.fingerprint);
+
+        Ok(())
+    }
+
+    // Send text, a link or an image, resolves to whether the receiver accepted it
+    pub async fn send_message(
+        &self,
+        ip: &str,
+        port: u16,
+        message: Message,
+        sys_info: DeviceInfo,
+        peer_id: Option<&str>,
+    ) -> Result<bool, String> {
+        let body = json!({
+            "message": message,
+            "sys_info": sys_info,
+            "device_id": self.identity().device_id,
+        });
+        if body.to_string().len() as u64 > MAX_MESSAGE_SIZE {
+            return Err("Message is too large, send it as a file instead".into());
+        }
+
+        let url = peer_url(ip, port, "/message");
+        debug_log!("Sending message to: {}", url);
+
+        let client = self.build_client(ip, peer_id)?;
+        let response = client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| format!("Request failed: {}", e))?
+            .json::<String>()
+            .await
+            .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+
+        Ok(response == "accepted")
+    }
+
+    // send a file transfer request with file names, size, hashes and device info of
+    // the device initiating the file transfer request
+    pub async fn file_transfer_request(
+        &self,
+        ip: &str,
+        port: u16,
+        files: &[FileUploadInfo],
+        sys_info: DeviceInfo,
+        peer_id: Option<&str>,
+    ) -> Result<TransferResponse, String> {
+        // sizes first, so how far hashing got is known from the start
+        let mut total = 0;
+        for file in files {
+            let (_, size, _) = open_local_file(&file.file_path)
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", file.name, e))?;
+            total += size;
+        }
+
+        // hash every file up front, the receiver checks the hash once all bytes are in
+        let mut progress = HashingProgress::new(total);
+        let mut selected_files = Vec::new();
+        for file in files {
+            selected_files.push(
+                file_info(file, |bytes| progress.advance(self, bytes))
+                    .await
+                    .map_err(|e| format!("Failed to read {}: {}", file.name, e))?,
+            );
+        }
+
+        let url = peer_url(ip, port, "/file-transfer-request");
+        debug_log!("Sending request to: {}", url);
+
+        let body = json!({
+            "files_info": selected_files,
+            "sys_info": sys_info,
+            "receiver_info": ip,
+            "device_id": self.identity().device_id,
+            // the receiver connects back here to check who we are before accepting on its own
+            "port": self.https_port(),
+            "compression": compression::SUPPORTED,
+        });
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        let client = self.build_client(ip, peer_id)?;
+        let response = client
+            .post(&url)
+            .headers(headers)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| format!("Request failed: {}", e))?;
+
+        let result = response
+            .json::<TransferResponse>()
+            .await
+            .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+
+        // declined requests have no transfer id, they get one for the history
+        let transfer_id = match &result {
+            TransferResponse::Accepted(transfer) => transfer.transfer_id.clone(),
+            TransferResponse::Rejected => Uuid::new_v4().to_string(),
+        };
+        self.record_sending(&transfer_id, ip, peer_id, files, &selected_files, &result);
+
+        Ok(result)
+    }
+
+    // upload files once the file transfer request is accepted,
+    // also report back the progress and status of each file to front-end while doing so.
+    // Files are queued and only a few are sent at once, as set in the settings.
+    // Files the receiver already has from an earlier attempt are resumed or skipped,
+    // the ones it didn't accept are left out.
+    // Resolves once every file went through or failed.
+    pub async fn upload_files(
+        &self,
+        files: Vec<FileUploadInfo>,
+        ip: String,
+        port: u16,
+        transfer: AcceptedTransfer,
+        peer_id: Option<String>,
+    ) -> TransferResult {
+        let transfer_id = transfer.transfer_id.clone();
+
+        let mut queue: Vec<(usize, &FileUploadInfo)> = Vec::new();
+        let mut declined = Vec::new();
+        for (index, file) in files.iter().enumerate() {
+            if transfer.accepts(&file.file_uuid) {
+                self.emit_file_status(&transfer_id, file, TransferStatus::Queued);
+                queue.push((index, file));
+            } else {
+                let result = self.emit_file_status(&transfer_id, file, TransferStatus::Declined);
+                declined.push((index, result));
+            }
+        }
+        self.emit(
+            "transfer-status",
+            TransferResult {
+                transfer_id: transfer_id.clone(),
+                status: TransferStatus::Sending,
+                files: Vec::new(),
+            },
+        );
+
+        let (cancelled, paused) = self.start_sending(
+            &transfer_id,
+            TransferPeer {
+                ip: ip.clone(),
+                port,
+                peer_id: peer_id.clone(),
+                token: transfer.token.clone(),
+            },
+        );
+
+        let settings = self.settings().get();
+        // sizes add up to the size of the whole transfer for its progress
+        let progress = self.transfer_progress(&transfer_id, Direction::Send);
+        let mut sizes = HashMap::new();
+        for (_, file) in &queue {
+            let size = match open_local_file(&file.file_path).await {
+                Ok((_, size, _)) => {
+                    progress.add_file(&file.file_uuid, &file.name, size);
+                    size
+                }
+                Err(_) => u64::MAX,
+            };
+            sizes.insert(file.file_uuid.as_str(), size);
+        }
+        if settings.queue_order == QueueOrder::SmallestFirst {
+            queue.sort_by_key(|(_, file)| sizes[file.file_uuid.as_str()]);
+        }
+
+        // every upload sends a single file or a batch of small files, a batch
+        // takes the place of its first file in the queue
+        let mut items: Vec<Vec<(usize, &FileUploadInfo)>> = Vec::new();
+        let mut batch: Option<(usize, u64)> = None;
+        for (index, file) in queue {
+            let size = sizes
+                .get(file.file_uuid.as_str())
+                .copied()
+                .unwrap_or(u64::MAX);
+            if !settings.batch_small_files || size >= batch::BATCH_FILE_SIZE_LIMIT {
+                items.push(vec![(index, file)]);
+                continue;
+            }
+
+            match &mut batch {
+                Some((item, bytes))
+                    if items[*item].len() < batch::BATCH_MAX_FILES
+                        && *bytes + size <= batch::BATCH_MAX_BYTES =>
+                {
+                    items[*item].push((index, file));
+                    *bytes += size;
+                }
+                _ => {
+                    items.push(vec![(index, file)]);
+                    batch = Some((items.len() - 1, size));
+                }
+            }
+        }
+
+        let uploads = items.into_iter().map(|item| {
+            let transfer = &transfer;
+            let ip = ip.as_str();
+            let peer_id = peer_id.as_deref();
+            let cancelled = &cancelled;
+            let sizes = &sizes;
+            let mut paused = paused.clone();
+
+            async move {
+                // a paused transfer keeps its queued files waiting here
+                let upload = async {
+                    let _ = paused.wait_for(|paused| !paused).await;
+                    match item.as_slice() {
+                        [(_, file)] => vec![
+                            self.upload_file_with_resume(file, transfer, ip, port, peer_id)
+                                .await,
+                        ],
+                        _ => {
+                            let files: Vec<(&FileUploadInfo, u64)> = item
+                                .iter()
+                                .map(|(_, file)| (*file, sizes[file.file_uuid.as_str()]))
+                                .collect();
+                            self.upload_batch(&files, transfer, ip, port, peer_id).await
+                        }
+                    }
+                };
+
+                let results = tokio::select! {
+                    _ = cancelled.cancelled() => None,
+                    results = upload => Some(results),
+                };
+
+                item.iter()
+                    .enumerate()
+                    .map(|(i, (index, file))| {
+                        let status = match results.as_ref().map(|results| &results[i]) {
+                            None => TransferStatus::Cancelled,
+                            Some(Ok(())) => TransferStatus::Completed,
+                            Some(Err(err)) if err.is::<TransferCancelled>() => {
+                                // the receiver cancelled, stop the other files as well
+                                self.cancelled_by_receiver(&transfer.transfer_id);
+                                TransferStatus::Cancelled
+                            }
+                            Some(Err(err)) => {
+                                debug_log!("Error uploading {}: {:?}", file.file_path, err);
+                                TransferStatus::Failed {
+                                    reason: err.to_string(),
+                                }
+                            }
+                        };
+
+                        (
+                            *index,
+                            self.emit_file_status(&transfer.transfer_id, file, status),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            }
+        });
+        let mut results: Vec<(usize, FileResult)> = stream::iter(uploads)
+            .buffer_unordered(settings.upload_concurrency.max(1))
+            .flat_map(stream::iter)
+            .collect()
+            .await;
+        results.extend(declined);
+        // report files in the order they were picked
+        results.sort_by_key(|(index, _)| *index);
+        let results: Vec<FileResult> = results.into_iter().map(|(_, result)| result).collect();
+        self.finish_transfer(&transfer_id);
+
+        let result = TransferResult {
+            transfer_id,
+            status: transfer_status(&results, cancelled.is_cancelled()),
+            files: results,
+        };
+        self.emit("transfer-status", &result);
+        self.record_sent(&result);
+
+        result
+    }
+
+    // Let the front-end know what happened to a file of a transfer
+    fn emit_file_status(
+        &self,
+        transfer_id: &str,
+        file: &FileUploadInfo,
+        status: TransferStatus,
+    ) -> FileResult {
+        let result = FileResult {
+            transfer_id: transfer_id.to_string(),
+            uuid: file.file_uuid.clone(),
+            filename: file.name.clone(),
+            status,
+        };
+        self.emit("file-status", &result);
+
+        result
+    }
+
+    // Tell the receiver of a transfer we cancelled to stop and drop what it received
+    pub(crate) async fn notify_cancelled(
+        &self,
+        peer: &TransferPeer,
+        transfer_id: &str,
+    ) -> Result<(), BoxError> {
+        let client = self.build_client(&peer.ip, peer.peer_id.as_deref())?;
+        let url = peer_url(&peer.ip, peer.port, &format!("/cancel/{}", transfer_id));
+
+        client
+            .post(&url)
+            .query(&[("token", &peer.token)])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    // Upload a file, resuming from the last byte the receiver has whenever the
+    // connection drops, until it goes through or we run out of attempts.
+    // Large files are sent in chunks over several connections.
+    pub async fn upload_file_with_resume(
+        &self,
+        file: &FileUploadInfo,
+        transfer: &AcceptedTransfer,
+        ip: &str,
+        port: u16,
+        peer_id: Option<&str>,
+    ) -> Result<(), BoxError> {
+        let (_, size, _) = open_local_file(&file.file_path).await?;
+        let chunked = size >= chunks::CHUNKED_UPLOAD_THRESHOLD;
+        let mut attempt = 1;
+
+        loop {
+            let result = if chunked {
+                self.upload_file_in_chunks(file, transfer, ip, port, peer_id)
+                    .await
+            } else {
+                self.upload_file_with_progress(file, transfer, ip, port, peer_id)
+                    .await
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < MAX_UPLOAD_ATTEMPTS && !err.is::<TransferCancelled>() => {
+                    debug_log!(
+                        "Upload of {} interrupted (attempt {}/{}): {}",
+                        file.name,
+                        attempt,
+                        MAX_UPLOAD_ATTEMPTS,
+                        err
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+
+            tokio::time::sleep(UPLOAD_RETRY_DELAY * attempt).await;
+            attempt += 1;
+        }
+    }
+
+    async fn upload_file_with_progress(
+        &self,
+        file: &FileUploadInfo,
+        transfer: &AcceptedTransfer,
+        ip: &str,
+        port: u16,
+        peer_id: Option<&str>,
+    ) -> Result<(), BoxError> {
+        let file_path = file.file_path.as_str();
+        let file_uuid = file.file_uuid.as_str();
+        let file_name = file.name.clone();
+
+        let client = self.build_client(ip, peer_id)?;
+
+        let upload_status = query_upload_status(&client, ip, port, transfer, file_uuid).await?;
+        if upload_status.complete {
+            debug_log!("{} was already received", file_name);
+            return Ok(());
+        }
+        let offset = upload_status.offset;
+        let (mut reader, total_size, mime_type) = open_local_file(file_path).await?;
+        reader.seek(SeekFrom::Start(offset)).await?;
+        let stream = FramedRead::new(reader, BytesCodec::new())
+            .map_ok(Bytes::from)
+            .map_err(std::io::Error::other);
+
+        let progress = self.transfer_progress(&transfer.transfer_id, Direction::Send);
+        progress.add_file(file_uuid, &file_name, total_size);
+        let progress_stream = ProgressStream {
+            inner: stream,
+            tranzit: self.clone(),
+            progress,
+            uploaded: Arc::new(AtomicU64::new(offset)),
+            sent: Arc::default(),
+            total: total_size,
+            transfer_id: transfer.transfer_id.clone(),
+            file: file.clone(),
+        };
+
+        let compression = file_compression(transfer, &file_name, &mime_type);
+        let throttle = self.upload_throttle(&transfer.transfer_id, peer_id);
+        let part = file_part(progress_stream, total_size - offset, compression, throttle)
+            .file_name(file_name.clone())
+            .mime_str(&mime_type)?;
+
+        let form = Form::new().part("file", part);
+
+        if offset > 0 {
+            debug_log!("Resuming upload of {} from byte {}", file_name, offset);
+        }
+        self.emit_file_status(&transfer.transfer_id, file, TransferStatus::Sending);
+
+        let mut query = vec![
+            ("transfer_id", transfer.transfer_id.clone()),
+            ("token", transfer.token.clone()),
+            ("file_id", file_uuid.to_string()),
+            ("offset", offset.to_string()),
+            ("size", total_size.to_string()),
+        ];
+        if let Some(relative_path) = &file.relative_path {
+            query.push(("path", relative_path.clone()));
+        }
+        if let Some(compression) = compression {
+            query.push(("compression", compression.as_str().to_string()));
+        }
+
+        let url = peer_url(ip, port, "/upload");
+        let response = client
+            .post(&url)
+            .query(&query)
+            .multipart(form)
+            .send()
+            .await?;
+
+        debug_log!("Upload response for {}: {:?}", file_name, response.status());
+        if response.status() == StatusCode::GONE {
+            return Err(TransferCancelled.into());
+        }
+        if response.status() == StatusCode::UNPROCESSABLE_ENTITY {
+            // the receiver dropped the corrupted file, so the next attempt starts over
+            return Err(format!("{} was corrupted in transit", file_name).into());
+        }
+        if !response.status().is_success() {
+            return Err(format!("Failed to upload {}: {}", file_name, response.status()).into());
+        }
+
+        debug_log!("{} uploaded", file_name);
+
+        Ok(())
     }
 }
 
+// Nonce of the peer and the pairing it started, see handle_pair_request
+#[derive(Deserialize)]
+struct PairChallenge {
+    session: String,
+    nonce: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DeviceIdentity {
+    pub(crate) device_id: String,
+    pub(crate) hostname: String,
+    pub(crate) fingerprint: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct FileInfo {
-    name: String,
-    size: u64,
+    pub name: String,
+    pub size: u64,
+    // path inside the shared folder, e.g. "project/src/main.rs", not set for single files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_path: Option<String>,
+    // id the file will be uploaded with
+    pub file_id: String,
+    // SHA-256 of the file contents as lowercase hex
+    pub hash: String,
 }
 #[derive(Serialize, Deserialize)]
 pub struct DeviceInfo {
-    hostname: String,
-    os_type: String,
+    pub hostname: String,
+    pub os_type: String,
 }
 
-// send a file transfer request with file names, size and device info of
-// the device initiating the file transfer request
-#[tauri::command]
-pub async fn file_transfer_request(
-    ip: String,
-    port: u16,
-    selected_files: Vec<FileInfo>,
-    sys_info: DeviceInfo,
-) -> Result<serde_json::Value, String> {
-    let url = format!("https://{}:{}/file-transfer-request", ip, port);
-    debug_log!("Sending request to: {}", url);
-
-    let body = json!({
-        "files_info": selected_files,
-        "sys_info": sys_info,
-        "receiver_info": ip
-    });
-
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
-    let client = reqwest::ClientBuilder::new()
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// The receiver refused an upload because the transfer was cancelled, never retried
+#[derive(Debug)]
+struct TransferCancelled;
+
+impl std::fmt::Display for TransferCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transfer cancelled")
+    }
+}
+
+impl std::error::Error for TransferCancelled {}
+
+const MAX_UPLOAD_ATTEMPTS: u32 = 5;
+const UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(2);
+// a trusted device that doesn't answer quickly gets asked about like any other
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+// URLs can't hold the scope id of IPv6 link-local addresses, requests to them go to
+// this name instead, which the client resolves to the scoped address
+const SCOPED_HOST: &str = "scoped-peer.tranzit.invalid";
+
+#[derive(Deserialize)]
+struct UploadStatus {
+    offset: u64,
+    complete: bool,
+    // chunks the receiver has of a file sent in chunks
+    #[serde(default)]
+    received: Vec<(u64, u64)>,
+}
+
+// Id and one-time token the receiver hands out when it accepts a transfer request,
+// uploads of the transfer are refused without them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptedTransfer {
+    pub transfer_id: String,
+    pub token: String,
+    // picked by the receiver, older receivers don't take compressed uploads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    // files the receiver accepted, older receivers take all of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<String>>,
+}
+
+impl AcceptedTransfer {
+    pub fn accepts(&self, file_id: &str) -> bool {
+        self.files
+            .as_ref()
+            .is_none_or(|files| files.iter().any(|id| id == file_id))
+    }
+}
+
+// Answer of the receiver to a file transfer request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TransferResponse {
+    Accepted(AcceptedTransfer),
+    Rejected,
+}
+
+// Where a file or a whole transfer is at, sent to the front-end as
+// e.g. {"status": "failed", "reason": "..."}
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TransferStatus {
+    Queued,
+    Sending,
+    // only for whole transfers, the queue was paused by the user
+    Paused,
+    Verifying,
+    Completed,
+    Failed { reason: String },
+    Cancelled,
+    // only for files, the receiver didn't accept it
+    Declined,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileResult {
+    pub transfer_id: String,
+    pub uuid: String,
+    pub filename: String,
+    #[serde(flatten)]
+    pub status: TransferStatus,
+}
+
+// Outcome of a transfer, failed if any of its files failed
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferResult {
+    pub transfer_id: String,
+    #[serde(flatten)]
+    pub status: TransferStatus,
+    pub files: Vec<FileResult>,
+}
+
+// Status of a whole transfer out of those of its files, files the receiver didn't
+// accept don't make it fail
+fn transfer_status(files: &[FileResult], cancelled: bool) -> TransferStatus {
+    let failed = files
+        .iter()
+        .filter(|file| {
+            !matches!(
+                file.status,
+                TransferStatus::Completed | TransferStatus::Declined
+            )
+        })
+        .count();
+
+    if cancelled {
+        TransferStatus::Cancelled
+    } else if failed == 0 {
+        TransferStatus::Completed
+    } else {
+        TransferStatus::Failed {
+            reason: format!("{} of {} files failed", failed, files.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileUploadInfo {
+    pub file_path: String,
+    pub file_uuid: String,
+    pub name: String,
+    #[serde(default)]
+    pub relative_path: Option<String>,
+}
+
+// List all files in a folder recursively along with their path relative to the
+// parent of the folder, so the folder itself is recreated on the receiving side.
+// Symlinks are skipped so a link pointing back up the tree can't loop forever.
+pub fn files_in_dir(dir: &Path) -> Result<Vec<(PathBuf, String)>, String> {
+    let root = dir.parent().unwrap_or(dir);
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+            let file_type = entry
+                .file_type()
+                .map_err(|e| format!("Failed to read {}: {}", entry.path().display(), e))?;
+
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                let path = entry.path();
+                let relative_path = path
+                    .strip_prefix(root)
+                    .unwrap_or(&path)
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.push((path, relative_path));
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+// Open a file that is about to be sent, file paths are content uris on Android.
+// Returns the file along with its size and mime type.
+async fn open_local_file(file_path: &str) -> Result<(tokio::fs::File, u64, String), BoxError> {
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    {
+        let file = tokio::fs::File::open(file_path).await?;
+        let metadata = file.metadata().await?;
+        let total_size = metadata.len();
+
+        let mime_type = infer::get_from_path(file_path)
+            .ok()
+            .flatten()
+            .map(|kind| kind.mime_type().to_string())
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+
+        Ok((file, total_size, mime_type))
+    }
+
+    #[cfg(target_os = "android")]
+    {
+        let app_handle = crate::APP_HANDLE
+            .get()
+            .ok_or("App handle not initialized")?;
+        let fs = app_handle.android_fs();
+        let file_uri = FileUri {
+            uri: file_path.to_string(),
+            document_top_tree_uri: None,
+        };
+
+        let std_file = fs
+            .open_file(&file_uri, FileAccessMode::Read)
+            .map_err(|e| format!("Android file open failed: {e}"))?;
+
+        let metadata = std_file
+            .metadata()
+            .map_err(|e| format!("Android file metadata error: {e}"))?;
+        let total_size = metadata.len();
+
+        let mime_type = fs
+            .get_mime_type(&file_uri)
+            .map_err(|e| format!("Android mime error: {e}"))?
+            .unwrap_or_else(|| "application/octet-stream".into());
+
+        // Just convert to async and stream
+        Ok((tokio::fs::File::from_std(std_file), total_size, mime_type))
+    }
+}
+
+// Compression for a file of a transfer, files that are compressed already go out as they are.
+// infer only knows formats by their magic bytes, so the name is checked too.
+fn file_compression(
+    transfer: &AcceptedTransfer,
+    name: &str,
+    mime_type: &str,
+) -> Option<Compression> {
+    let guessed = mime_guess::from_path(name).first_or_octet_stream();
+    transfer.compression.filter(|_| {
+        compression::worth_compressing(mime_type)
+            && compression::worth_compressing(guessed.essence_str())
+    })
+}
+
+// Multipart part with the bytes of a file, the size of a compressed one isn't known up front.
+// The speed limits count the bytes that go out, after compression.
+fn file_part<S>(
+    stream: S,
+    length: u64,
+    compression: Option<Compression>,
+    throttle: Throttle,
+) -> Part
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin + Send + 'static,
+{
+    match compression {
+        Some(compression) => Part::stream(reqwest::Body::wrap_stream(
+            throttle.limit(compression::compress_stream(stream, compression)),
+        )),
+        None => {
+            Part::stream_with_length(reqwest::Body::wrap_stream(throttle.limit(stream)), length)
+        }
+    }
+}
+
+// Describe a file for the transfer request, reads the whole file to hash it
+async fn file_info(file: &FileUploadInfo, progress: impl FnMut(u64)) -> Result<FileInfo, BoxError> {
+    let (reader, size, _) = open_local_file(&file.file_path).await?;
+    let hash =
+        integrity::to_hex(integrity::hash_reader_with_progress(reader, size, progress).await?);
+
+    Ok(FileInfo {
+        name: file.name.clone(),
+        size,
+        relative_path: file.relative_path.clone(),
+        file_id: file.file_uuid.clone(),
+        hash,
+    })
+}
+
+// URL of a path on a peer
+fn peer_url(ip: &str, port: u16, path: &str) -> String {
+    if ip.contains('%') {
+        format!("https://{}:{}{}", SCOPED_HOST, port, path)
+    } else if ip.contains(':') {
+        format!("https://[{}]:{}{}", ip, port, path)
+    } else {
+        format!("https://{}:{}{}", ip, port, path)
+    }
+}
+
+// Client builder that reaches a peer at the address peer_url points to
+fn client_builder(ip: &str) -> Result<ClientBuilder, String> {
+    let builder = Client::builder();
+    if !ip.contains('%') {
+        return Ok(builder);
+    }
+
+    // the port of the URL is used over the one resolved
+    Ok(builder.resolve(SCOPED_HOST, network::socket_addr(ip, 0)?))
+}
+
+// Identity of the device at an address, its fingerprint is the one of the certificate
+// seen in the TLS handshake rather than what the device claims
+pub(crate) async fn fetch_identity(ip: &str, port: u16) -> Result<DeviceIdentity, String> {
+    let client = client_builder(ip)?
         .danger_accept_invalid_certs(true)
+        .tls_info(true)
         .build()
         .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+
+    let url = peer_url(ip, port, "/identity");
     let response = client
-        .post(&url)
-        .headers(headers)
-        .json(&body)
+        .get(&url)
+        .timeout(VERIFY_TIMEOUT)
         .send()
         .await
         .map_err(|e| format!("Request failed: {}", e))?;
 
-    let result_json = response
-        .json::<serde_json::Value>()
+    let fingerprint = response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .map(identity::fingerprint)
+        .ok_or("Peer did not present a certificate")?;
+
+    let identity = response
+        .json::<DeviceIdentity>()
         .await
         .map_err(|e| format!("Failed to parse JSON: {}", e))?;
 
-    Ok(result_json)
-}
+    if identity.fingerprint != fingerprint {
+        return Err("Peer certificate does not match its advertised identity".into());
+    }
 
-#[derive(serde::Deserialize)]
-pub struct FileUploadInfo {
-    file_path: String,
-    file_uuid: String,
-    name: String,
+    Ok(identity)
 }
 
-// upload files once the file transfer request is accepted,
-// also report back the progress to front-end while doing so
-#[tauri::command]
-pub async fn upload_files(
-    files: Vec<FileUploadInfo>,
-    ip: String,
+// Ask the receiver how many bytes of the file it already has
+async fn query_upload_status(
+    client: &Client,
+    ip: &str,
     port: u16,
-    app_handle: AppHandle,
-) -> Result<(), String> {
-    for file in files {
-        let app_handle = app_handle.clone();
-        let ip = ip.clone();
-
-        tauri::async_runtime::spawn(async move {
-            if let Err(err) = upload_file_with_progress(
-                &file.file_path,
-                &file.name,
-                &file.file_uuid,
-                &ip,
-                port,
-                &app_handle,
-            )
-            .await
-            {
-                debug_log!("Error uploading {}: {:?}", file.file_path, err);
-            }
-        });
+    transfer: &AcceptedTransfer,
+    file_uuid: &str,
+) -> Result<UploadStatus, BoxError> {
+    let path = format!("/upload-offset/{}/{}", transfer.transfer_id, file_uuid);
+    let url = peer_url(ip, port, &path);
+
+    let response = client
+        .get(&url)
+        .query(&[("token", &transfer.token)])
+        .send()
+        .await?;
+    if response.status() == StatusCode::GONE {
+        return Err(TransferCancelled.into());
     }
 
-    Ok(())
+    let status = response.error_for_status()?.json::<UploadStatus>().await?;
+
+    Ok(status)
 }
 
 pin_project! {
     pub struct ProgressStream<S> {
         #[pin]
         inner: S,
-        uploaded: u64,
+        tranzit: Tranzit,
+        progress: Arc<TransferProgress>,
+        // bytes of the file that are out, shared by the connections sending its chunks
+        uploaded: Arc<AtomicU64>,
+        // bytes of this stream, taken back off uploaded when its chunk fails
+        sent: Arc<AtomicU64>,
         total: u64,
-        file_name: String,
-        file_uuid: String,
-        app_handle: tauri::AppHandle,
+        transfer_id: String,
+        file: FileUploadInfo,
     }
 }
 
@@ -171,123 +1073,271 @@ where
 
         match this.inner.as_mut().poll_next(cx) {
             Poll::Ready(Some(Ok(chunk))) => {
-                *this.uploaded += chunk.len() as u64;
-                let percent = ((*this.uploaded as f64 / *this.total as f64) * 100.0).round();
-
-                let _ = this.app_handle.emit(
-                    "upload-progress",
-                    serde_json::json!({
-                        "filename": *this.file_name,
-                        "percent": percent,
-                        "uuid": *this.file_uuid,
-                    }),
-                );
+                let len = chunk.len() as u64;
+                this.sent.fetch_add(len, Ordering::Relaxed);
+                this.uploaded.fetch_add(len, Ordering::Relaxed);
+                let uploaded = this.uploaded.clone();
+                this.progress
+                    .update_with(this.tranzit, &this.file.file_uuid, |_| {
+                        uploaded.load(Ordering::Relaxed)
+                    });
 
                 Poll::Ready(Some(Ok(chunk)))
             }
+            // all bytes are out, the receiver checks the hash before it responds
+            Poll::Ready(None) => {
+                if this.uploaded.load(Ordering::Relaxed) >= *this.total {
+                    this.tranzit.emit_file_status(
+                        this.transfer_id,
+                        this.file,
+                        TransferStatus::Verifying,
+                    );
+                }
+                Poll::Ready(None)
+            }
             other => other,
         }
     }
 }
 
-#[tauri::command]
-pub async fn upload_file_with_progress(
-    file_path: &str,
-    name: &str,
-    file_uuid: &str,
-    ip: &str,
-    port: u16,
-    app_handle: &tauri::AppHandle,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use tokio_stream::Stream;
-    let file_name = String::from(name);
-    let (stream, total_size, mime_type): (
-        Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>,
-        u64,
-        String,
-    ) = {
-        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
-        {
-            let file = tokio::fs::File::open(file_path).await?;
-            let metadata = file.metadata().await?;
-            let total_size = metadata.len();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use crate::settings::Settings;
+    use crate::test_support::{answer_requests, instance, TempDir};
+    use tokio::sync::mpsc::UnboundedReceiver;
 
-            let mime_type = infer::get_from_path(file_path)
-                .ok()
-                .flatten()
-                .map(|kind| kind.mime_type().to_string())
-                .unwrap_or_else(|| "application/octet-stream".to_string());
+    fn files(statuses: &[TransferStatus]) -> Vec<FileResult> {
+        statuses
+            .iter()
+            .enumerate()
+            .map(|(i, status)| FileResult {
+                transfer_id: "transfer".into(),
+                uuid: i.to_string(),
+                filename: format!("{}.txt", i),
+                status: status.clone(),
+            })
+            .collect()
+    }
 
-            let stream = FramedRead::new(file, BytesCodec::new())
-                .map_ok(Bytes::from)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+    #[test]
+    fn transfer_completes_when_every_accepted_file_does() {
+        let results = files(&[TransferStatus::Completed, TransferStatus::Declined]);
+        assert_eq!(transfer_status(&results, false), TransferStatus::Completed);
+        // nothing was sent when the receiver accepted none of them
+        assert_eq!(transfer_status(&[], false), TransferStatus::Completed);
+    }
 
-            (Box::pin(stream), total_size, mime_type)
-        }
+    #[test]
+    fn transfer_fails_with_the_files_that_did() {
+        let results = files(&[
+            TransferStatus::Completed,
+            TransferStatus::Failed {
+                reason: "Connection reset".into(),
+            },
+            TransferStatus::Declined,
+            TransferStatus::Cancelled,
+        ]);
+        assert_eq!(
+            transfer_status(&results, false),
+            TransferStatus::Failed {
+                reason: "2 of 4 files failed".into()
+            }
+        );
+    }
 
-        #[cfg(target_os = "android")]
+    #[test]
+    fn cancelled_transfer_is_cancelled_whatever_its_files_did() {
+        let results = files(&[
+            TransferStatus::Completed,
+            TransferStatus::Failed {
+                reason: "Connection reset".into(),
+            },
+        ]);
+        assert_eq!(transfer_status(&results, true), TransferStatus::Cancelled);
+    }
+
+    // Files of the given names and sizes in the directory of the sender
+    fn local_files(dir: &TempDir, files: &[(&str, usize)]) -> Vec<FileUploadInfo> {
+        files
+            .iter()
+            .map(|&(name, size)| {
+                let path = dir.join(name);
+                std::fs::write(&path, vec![b'x'; size]).unwrap();
+                FileUploadInfo {
+                    file_path: path.to_string_lossy().to_string(),
+                    file_uuid: uuid::Uuid::new_v4().to_string(),
+                    name: name.into(),
+                    relative_path: None,
+                }
+            })
+            .collect()
+    }
+
+    async fn accepted_transfer(
+        sender: &Tranzit,
+        port: u16,
+        files: &[FileUploadInfo],
+    ) -> AcceptedTransfer {
+        let device = DeviceInfo {
+            hostname: "sender".into(),
+            os_type: "test".into(),
+        };
+        match sender
+            .file_transfer_request("127.0.0.1", port, files, device, None)
+            .await
+            .unwrap()
         {
-            let fs = app_handle.android_fs();
-            let file_uri = FileUri {
-                uri: file_path.to_string(),
-                document_top_tree_uri: None,
-            };
+            TransferResponse::Accepted(transfer) => transfer,
+            _ => panic!("transfer request was rejected"),
+        }
+    }
 
-            let std_file = fs
-                .open_file(&file_uri, FileAccessMode::Read)
-                .map_err(|e| format!("Android file open failed: {e}"))?;
+    // Names of the files in the order they started being sent
+    fn sending_order(events: &mut UnboundedReceiver<Event>) -> Vec<String> {
+        let mut names = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if event.name == "file-status" && event.payload["status"] == "sending" {
+                names.push(event.payload["filename"].as_str().unwrap().to_string());
+            }
+        }
+        names
+    }
 
-            let metadata = std_file
-                .metadata()
-                .map_err(|e| format!("Android file metadata error: {e}"))?;
-            let total_size = metadata.len();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn smallest_files_are_sent_first() {
+        let (sender, mut sender_events, sender_dir) = instance();
+        let (receiver, receiver_events, _receiver_dir) = instance();
+        let port = receiver.start_http_server().await.unwrap().port();
+        answer_requests(
+            &receiver,
+            receiver_events,
+            "file-transfer-request",
+            "accepted",
+        );
+        // one file at a time so they start in the order of the queue
+        sender
+            .settings()
+            .set(Settings {
+                upload_concurrency: 1,
+                queue_order: QueueOrder::SmallestFirst,
+                batch_small_files: false,
+                ..Settings::default()
+            })
+            .unwrap();
 
-            let mime_type = fs
-                .get_mime_type(&file_uri)
-                .map_err(|e| format!("Android mime error: {e}"))?
-                .unwrap_or_else(|| "application/octet-stream".into());
+        let files = local_files(
+            &sender_dir,
+            &[("large.bin", 4096), ("small.bin", 16), ("medium.bin", 512)],
+        );
+        let transfer = accepted_transfer(&sender, port, &files).await;
+        let result = sender
+            .upload_files(files, "127.0.0.1".into(), port, transfer, None)
+            .await;
 
-            // Just convert to async and stream
-            let file = tokio::fs::File::from_std(std_file);
+        assert_eq!(result.status, TransferStatus::Completed);
+        assert_eq!(
+            sending_order(&mut sender_events),
+            ["small.bin", "medium.bin", "large.bin"]
+        );
 
-            let stream = ReaderStream::new(file)
-                .map_ok(Bytes::from)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+        receiver.shutdown();
+    }
 
-            (Box::pin(stream), total_size, mime_type)
-        }
-    };
+    #[tokio::test(flavor = "multi_thread")]
+    async fn files_are_sent_in_the_order_they_were_picked() {
+        let (sender, mut sender_events, sender_dir) = instance();
+        let (receiver, receiver_events, _receiver_dir) = instance();
+        let port = receiver.start_http_server().await.unwrap().port();
+        answer_requests(
+            &receiver,
+            receiver_events,
+            "file-transfer-request",
+            "accepted",
+        );
+        sender
+            .settings()
+            .set(Settings {
+                upload_concurrency: 1,
+                batch_small_files: false,
+                ..Settings::default()
+            })
+            .unwrap();
 
-    let progress_stream = ProgressStream {
-        inner: stream,
-        uploaded: 0,
-        total: total_size,
-        file_name: file_name.clone(),
-        file_uuid: file_uuid.to_string(),
-        app_handle: app_handle.clone(),
-    };
+        let files = local_files(
+            &sender_dir,
+            &[("large.bin", 4096), ("small.bin", 16), ("medium.bin", 512)],
+        );
+        let transfer = accepted_transfer(&sender, port, &files).await;
+        let result = sender
+            .upload_files(files, "127.0.0.1".into(), port, transfer, None)
+            .await;
 
-    let body = reqwest::Body::wrap_stream(progress_stream);
+        assert_eq!(result.status, TransferStatus::Completed);
+        assert_eq!(
+            sending_order(&mut sender_events),
+            ["large.bin", "small.bin", "medium.bin"]
+        );
 
-    let part = Part::stream_with_length(body, total_size)
-        .file_name(file_name.clone())
-        .mime_str(&mime_type)?;
+        receiver.shutdown();
+    }
 
-    let form = Form::new().part("file", part);
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paused_transfer_sends_nothing_until_resumed() {
+        let (sender, mut sender_events, sender_dir) = instance();
+        let (receiver, receiver_events, receiver_dir) = instance();
+        let port = receiver.start_http_server().await.unwrap().port();
+        answer_requests(
+            &receiver,
+            receiver_events,
+            "file-transfer-request",
+            "accepted",
+        );
 
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?;
+        let files = local_files(&sender_dir, &[("paused.bin", 1024)]);
+        let transfer = accepted_transfer(&sender, port, &files).await;
+        let transfer_id = transfer.transfer_id.clone();
+        // paused before its queue starts, which is kept when the upload registers it
+        sender.start_sending(
+            &transfer_id,
+            TransferPeer {
+                ip: "127.0.0.1".into(),
+                port,
+                peer_id: None,
+                token: transfer.token.clone(),
+            },
+        );
+        sender.pause_transfer(&transfer_id).unwrap();
 
-    let url = format!("https://{}:{}/upload", ip, port);
-    let response = client.post(&url).multipart(form).send().await?;
+        let upload = tokio::spawn({
+            let sender = sender.clone();
+            async move {
+                sender
+                    .upload_files(files, "127.0.0.1".into(), port, transfer, None)
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(sending_order(&mut sender_events).is_empty());
+        assert!(!upload.is_finished());
+        assert!(!receiver_dir.join("received/paused.bin").exists());
 
-    debug_log!("Upload response for {}: {:?}", file_name, response.status());
-    if response.status().is_success() {
-        debug_log!("{} uploaded", file_name);
-    } else {
-        debug_log!("Failed to upload {}: {}", file_name, response.status());
+        sender.resume_transfer(&transfer_id).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(30), upload)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.status, TransferStatus::Completed);
+        assert!(receiver_dir.join("received/paused.bin").is_file());
+
+        receiver.shutdown();
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn only_transfers_being_sent_can_be_paused() {
+        let (tranzit, _events, _dir) = instance();
+        assert!(tranzit.pause_transfer("unknown-transfer").is_err());
+        assert!(tranzit.resume_transfer("unknown-transfer").is_err());
+    }
 }
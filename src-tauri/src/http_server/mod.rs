@@ -1,24 +1,136 @@
 mod utils;
+#[cfg(target_os = "android")]
 mod utils_android;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+mod utils_desktop;
 
+use crate::compression::{self, Compression};
 use crate::debug_log;
+use crate::history::{HistoryFile, HistoryPeer, Outcome};
+use crate::http_requests;
+use crate::identity;
+use crate::integrity::{self, FileHasher};
 use crate::mdns::Peer;
-use once_cell::sync::Lazy;
+use crate::messages::{Message, MAX_MESSAGE_SIZE};
+use crate::network;
+use crate::progress::Direction;
+use crate::throttle::Throttle;
+use crate::tranzit::{Tranzit, DEFAULT_HTTPS_PORT};
+use bytes::{Bytes, BytesMut};
+use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Mutex},
 };
-use tauri::{AppHandle, Emitter};
 use tokio::sync::oneshot;
 use uuid::Uuid;
+use warp::multipart::Part;
 use warp::{self, http::Method, http::StatusCode, reject::Reject, Filter, Rejection, Reply};
 
-type ResponseSender = oneshot::Sender<String>;
+// What the user answered to a pending request
+pub(crate) struct RequestAnswer {
+    pub(crate) answer: String,
+    // accepted transfers can be saved somewhere else than usual
+    receive_dir: Option<PathBuf>,
+    // files the user wants out of an accepted transfer, all of them when not set
+    files: Option<Vec<FileChoice>>,
+}
+
+// A file picked when accepting a transfer request, saved under another name when given
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileChoice {
+    pub file_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+type ResponseSender = oneshot::Sender<RequestAnswer>;
+
+// How long to wait for the user to answer a transfer request or a message
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long to wait for the user to confirm a pairing verification code
+pub(crate) const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long an accepted transfer is remembered once it is complete or cancelled,
+// a sender that missed our answer to its last upload can still retry it
+const APPROVAL_GRACE: Duration = Duration::from_secs(5 * 60);
+// An accepted transfer nothing arrived of for this long is given up on, the sender
+// stopped or never started. Long pauses of the sender end up here too.
+const SENDER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// Requests waiting for the user to accept or reject them, keyed on request id
+pub(crate) type PendingRequests = Mutex<HashMap<String, ResponseSender>>;
+
+// A pairing started by another device, waiting for it to reveal its nonce
+pub(crate) struct PairingSession {
+    peer: http_requests::DeviceIdentity,
+    // hash of the nonce of the other device
+    commitment: String,
+    nonce: String,
+    started: Instant,
+}
+
+// Pairings other devices started, keyed on session id
+pub(crate) type PairingSessions = Mutex<HashMap<String, PairingSession>>;
+
+// Takes a request out of the pending ones when it's dropped, the front-end is told
+// when nobody waits for its answer anymore
+struct PendingRequest<'a> {
+    tranzit: &'a Tranzit,
+    id: &'a str,
+}
 
-static PENDING_REQUESTS: Lazy<Arc<Mutex<HashMap<String, ResponseSender>>>> =
-    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        let unanswered = self
+            .tranzit
+            .inner
+            .pending_requests
+            .lock()
+            .unwrap()
+            .remove(self.id);
+        if unanswered.is_some() {
+            self.tranzit
+                .emit("request-closed", serde_json::json!({ "id": self.id }));
+        }
+    }
+}
+
+// A file the user agreed to receive, uploads have to match it
+#[derive(Clone)]
+pub(crate) struct ApprovedFile {
+    name: String,
+    size: u64,
+    relative_path: Option<String>,
+    // name the user gave the file when accepting it
+    rename: Option<String>,
+    // SHA-256 from the transfer request, checked once all bytes are in
+    hash: String,
+    received: bool,
+    // byte ranges received of a file sent in chunks, sorted and merged
+    chunks: Vec<(u64, u64)>,
+}
+
+// An accepted transfer request, uploads present its one-time token
+pub(crate) struct ApprovedTransfer {
+    token: String,
+    files: HashMap<String, ApprovedFile>,
+    // device id the sender claimed, used to pick a destination rule
+    sender_id: Option<String>,
+    // picked by the user when accepting
+    receive_dir: Option<PathBuf>,
+    // uploads may be compressed with this, picked from what the sender offered
+    compression: Option<Compression>,
+}
+
+// Accepted transfer requests, keyed on the transfer id handed to the sender
+pub(crate) type ApprovedTransfers = Mutex<HashMap<String, ApprovedTransfer>>;
 
 // Custom internal error used for returning 5xx
 #[derive(Debug)]
@@ -26,16 +138,85 @@ pub struct InternalServerError;
 
 impl Reject for InternalServerError {}
 
+// Rejected when transfer/file ids are malformed
+#[derive(Debug)]
+pub struct BadRequest;
+
+impl Reject for BadRequest {}
+
+// Rejected when an upload starts past the end of the partially received file
+#[derive(Debug)]
+pub struct OffsetMismatch;
+
+impl Reject for OffsetMismatch {}
+
+// Rejected when an upload ended before all bytes of its file came in, the sender
+// asks for the offset again and resumes from there
+#[derive(Debug)]
+pub struct IncompleteUpload;
+
+impl Reject for IncompleteUpload {}
+
+// Rejected when a received file doesn't match the hash from the transfer request
+#[derive(Debug)]
+pub struct HashMismatch;
+
+impl Reject for HashMismatch {}
+
+// Rejected when an upload doesn't belong to an accepted transfer request
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl Reject for Forbidden {}
+
+// Rejected when the transfer was cancelled by either side
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Reject for Cancelled {}
+
 // Convert custom rejections into proper HTTP responses
-async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
+async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     if err.find::<InternalServerError>().is_some() {
         Ok(warp::reply::with_status(
             "Internal Server Error",
             StatusCode::INTERNAL_SERVER_ERROR,
         ))
+    } else if err.find::<OffsetMismatch>().is_some() {
+        Ok(warp::reply::with_status(
+            "Upload offset mismatch",
+            StatusCode::CONFLICT,
+        ))
+    } else if err.find::<IncompleteUpload>().is_some() {
+        Ok(warp::reply::with_status(
+            "Upload incomplete",
+            StatusCode::CONFLICT,
+        ))
+    } else if err.find::<HashMismatch>().is_some() {
+        Ok(warp::reply::with_status(
+            "File hash mismatch",
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ))
+    } else if err.find::<Forbidden>().is_some() {
+        Ok(warp::reply::with_status(
+            "Upload not approved",
+            StatusCode::FORBIDDEN,
+        ))
+    } else if err.find::<Cancelled>().is_some() {
+        Ok(warp::reply::with_status(
+            "Transfer cancelled",
+            StatusCode::GONE,
+        ))
+    } else if err.find::<BadRequest>().is_some()
+        || err.find::<warp::reject::InvalidQuery>().is_some()
+    {
+        Ok(warp::reply::with_status(
+            "Bad Request",
+            StatusCode::BAD_REQUEST,
+        ))
     } else if err.is_not_found() {
         Ok(warp::reply::with_status("Not Found", StatusCode::NOT_FOUND))
-    } else if let Some(_) = err.find::<warp::reject::PayloadTooLarge>() {
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
         Ok(warp::reply::with_status(
             "Payload too large",
             StatusCode::PAYLOAD_TOO_LARGE,
@@ -53,6 +234,10 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::In
 struct FilesInfo {
     name: String,
     size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relative_path: Option<String>,
+    file_id: String,
+    hash: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -61,33 +246,291 @@ struct DeviceInfo {
     os_type: String,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct DeviceIdentity {
+    device_id: String,
+    hostname: String,
+    fingerprint: String,
+}
+
+// A device asking to be paired, it serves the certificate it wants pinned on port
+#[derive(Debug, Clone, Deserialize)]
+struct PairRequest {
+    device_id: String,
+    fingerprint: String,
+    #[serde(default)]
+    port: Option<u16>,
+    // hash of a nonce the device reveals once it knows ours
+    commitment: String,
+}
+
+// Nonce of our side of a pairing, sent back before either nonce is known to the other
+#[derive(Debug, Serialize)]
+struct PairChallenge {
+    session: String,
+    nonce: String,
+}
+
+// The nonce a device committed to when it asked to pair
+#[derive(Debug, Deserialize)]
+struct PairReveal {
+    session: String,
+    nonce: String,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct FileTransferRequest {
     files_info: Vec<FilesInfo>,
     sys_info: DeviceInfo,
     receiver_info: String,
+    #[serde(default)]
+    device_id: Option<String>,
+    // https port of the sender, older senders don't send it
+    #[serde(default)]
+    port: Option<u16>,
+    // compressions the sender can upload with, senders without any send none
+    #[serde(default)]
+    compression: Vec<String>,
 }
 
-#[tauri::command]
-pub fn start_http_server(app: AppHandle) {
-    tauri::async_runtime::spawn(async move {
-        // Generate TLS certs and write them to disk (cert.pem & key.pem)
-        #[cfg(target_os = "android")]
-        let (cert_path, key_path) =
-            utils_android::generate_tls_certs_to_disk(&app).unwrap_or_else(|e| {
-                debug_log!("Failed to generate TLS certs on Android: {}", e);
-                std::process::exit(1);
-            });
+// Text, a link or an image someone wants to send us
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct MessageRequest {
+    message: Message,
+    sys_info: DeviceInfo,
+    #[serde(default)]
+    device_id: Option<String>,
+}
 
-        // Generate TLS certs and write them to disk (cert.pem & key.pem)
-        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
-        let (cert_path, key_path) = utils::generate_tls_certs_to_disk().unwrap_or_else(|e| {
-            debug_log!("Failed to generate TLS certs: {}", e);
-            std::process::exit(1);
-        });
+// Answer to a file transfer request, accepted transfers get an id and a token
+// the uploads have to present
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum TransferResponse {
+    Accepted {
+        transfer_id: String,
+        token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
+        // ids of the files the user accepted, the sender leaves out the others
+        files: Vec<String>,
+    },
+    Rejected,
+}
+
+// Query parameters sent with every upload so partially received files
+// can be resumed from where they left off
+#[derive(Debug, Clone, Deserialize)]
+struct UploadParams {
+    transfer_id: String,
+    token: String,
+    file_id: String,
+    offset: u64,
+    size: u64,
+    // where to put the file inside the download directory when a folder is sent
+    #[serde(default)]
+    path: Option<String>,
+    // size of a chunk of a file sent over several connections, the chunk starts at offset
+    #[serde(default)]
+    length: Option<u64>,
+    // the file was compressed for the upload, offset, length and size are of the original
+    #[serde(default)]
+    compression: Option<Compression>,
+}
+
+// Token of an accepted transfer, sent along with requests about it
+#[derive(Debug, Clone, Deserialize)]
+struct TokenParams {
+    token: String,
+}
+
+// Query parameters of a batch of small files sent as one stream
+#[derive(Debug, Clone, Deserialize)]
+struct BatchParams {
+    transfer_id: String,
+    token: String,
+    // the whole batch is compressed, sizes in it are of the original files
+    #[serde(default)]
+    compression: Option<Compression>,
+}
+
+// Files of a batch the receiver has, the sender sends the others again
+#[derive(Debug, Clone, Serialize)]
+struct BatchStatus {
+    received: Vec<String>,
+}
+
+// How much of a file the receiver already has, returned by the upload-offset route
+#[derive(Debug, Clone, Serialize)]
+struct UploadStatus {
+    offset: u64,
+    complete: bool,
+    // chunks of the file that were received, when it is sent in chunks
+    received: Vec<(u64, u64)>,
+}
+
+// transfer and file ids end up as path components of partial files,
+// so only accept plain uuids and only spelled one way
+pub(crate) fn is_valid_id(id: &str) -> bool {
+    Uuid::try_parse(id).is_ok_and(|uuid| uuid.hyphenated().to_string() == id)
+}
+
+// Split a relative path sent by a peer into sanitized components. Anything that
+// could point outside the download directory (absolute paths, drive letters, "..")
+// is refused rather than cleaned up, so a file never ends up somewhere unexpected.
+fn safe_relative_path(path: &str) -> Option<Vec<String>> {
+    if path.starts_with('/') || path.starts_with('\\') {
+        return None;
+    }
+
+    let mut components = Vec::new();
+    for component in path.split(['/', '\\']) {
+        if component.is_empty() || component == "." || component == ".." {
+            return None;
+        }
+
+        // sanitize also strips characters like ':', so "C:" can't become a drive letter
+        let safe_component = sanitize_filename::sanitize(component);
+        if safe_component.is_empty() {
+            return None;
+        }
+        components.push(safe_component);
+    }
+
+    if components.is_empty() {
+        None
+    } else {
+        Some(components)
+    }
+}
+
+// Mime type of an uploaded file, guessed from its name when the sender didn't know
+fn content_type(part: &Part, filename: &str) -> String {
+    match part.content_type() {
+        Some(mime_type) if mime_type != "application/octet-stream" => mime_type.to_string(),
+        _ => mime_guess::from_path(filename)
+            .first_or_octet_stream()
+            .to_string(),
+    }
+}
+
+// Add a byte range to a sorted list of ranges, merging the ones that touch
+fn add_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    ranges.push((start, end));
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+// Take a byte range out of a sorted list of ranges, splitting the one it is in
+fn remove_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    *ranges = ranges
+        .iter()
+        .flat_map(|&(from, to)| [(from, to.min(start)), (from.max(end), to)])
+        .filter(|(from, to)| from < to)
+        .collect();
+}
+
+// Every file in a batch starts with its id and its size as a big endian u64
+const BATCH_HEADER_LEN: usize = 36 + 8;
+
+// Reads the files of a batch upload out of the request body as it comes in
+struct BatchReader<S> {
+    body: S,
+    buffer: BytesMut,
+}
 
-        let cert_bytes = std::fs::read(&cert_path).expect("Failed to read TLS cert");
-        let key_bytes = std::fs::read(&key_path).expect("Failed to read TLS key");
+impl<S> BatchReader<S>
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Unpin,
+{
+    fn new(body: S) -> Self {
+        Self {
+            body,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    // Append the next piece of the body to the buffer, false at the end of the body
+    async fn fill(&mut self) -> Result<bool, Rejection> {
+        match self.body.next().await {
+            Some(Ok(data)) => {
+                self.buffer.extend_from_slice(&data);
+                Ok(true)
+            }
+            Some(Err(e)) => {
+                debug_log!("Failed to read batch from stream: {}", e);
+                Err(warp::reject::custom(InternalServerError))
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Id and size of the next file, None at the end of the batch
+    async fn next_file(&mut self) -> Result<Option<(String, u64)>, Rejection> {
+        while self.buffer.len() < BATCH_HEADER_LEN {
+            if !self.fill().await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                debug_log!("Batch ended in the middle of a header");
+                return Err(warp::reject::custom(BadRequest));
+            }
+        }
+
+        let header = self.buffer.split_to(BATCH_HEADER_LEN);
+        let file_id = String::from_utf8(header[..36].to_vec())
+            .ok()
+            .filter(|id| is_valid_id(id))
+            .ok_or_else(|| warp::reject::custom(BadRequest))?;
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&header[36..]);
+
+        Ok(Some((file_id, u64::from_be_bytes(size))))
+    }
+
+    // Up to `remaining` bytes of the current file
+    async fn read(&mut self, remaining: u64) -> Result<Bytes, Rejection> {
+        if self.buffer.is_empty() && !self.fill().await? {
+            debug_log!("Batch ended in the middle of a file");
+            return Err(warp::reject::custom(BadRequest));
+        }
+
+        let len = self.buffer.len().min(remaining as usize);
+        Ok(self.buffer.split_to(len).freeze())
+    }
+}
+
+// Compare a token of an upload with the one handed out without stopping at the first
+// byte that differs, so how long it takes doesn't tell how much of a guess was right
+fn tokens_match(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Hands a clone of the instance to route handlers
+fn with_tranzit(tranzit: Tranzit) -> impl Filter<Extract = (Tranzit,), Error = Infallible> + Clone {
+    warp::any().map(move || tranzit.clone())
+}
+
+impl Tranzit {
+    // Start the https server on the configured port, it keeps running until shutdown.
+    // Returns the address it is listening on.
+    pub async fn start_http_server(&self) -> Result<SocketAddr, String> {
+        // Serve the persistent certificate of this install, peers pin its fingerprint
+        let cert_bytes = self.identity().cert_pem.as_bytes().to_vec();
+        let key_bytes = self.identity().key_pem.as_bytes().to_vec();
 
         // Configure CORS
         let cors = warp::cors()
@@ -96,96 +539,1577 @@ pub fn start_http_server(app: AppHandle) {
             .allow_headers(vec!["Content-Type"]);
 
         // assisted-discovery route
-        let app_ = app.clone();
         let post_info = warp::path("assisted-discovery")
             .and(warp::post())
             .and(warp::body::json())
-            .map(move |device_info: Peer| {
-                debug_log!("received assisted discovery request");
-                match app_.emit("assisted-discovery", &device_info) {
-                    Ok(_) => debug_log!("Assisted discovery Event emitted successfully!"),
-                    Err(e) => debug_log!("Failed to emit assisted discovery event: {}", e),
-                }
-                warp::reply::json(&"Device info received")
-            });
+            .and(warp::addr::remote())
+            .and(with_tranzit(self.clone()))
+            .map(
+                |mut device_info: Peer, remote: Option<SocketAddr>, tranzit: Tranzit| {
+                    debug_log!("received assisted discovery request");
+                    // the address the request came from is reachable, the one claimed might not be
+                    if let Some(remote) = remote {
+                        device_info.ip = network::ip_string(&remote);
+                        device_info.addresses = vec![device_info.ip.clone()];
+                    }
+                    tranzit.emit("assisted-discovery", &device_info);
+                    warp::reply::json(&"Device info received")
+                },
+            );
 
         // file-transfer-request route
         let my_endpoint_route = warp::path!("file-transfer-request")
             .and(warp::post())
             .and(warp::body::json())
-            .and_then({
-                let _app = app.clone();
-                move |req_body: FileTransferRequest| {
-                    let app = _app.clone();
-                    async move {
-                        let (tx, rx) = oneshot::channel::<String>();
-                        let req_id = Uuid::new_v4().to_string();
-
-                        PENDING_REQUESTS.lock().unwrap().insert(req_id.clone(), tx);
-
-                        app.emit(
-                            "file-transfer-request",
-                            serde_json::json!({
-                                "id": req_id.clone(),
-                                "data": req_body
-                            }),
-                        )
-                        .unwrap();
-
-                        let response = tokio::time::timeout(std::time::Duration::from_secs(30), rx)
-                            .await
-                            .map_err(|_| warp::reject::custom(InternalServerError))?
-                            .map_err(|_| warp::reject::custom(InternalServerError))?;
-
-                        Ok::<_, Rejection>(warp::reply::json(&response))
-                    }
-                }
+            .and(warp::addr::remote())
+            .and(with_tranzit(self.clone()))
+            .and_then(handle_file_transfer_request);
+
+        // message route, text, links and images that don't need to be saved as files
+        let message_route = warp::path!("message")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(MAX_MESSAGE_SIZE))
+            .and(warp::body::json())
+            .and(with_tranzit(self.clone()))
+            .and_then(handle_message);
+
+        // identity route, lets peers learn the device id and certificate fingerprint before pairing
+        let identity_route = warp::path!("identity")
+            .and(warp::get())
+            .and(with_tranzit(self.clone()))
+            .map(|tranzit: Tranzit| {
+                let identity = tranzit.identity();
+                warp::reply::json(&DeviceIdentity {
+                    device_id: identity.device_id.clone(),
+                    hostname: tranzit
+                        .sys_info()
+                        .hostname
+                        .unwrap_or_else(|| "unknown".into()),
+                    fingerprint: identity.fingerprint.clone(),
+                })
             });
 
-        #[cfg(target_os = "android")]
-        let save_file_fn = utils_android::save_file;
+        // pair-request route, checks the certificate of the peer and starts a pairing
+        let pair_request_route = warp::path!("pair-request")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(warp::addr::remote())
+            .and(with_tranzit(self.clone()))
+            .and_then(handle_pair_request);
 
-        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
-        let save_file_fn = utils::save_file;
+        // pair-confirm route, asks the user to confirm the verification code
+        // and pins the certificate fingerprint of the peer once accepted
+        let pair_confirm_route = warp::path!("pair-confirm")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_tranzit(self.clone()))
+            .and_then(handle_pair_confirm);
+
+        // remove partial files of transfers accepted before a restart, they can't be
+        // resumed as approvals aren't kept across restarts
+        utils::remove_orphaned_partials(self).await;
 
         // upload route
         let upload_route = warp::path!("upload")
             .and(warp::post())
-            // accept multipart form data and set max uploadable file size to 5GB
-            .and(warp::multipart::form().max_length(5000 * 1024 * 1024))
-            .and_then(save_file_fn);
+            .and(warp::query::<UploadParams>())
+            // accept multipart form data of any length, compressed uploads don't know
+            // theirs up front and the handlers stop at the approved size of the file
+            .and(warp::multipart::form().max_length(None))
+            .and(with_tranzit(self.clone()))
+            .and_then(utils::save_file);
+
+        // upload-chunk route, large files come in chunks over several connections
+        let upload_chunk_route = warp::path!("upload-chunk")
+            .and(warp::post())
+            .and(warp::query::<UploadParams>())
+            .and(warp::multipart::form().max_length(None))
+            .and(with_tranzit(self.clone()))
+            .and_then(utils::save_chunk);
+
+        // upload-batch route, many small files come as one stream over a single connection
+        let upload_batch_route = warp::path!("upload-batch")
+            .and(warp::post())
+            .and(warp::query::<BatchParams>())
+            .and(warp::body::stream())
+            .and(with_tranzit(self.clone()))
+            .and_then(utils::save_batch);
+
+        // upload-offset route, lets the sender know where to resume an interrupted upload
+        let upload_offset_route = warp::path!("upload-offset" / String / String)
+            .and(warp::get())
+            .and(warp::query::<TokenParams>())
+            .and(with_tranzit(self.clone()))
+            .and_then(utils::upload_offset);
+
+        // cancel route, the sender cancelled a transfer
+        let cancel_route = warp::path!("cancel" / String)
+            .and(warp::post())
+            .and(warp::query::<TokenParams>())
+            .and(with_tranzit(self.clone()))
+            .and_then(handle_cancel);
 
         // combine all routes
         let routes = my_endpoint_route
+            .or(message_route)
             .or(upload_route)
+            .or(upload_chunk_route)
+            .or(upload_batch_route)
+            .or(upload_offset_route)
+            .or(cancel_route)
+            .or(identity_route)
+            .or(pair_request_route)
+            .or(pair_confirm_route)
             .or(post_info)
             .with(cors)
             .recover(handle_rejection);
 
-        let port: u16 = match env::var("HTTPS_PORT") {
-            Ok(val) => match val.parse() {
-                Ok(num) => num,
-                Err(_) => 21212,
+        let port = self.config().https_port;
+        let shutdown = self.inner.shutdown.clone();
+
+        // start the https server on both IPv6 and IPv4, binding the second fails where
+        // the first takes both
+        let mut bound: Option<SocketAddr> = None;
+        let mut bind_error = None;
+        for ip in network::UNSPECIFIED {
+            let port = bound.map_or(port, |addr| addr.port());
+            let shutdown = shutdown.clone();
+            match warp::serve(routes.clone())
+                .tls()
+                .cert(&cert_bytes)
+                .key(&key_bytes)
+                .try_bind_with_graceful_shutdown(
+                    (ip, port),
+                    async move { shutdown.cancelled().await },
+                ) {
+                Ok((addr, server)) => {
+                    debug_log!("🔒🖥️ Starting HTTPS server on https://{}", addr);
+                    tokio::spawn(server);
+                    bound.get_or_insert(addr);
+                }
+                Err(e) => bind_error = Some(e),
+            }
+        }
+
+        let addr = bound.ok_or_else(|| {
+            let e = bind_error.map(|e| e.to_string()).unwrap_or_default();
+            format!("Failed to start HTTPS server on port {}: {}", port, e)
+        })?;
+        self.inner.https_port.store(addr.port(), Ordering::Relaxed);
+
+        Ok(addr)
+    }
+
+    // Remember the files the user accepted out of a transfer request, the answer
+    // has the id, the token and the compression the sender uploads them with
+    fn approve_transfer(
+        &self,
+        request: &FileTransferRequest,
+        accepted: &HashMap<String, Option<String>>,
+        receive_dir: Option<PathBuf>,
+    ) -> TransferResponse {
+        let transfer_id = Uuid::new_v4().to_string();
+        // two random uuids, 244 random bits
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        let files: HashMap<String, ApprovedFile> = request
+            .files_info
+            .iter()
+            .filter_map(|file| {
+                let rename = accepted.get(&file.file_id)?;
+                Some((
+                    file.file_id.clone(),
+                    ApprovedFile {
+                        name: file.name.clone(),
+                        size: file.size,
+                        relative_path: file.relative_path.clone(),
+                        rename: rename.clone(),
+                        hash: file.hash.clone(),
+                        received: false,
+                        chunks: Vec::new(),
+                    },
+                ))
+            })
+            .collect();
+        let compression = compression::negotiate(&request.compression);
+
+        let progress = self.transfer_progress(&transfer_id, Direction::Receive);
+        for (file_id, file) in &files {
+            progress.add_file(
+                file_id,
+                file.rename.as_ref().unwrap_or(&file.name),
+                file.size,
+            );
+        }
+
+        let file_ids = files.keys().cloned().collect();
+        self.inner.approved_transfers.lock().unwrap().insert(
+            transfer_id.clone(),
+            ApprovedTransfer {
+                token: token.clone(),
+                files,
+                sender_id: request.device_id.clone(),
+                receive_dir,
+                compression,
             },
-            Err(_) => {
-                debug_log!("HTTPS_PORT environment variable not set, falling back to 21212");
-                21212
+        );
+
+        self.forget_transfer_when_idle(&transfer_id);
+
+        TransferResponse::Accepted {
+            transfer_id,
+            token,
+            compression,
+            files: file_ids,
+        }
+    }
+
+    // Answer a transfer request of a trusted device without asking, none when the user
+    // has to. Requests are only answered this way once the sender proved who it is by
+    // presenting the pinned certificate when connected back to.
+    async fn auto_answer(
+        &self,
+        request: &FileTransferRequest,
+        remote: Option<SocketAddr>,
+    ) -> Option<bool> {
+        let device_id = request.device_id.as_deref()?;
+        let settings = self.settings().get();
+        let trusted = settings.trusted_device(device_id)?;
+
+        let total_size = request.files_info.iter().map(|file| file.size).sum();
+        let mime_types: Vec<String> = request
+            .files_info
+            .iter()
+            .map(|file| {
+                mime_guess::from_path(&file.name)
+                    .first_or_octet_stream()
+                    .to_string()
+            })
+            .collect();
+        let accepted = trusted.answer(total_size, &mime_types)?;
+
+        // the device id is only claimed, rejecting on it would let anyone get a device's
+        // requests turned down. Senders that don't run a server, like the CLI, can't be
+        // connected back to and are always asked about.
+        let mut addr = network::canonical(remote?);
+        addr.set_port(request.port.unwrap_or(DEFAULT_HTTPS_PORT));
+        if !self.verify_paired_device(device_id, addr).await {
+            debug_log!(
+                "⚠️ {} claims to be trusted device {} but couldn't prove it",
+                addr.ip(),
+                device_id
+            );
+            return None;
+        }
+
+        debug_log!(
+            "{} Transfer request of trusted device {} {}",
+            if accepted { "✅" } else { "🚫" },
+            device_id,
+            if accepted { "accepted" } else { "rejected" }
+        );
+
+        Some(accepted)
+    }
+
+    // Name the user gave a file when accepting it, if they renamed it
+    fn renamed_file(&self, transfer_id: &str, file_id: &str) -> Option<String> {
+        self.approved_file(transfer_id, file_id)
+            .and_then(|file| file.rename)
+    }
+
+    // Check the token of a request about an accepted transfer
+    fn authorize(&self, transfer_id: &str, token: &str) -> Result<(), Rejection> {
+        match self
+            .inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+        {
+            Some(transfer) if tokens_match(&transfer.token, token) => Ok(()),
+            _ => {
+                debug_log!("⛔ Refusing request for unknown transfer {}", transfer_id);
+                Err(warp::reject::custom(Forbidden))
             }
+        }
+    }
+
+    // Whether a transfer was accepted and its files can still be uploaded
+    fn is_approved(&self, transfer_id: &str) -> bool {
+        self.inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .contains_key(transfer_id)
+    }
+
+    // A file of an accepted transfer
+    fn approved_file(&self, transfer_id: &str, file_id: &str) -> Option<ApprovedFile> {
+        self.inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .and_then(|transfer| transfer.files.get(file_id))
+            .cloned()
+    }
+
+    // Speed limits for the uploads of a transfer we receive, including those of its sender
+    fn receive_throttle(&self, transfer_id: &str) -> Throttle {
+        let sender_id = self
+            .inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .and_then(|transfer| transfer.sender_id.clone());
+
+        self.download_throttle(transfer_id, sender_id.as_deref())
+    }
+
+    // Refuse compressed uploads of a transfer that wasn't accepted with that compression
+    fn check_compression(
+        &self,
+        transfer_id: &str,
+        compression: Option<Compression>,
+    ) -> Result<(), Rejection> {
+        let Some(compression) = compression else {
+            return Ok(());
         };
-        debug_log!("🔒🖥️ Starting HTTPS server on https://0.0.0.0:21212");
-        // start the https server
-        warp::serve(routes)
-            .tls()
-            .cert(cert_bytes)
-            .key(key_bytes)
-            .run(([0, 0, 0, 0], port))
-            .await;
+
+        let negotiated = self
+            .inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .and_then(|transfer| transfer.compression);
+        if negotiated != Some(compression) {
+            debug_log!(
+                "⛔ Refusing upload compressed with {}, it wasn't negotiated",
+                compression.as_str()
+            );
+            return Err(warp::reject::custom(BadRequest));
+        }
+
+        Ok(())
+    }
+
+    // Refuse uploads that don't match a file the user accepted or that was already received
+    fn check_upload(&self, params: &UploadParams, filename: &str) -> Result<(), Rejection> {
+        self.authorize(&params.transfer_id, &params.token)?;
+        if self.is_cancelled(&params.transfer_id) {
+            return Err(warp::reject::custom(Cancelled));
+        }
+        self.check_compression(&params.transfer_id, params.compression)?;
+
+        let approved_transfers = self.inner.approved_transfers.lock().unwrap();
+        let approved = approved_transfers
+            .get(&params.transfer_id)
+            .and_then(|transfer| transfer.files.get(&params.file_id))
+            .is_some_and(|file| {
+                !file.received
+                    && file.size == params.size
+                    && file.relative_path == params.path
+                    && sanitize_filename::sanitize(&file.name)
+                        == sanitize_filename::sanitize(filename)
+            });
+
+        if !approved {
+            debug_log!(
+                "⛔ Refusing upload of {} ({} bytes), it wasn't approved",
+                filename,
+                params.size
+            );
+            return Err(warp::reject::custom(Forbidden));
+        }
+
+        Ok(())
+    }
+
+    // Check a received file against the hash from its transfer request
+    fn verify_hash(&self, params: &UploadParams, hasher: FileHasher) -> bool {
+        let approved_transfers = self.inner.approved_transfers.lock().unwrap();
+        let Some(file) = approved_transfers
+            .get(&params.transfer_id)
+            .and_then(|transfer| transfer.files.get(&params.file_id))
+        else {
+            return false;
+        };
+
+        let actual = integrity::to_hex(hasher);
+        if actual != file.hash {
+            debug_log!(
+                "❌ Hash mismatch for {}, expected {} got {}",
+                params.file_id,
+                file.hash,
+                actual
+            );
+            return false;
+        }
+
+        true
+    }
+
+    // Remember that a file is saved where it belongs, it can't be uploaded again.
+    // The transfer is done with its last file.
+    fn mark_received(&self, params: &UploadParams) {
+        let mut approved_transfers = self.inner.approved_transfers.lock().unwrap();
+        let Some(transfer) = approved_transfers.get_mut(&params.transfer_id) else {
+            return;
+        };
+        if let Some(file) = transfer.files.get_mut(&params.file_id) {
+            file.received = true;
+        }
+        let complete = transfer.files.values().all(|file| file.received);
+        drop(approved_transfers);
+
+        if complete {
+            self.finish_transfer(&params.transfer_id);
+            self.forget_transfer_later(&params.transfer_id);
+        }
+    }
+
+    // Remember a chunk that was written, returns true for the chunk that completes the file
+    fn record_chunk(&self, params: &UploadParams, start: u64, end: u64) -> bool {
+        let mut approved_transfers = self.inner.approved_transfers.lock().unwrap();
+        let Some(file) = approved_transfers
+            .get_mut(&params.transfer_id)
+            .and_then(|transfer| transfer.files.get_mut(&params.file_id))
+        else {
+            return false;
+        };
+
+        let was_complete = file.chunks == [(0, file.size)];
+        add_range(&mut file.chunks, start, end);
+
+        !was_complete && file.chunks == [(0, file.size)]
+    }
+
+    // Chunks received of a file, empty when it isn't sent in chunks
+    fn received_chunks(&self, transfer_id: &str, file_id: &str) -> Vec<(u64, u64)> {
+        self.inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .and_then(|transfer| transfer.files.get(file_id))
+            .map(|file| file.chunks.clone())
+            .unwrap_or_default()
+    }
+
+    // Forget a chunk that completed a file which couldn't be finished, the sender
+    // sends it again
+    fn forget_chunk(&self, params: &UploadParams, start: u64, end: u64) {
+        if let Some(file) = self
+            .inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get_mut(&params.transfer_id)
+            .and_then(|transfer| transfer.files.get_mut(&params.file_id))
+        {
+            remove_range(&mut file.chunks, start, end);
+        }
+    }
+
+    // Forget the chunks of a corrupted file, the sender has to send all of them again
+    fn reset_chunks(&self, params: &UploadParams) {
+        if let Some(file) = self
+            .inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get_mut(&params.transfer_id)
+            .and_then(|transfer| transfer.files.get_mut(&params.file_id))
+        {
+            file.chunks.clear();
+        }
+    }
+
+    // Directory a received file goes to: the one picked when accepting the transfer,
+    // the first matching destination rule or the receive directory, in that order.
+    // None means the downloads directory.
+    fn destination_dir(&self, transfer_id: &str, mime_type: &str) -> Option<PathBuf> {
+        let (picked_dir, sender_id) = self
+            .inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .map(|transfer| (transfer.receive_dir.clone(), transfer.sender_id.clone()))
+            .unwrap_or_default();
+
+        let settings = self.settings().get();
+        picked_dir
+            .or_else(|| {
+                settings
+                    .matching_rule_dir(sender_id.as_deref(), mime_type)
+                    .map(PathBuf::from)
+            })
+            .or_else(|| self.config().download_dir.clone())
+            .or(settings.receive_dir)
+    }
+
+    // Remove the partial files of a transfer
+    pub(crate) async fn remove_partials(&self, transfer_id: &str) {
+        utils::remove_partials(self, transfer_id).await;
+    }
+
+    // Ask the user about a request through an event and wait for the answer. The
+    // request is forgotten once nobody waits for it anymore, whether it was answered,
+    // timed out or the peer gave up on it.
+    pub(crate) async fn ask_user(
+        &self,
+        req_id: &str,
+        event: &str,
+        payload: serde_json::Value,
+        timeout: Duration,
+    ) -> Result<RequestAnswer, Rejection> {
+        let (tx, rx) = oneshot::channel::<RequestAnswer>();
+        self.inner
+            .pending_requests
+            .lock()
+            .unwrap()
+            .insert(req_id.to_string(), tx);
+        let _pending = PendingRequest {
+            tranzit: self,
+            id: req_id,
+        };
+
+        self.emit(event, payload);
+
+        tokio::time::timeout(timeout, rx)
+            .await
+            .map_err(|_| warp::reject::custom(InternalServerError))?
+            .map_err(|_| warp::reject::custom(InternalServerError))
+    }
+
+    // Forget a transfer after a while, once it is complete or cancelled, along with
+    // what's left of its partial files. Until then late uploads of it are still
+    // answered, a cancelled one keeps refusing them.
+    pub(crate) fn forget_transfer_later(&self, transfer_id: &str) {
+        let tranzit = self.clone();
+        let transfer_id = transfer_id.to_string();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(APPROVAL_GRACE) => {}
+                _ = tranzit.inner.shutdown.cancelled() => return,
+            }
+            tranzit.forget_transfer(&transfer_id).await;
+        });
+    }
+
+    // Forget an accepted transfer once nothing of it arrived for a while, it's
+    // left alone once it is complete or cancelled
+    fn forget_transfer_when_idle(&self, transfer_id: &str) {
+        let tranzit = self.clone();
+        let transfer_id = transfer_id.to_string();
+        let progress = self.transfer_progress(&transfer_id, Direction::Receive);
+        tokio::spawn(async move {
+            let mut bytes = progress.bytes();
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(SENDER_TIMEOUT) => {}
+                    _ = tranzit.inner.shutdown.cancelled() => return,
+                }
+                let complete = match tranzit
+                    .inner
+                    .approved_transfers
+                    .lock()
+                    .unwrap()
+                    .get(&transfer_id)
+                {
+                    Some(transfer) => transfer.files.values().all(|file| file.received),
+                    None => return,
+                };
+                if complete || tranzit.is_cancelled(&transfer_id) {
+                    return;
+                }
+
+                let received = progress.bytes();
+                if received == bytes {
+                    break;
+                }
+                bytes = received;
+            }
+
+            debug_log!("⌛ Nothing of transfer {} arrived in a while", transfer_id);
+            tranzit.forget_transfer(&transfer_id).await;
+        });
+    }
+
+    // Drop a transfer and its partial files, one that didn't finish is recorded
+    // as failed as the sender won't get to finish it anymore
+    async fn forget_transfer(&self, transfer_id: &str) {
+        self.inner
+            .approved_transfers
+            .lock()
+            .unwrap()
+            .remove(transfer_id);
+        self.inner.transfers.lock().unwrap().remove(transfer_id);
+        self.record_failed(transfer_id, "Sender stopped sending");
+        self.remove_partials(transfer_id).await;
+    }
+
+    // Answer a pending file transfer or pair request with the choice of the user,
+    // accepted transfers are saved to receive_dir when given and only the picked
+    // files are received when there are any
+    pub fn respond_to_request(
+        &self,
+        id: &str,
+        data: String,
+        receive_dir: Option<PathBuf>,
+        files: Option<Vec<FileChoice>>,
+    ) {
+        if let Some(sender) = self.inner.pending_requests.lock().unwrap().remove(id) {
+            let _ = sender.send(RequestAnswer {
+                answer: data,
+                receive_dir,
+                files,
+            });
+        }
+    }
+}
+
+// Stop receiving a transfer the sender cancelled
+async fn handle_cancel(
+    transfer_id: String,
+    params: TokenParams,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    tranzit.authorize(&transfer_id, &params.token)?;
+
+    tranzit.cancel_locally(&transfer_id).await;
+
+    Ok(warp::reply::with_status(
+        "Transfer cancelled",
+        StatusCode::OK,
+    ))
+}
+
+// Ask the user to accept or reject incoming files and reply with their choice
+async fn handle_file_transfer_request(
+    req_body: FileTransferRequest,
+    remote: Option<SocketAddr>,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    // uploads of a file that can't be received would only fail once the user accepted it
+    if !is_valid_request(&req_body) {
+        return Err(warp::reject::custom(BadRequest));
+    }
+
+    let req_id = Uuid::new_v4().to_string();
+
+    // trusted devices get an answer right away, everyone else waits for the user
+    let response = match tranzit.auto_answer(&req_body, remote).await {
+        Some(accepted) => {
+            tranzit.emit(
+                "file-transfer-auto-answered",
+                serde_json::json!({
+                    "id": req_id.clone(),
+                    "data": req_body,
+                    "accepted": accepted,
+                }),
+            );
+            RequestAnswer {
+                answer: if accepted { "accepted" } else { "rejected" }.into(),
+                receive_dir: None,
+                files: None,
+            }
+        }
+        None => {
+            let payload = serde_json::json!({
+                "id": req_id.clone(),
+                "data": req_body
+            });
+            tranzit
+                .ask_user(&req_id, "file-transfer-request", payload, REQUEST_TIMEOUT)
+                .await?
+        }
+    };
+
+    // accepting none of the files is the same as rejecting the request
+    let accepted_files = if response.answer == "accepted" {
+        accepted_files(&req_body, response.files)
+    } else {
+        HashMap::new()
+    };
+    let accepted = !accepted_files.is_empty();
+    let response = if accepted {
+        tranzit.approve_transfer(&req_body, &accepted_files, response.receive_dir)
+    } else {
+        TransferResponse::Rejected
+    };
+    // declined requests have no transfer id, the request id stands in for the history
+    let history_id = match &response {
+        TransferResponse::Accepted { transfer_id, .. } => transfer_id.clone(),
+        TransferResponse::Rejected => req_id,
+    };
+
+    let peer = HistoryPeer {
+        device_id: req_body.device_id.clone(),
+        hostname: Some(req_body.sys_info.hostname.clone()),
+        ip: remote.map(|addr| network::ip_string(&addr)),
+    };
+    let files = req_body
+        .files_info
+        .iter()
+        .map(|file| {
+            let rename = accepted_files.get(&file.file_id);
+            HistoryFile {
+                file_id: file.file_id.clone(),
+                name: rename
+                    .cloned()
+                    .flatten()
+                    .unwrap_or_else(|| file.name.clone()),
+                relative_path: file.relative_path.clone(),
+                size: file.size,
+                hash: file.hash.clone(),
+                path: None,
+                outcome: match rename {
+                    Some(_) => Outcome::InProgress,
+                    None => Outcome::Declined,
+                },
+            }
+        })
+        .collect();
+    tranzit.record_receiving(&history_id, peer, files, accepted);
+
+    Ok(warp::reply::json(&response))
+}
+
+// Whether every file of a transfer request could be uploaded: ids end up in the paths
+// of partial files, relative paths have to stay inside the download directory and
+// hashes are compared against to_hex once a file is in
+fn is_valid_request(request: &FileTransferRequest) -> bool {
+    let mut file_ids = HashSet::new();
+    request.files_info.iter().all(|file| {
+        let valid = is_valid_id(&file.file_id)
+            && file_ids.insert(file.file_id.as_str())
+            && file
+                .relative_path
+                .as_deref()
+                .is_none_or(|path| safe_relative_path(path).is_some())
+            && integrity::is_valid_hex(&file.hash);
+        if !valid {
+            debug_log!("Transfer request with a malformed file {}", file.file_id);
+        }
+        valid
+    })
+}
+
+// A file name with a number added to tell it apart, e.g. "photo (2).jpg"
+fn numbered_name(filename: &str, number: u32) -> String {
+    let path = Path::new(filename);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("file");
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if !ext.is_empty() => format!("{} ({}).{}", stem, number, ext),
+        _ => format!("{} ({})", stem, number),
+    }
+}
+
+// Files the user accepted out of a transfer request along with the names they gave
+// them, keyed on file id. Every file is accepted when the user didn't pick any.
+fn accepted_files(
+    request: &FileTransferRequest,
+    choices: Option<Vec<FileChoice>>,
+) -> HashMap<String, Option<String>> {
+    let mut accepted: HashMap<String, Option<String>> = match choices {
+        None => request
+            .files_info
+            .iter()
+            .map(|file| (file.file_id.clone(), None))
+            .collect(),
+        Some(choices) => choices
+            .into_iter()
+            .filter(|choice| {
+                request
+                    .files_info
+                    .iter()
+                    .any(|file| file.file_id == choice.file_id)
+            })
+            .map(|choice| {
+                // a new name can't point anywhere else, blank ones keep the original
+                let rename = choice
+                    .name
+                    .map(|name| sanitize_filename::sanitize(name.trim()))
+                    .filter(|name| !name.is_empty());
+                (choice.file_id, rename)
+            })
+            .collect(),
+    };
+
+    // files going to the same folder get different names, e.g. when two were renamed
+    // to the same one, so they don't race for one path when they are saved
+    let mut taken = HashSet::new();
+    for file in &request.files_info {
+        let Some(rename) = accepted.get_mut(&file.file_id) else {
+            continue;
+        };
+
+        let mut dirs = file
+            .relative_path
+            .as_deref()
+            .and_then(safe_relative_path)
+            .unwrap_or_default();
+        let original = dirs
+            .pop()
+            .unwrap_or_else(|| sanitize_filename::sanitize(&file.name));
+        let name = rename.clone().unwrap_or(original);
+
+        let mut unique = name.clone();
+        let mut number = 1;
+        while !taken.insert((dirs.clone(), unique.to_lowercase())) {
+            unique = numbered_name(&name, number);
+            number += 1;
+        }
+        if unique != name {
+            *rename = Some(unique);
+        }
+    }
+
+    accepted
+}
+
+// Ask the user to accept a message and reply with their choice, the front-end
+// puts an accepted message on the clipboard or shows it
+async fn handle_message(
+    req_body: MessageRequest,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    let req_id = Uuid::new_v4().to_string();
+    let payload = serde_json::json!({
+        "id": req_id.clone(),
+        "data": req_body
+    });
+    let response = tranzit
+        .ask_user(&req_id, "message-request", payload, REQUEST_TIMEOUT)
+        .await?;
+
+    Ok(warp::reply::json(&response.answer))
+}
+
+// Start pairing with a peer that proves it holds the certificate it wants pinned,
+// it gets our nonce in exchange for its commitment
+async fn handle_pair_request(
+    request: PairRequest,
+    remote: Option<SocketAddr>,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    // the certificate to pin is read from a connection back to the requester, so
+    // nobody can ask to pair with the fingerprint of another device
+    let mut addr = network::canonical(remote.ok_or(warp::reject::custom(BadRequest))?);
+    addr.set_port(request.port.unwrap_or(DEFAULT_HTTPS_PORT));
+    let peer = http_requests::fetch_identity(&network::ip_string(&addr), addr.port())
+        .await
+        .map_err(|e| {
+            debug_log!("Could not reach {} to check its certificate: {}", addr, e);
+            warp::reject::custom(BadRequest)
+        })?;
+    if peer.fingerprint != request.fingerprint || peer.device_id != request.device_id {
+        debug_log!(
+            "⚠️ {} asked to pair as {} but presents the certificate of {}",
+            addr,
+            request.device_id,
+            peer.device_id
+        );
+        return Err(warp::reject::custom(BadRequest));
+    }
+
+    // the code can't be shown before the peer reveals the nonce it committed to
+    let session = Uuid::new_v4().to_string();
+    let nonce = Uuid::new_v4().simple().to_string();
+    {
+        let mut sessions = tranzit.inner.pairings.lock().unwrap();
+        sessions.retain(|_, pairing| pairing.started.elapsed() < PAIRING_TIMEOUT);
+        sessions.insert(
+            session.clone(),
+            PairingSession {
+                peer,
+                commitment: request.commitment,
+                nonce: nonce.clone(),
+                started: Instant::now(),
+            },
+        );
+    }
+
+    Ok(warp::reply::json(&PairChallenge { session, nonce }))
+}
+
+// Check the nonce a peer committed to, then ask the user to confirm the verification
+// code and pin the certificate fingerprint of the peer once accepted
+async fn handle_pair_confirm(
+    reveal: PairReveal,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    let pairing = tranzit
+        .inner
+        .pairings
+        .lock()
+        .unwrap()
+        .remove(&reveal.session)
+        .filter(|pairing| pairing.started.elapsed() < PAIRING_TIMEOUT)
+        .ok_or(warp::reject::custom(BadRequest))?;
+    if identity::commitment(&reveal.nonce) != pairing.commitment {
+        debug_log!(
+            "⚠️ {} revealed another nonce than it committed to",
+            pairing.peer.device_id
+        );
+        return Err(warp::reject::custom(BadRequest));
+    }
+
+    let peer = pairing.peer;
+    let code = identity::verification_code(
+        &peer.fingerprint,
+        &tranzit.identity().fingerprint,
+        &reveal.nonce,
+        &pairing.nonce,
+    );
+    let req_id = Uuid::new_v4().to_string();
+    let payload = serde_json::json!({
+        "id": req_id.clone(),
+        "device_id": peer.device_id,
+        "hostname": peer.hostname,
+        "code": code,
     });
+    let response = tranzit
+        .ask_user(&req_id, "pair-request", payload, PAIRING_TIMEOUT)
+        .await?;
+
+    if response.answer == "accepted" {
+        tranzit
+            .paired_devices()
+            .pin(&peer.device_id, &peer.hostname, &peer.fingerprint);
+    }
+
+    Ok(warp::reply::json(&response.answer))
 }
 
-#[tauri::command]
-pub async fn respond_to_request(id: String, data: String) {
-    if let Some(sender) = PENDING_REQUESTS.lock().unwrap().remove(&id) {
-        let _ = sender.send(data);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{AutoAcceptPolicy, Settings, TrustedDevice};
+    use crate::test_support::instance;
+
+    fn components(parts: &[&str]) -> Option<Vec<String>> {
+        Some(parts.iter().map(|part| part.to_string()).collect())
+    }
+
+    #[test]
+    fn ids_are_canonical_uuids() {
+        assert!(is_valid_id("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+
+        for id in [
+            "67E55044-10B1-426F-9247-BB680E5FE0C8",
+            "67e5504410b1426f9247bb680e5fe0c8",
+            "{67e55044-10b1-426f-9247-bb680e5fe0c8}",
+            "urn:uuid:67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "../67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "",
+        ] {
+            assert!(!is_valid_id(id), "{} was accepted", id);
+        }
+    }
+
+    #[test]
+    fn requests_of_files_that_cant_be_uploaded_are_refused() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let request = |files: &[(&str, Option<&str>)]| {
+            let mut request = transfer_request(
+                &files
+                    .iter()
+                    .map(|&(file_id, path)| (file_id, "a.txt", path))
+                    .collect::<Vec<_>>(),
+            );
+            for file in &mut request.files_info {
+                file.hash = hash.into();
+            }
+            request
+        };
+
+        assert!(is_valid_request(&request(&[
+            (FILE_A, None),
+            (FILE_B, Some("photos/a.txt"))
+        ])));
+        assert!(!is_valid_request(&request(&[
+            (FILE_A, None),
+            (FILE_A, None)
+        ])));
+        assert!(!is_valid_request(&request(&[("../a", None)])));
+        assert!(!is_valid_request(&request(&[(FILE_A, Some("../a.txt"))])));
+
+        let mut bad_hash = request(&[(FILE_A, None)]);
+        bad_hash.files_info[0].hash = "abc".into();
+        assert!(!is_valid_request(&bad_hash));
+    }
+
+    #[test]
+    fn relative_paths_keep_their_components() {
+        assert_eq!(safe_relative_path("a.txt"), components(&["a.txt"]));
+        assert_eq!(
+            safe_relative_path("photos/2024/a.jpg"),
+            components(&["photos", "2024", "a.jpg"])
+        );
+        assert_eq!(
+            safe_relative_path("photos\\2024\\a.jpg"),
+            components(&["photos", "2024", "a.jpg"])
+        );
+        assert_eq!(
+            safe_relative_path("photos/2024\\a.jpg"),
+            components(&["photos", "2024", "a.jpg"])
+        );
+    }
+
+    #[test]
+    fn parent_and_current_directories_are_refused() {
+        assert_eq!(safe_relative_path(".."), None);
+        assert_eq!(safe_relative_path("../a.txt"), None);
+        assert_eq!(safe_relative_path("photos/../../a.txt"), None);
+        assert_eq!(safe_relative_path("photos\\..\\a.txt"), None);
+        assert_eq!(safe_relative_path("./a.txt"), None);
+    }
+
+    #[test]
+    fn absolute_paths_are_refused() {
+        assert_eq!(safe_relative_path("/etc/passwd"), None);
+        assert_eq!(safe_relative_path("\\Windows\\win.ini"), None);
+        // UNC paths
+        assert_eq!(safe_relative_path("\\\\server\\share\\a.txt"), None);
+        assert_eq!(safe_relative_path("//server/share/a.txt"), None);
+    }
+
+    #[test]
+    fn drive_letters_stay_inside() {
+        for path in ["C:\\Windows\\win.ini", "C:/Windows/win.ini", "C:win.ini"] {
+            let components = safe_relative_path(path).unwrap();
+            assert!(
+                components.iter().all(|c| !c.contains(':')),
+                "{} gave {:?}",
+                path,
+                components
+            );
+        }
+    }
+
+    #[test]
+    fn empty_components_are_refused() {
+        assert_eq!(safe_relative_path(""), None);
+        assert_eq!(safe_relative_path("photos//a.txt"), None);
+        assert_eq!(safe_relative_path("photos/"), None);
+        assert_eq!(safe_relative_path("photos\\\\a.txt"), None);
+    }
+
+    #[test]
+    fn nul_bytes_are_stripped_or_refused() {
+        assert_eq!(safe_relative_path("\0"), None);
+        assert_eq!(safe_relative_path("photos/\0"), None);
+        assert_eq!(safe_relative_path("a\0.txt"), components(&["a.txt"]));
+    }
+
+    #[test]
+    fn ranges_in_order_are_kept() {
+        let mut ranges = Vec::new();
+        add_range(&mut ranges, 0, 10);
+        add_range(&mut ranges, 20, 30);
+        assert_eq!(ranges, [(0, 10), (20, 30)]);
+    }
+
+    #[test]
+    fn adjacent_ranges_are_merged() {
+        let mut ranges = vec![(0, 10)];
+        add_range(&mut ranges, 10, 20);
+        assert_eq!(ranges, [(0, 20)]);
+
+        add_range(&mut ranges, 30, 40);
+        add_range(&mut ranges, 20, 30);
+        assert_eq!(ranges, [(0, 40)]);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let mut ranges = vec![(0, 10), (20, 30)];
+        add_range(&mut ranges, 5, 25);
+        assert_eq!(ranges, [(0, 30)]);
+
+        // one inside another
+        add_range(&mut ranges, 10, 15);
+        assert_eq!(ranges, [(0, 30)]);
+    }
+
+    #[test]
+    fn out_of_order_ranges_are_sorted() {
+        let mut ranges = Vec::new();
+        add_range(&mut ranges, 40, 50);
+        add_range(&mut ranges, 0, 10);
+        add_range(&mut ranges, 20, 30);
+        assert_eq!(ranges, [(0, 10), (20, 30), (40, 50)]);
+    }
+
+    #[test]
+    fn removed_ranges_split_the_rest() {
+        let mut ranges = vec![(0, 100)];
+        remove_range(&mut ranges, 40, 60);
+        assert_eq!(ranges, [(0, 40), (60, 100)]);
+
+        remove_range(&mut ranges, 0, 40);
+        assert_eq!(ranges, [(60, 100)]);
+
+        // adding it back makes the file whole again
+        add_range(&mut ranges, 0, 60);
+        assert_eq!(ranges, [(0, 100)]);
+
+        remove_range(&mut ranges, 90, 120);
+        assert_eq!(ranges, [(0, 90)]);
+    }
+
+    #[test]
+    fn full_coverage_is_one_range() {
+        let mut ranges = Vec::new();
+        for start in [30, 10, 0, 20] {
+            add_range(&mut ranges, start, start + 10);
+        }
+        assert_eq!(ranges, [(0, 40)]);
+    }
+
+    const FILE_A: &str = "0b0ee1c6-2d44-4a8a-9e8b-7f6d5c4b3a21";
+    const FILE_B: &str = "5f7a9c1e-8b3d-4e6f-a1c2-d3e4f5a6b7c8";
+
+    fn batch_body(pieces: Vec<Vec<u8>>) -> impl Stream<Item = std::io::Result<Bytes>> + Unpin {
+        futures_util::stream::iter(pieces.into_iter().map(|piece| Ok(Bytes::from(piece))))
+    }
+
+    fn batch_file(file_id: &str, size: u64, contents: &[u8]) -> Vec<u8> {
+        let mut entry = file_id.as_bytes().to_vec();
+        entry.extend_from_slice(&size.to_be_bytes());
+        entry.extend_from_slice(contents);
+        entry
+    }
+
+    async fn read_file<S>(reader: &mut BatchReader<S>, size: u64) -> Result<Vec<u8>, Rejection>
+    where
+        S: Stream<Item = std::io::Result<Bytes>> + Unpin,
+    {
+        let mut contents = Vec::new();
+        while (contents.len() as u64) < size {
+            contents.extend_from_slice(&reader.read(size - contents.len() as u64).await?);
+        }
+        Ok(contents)
+    }
+
+    #[tokio::test]
+    async fn batch_files_are_read_across_pieces() {
+        let mut body = batch_file(FILE_A, 5, b"hello");
+        body.extend(batch_file(FILE_B, 0, b""));
+        body.extend(batch_file(FILE_A, 3, b"abc"));
+        // split at every few bytes, headers included
+        let mut reader = BatchReader::new(batch_body(body.chunks(7).map(<[u8]>::to_vec).collect()));
+
+        assert_eq!(reader.next_file().await.unwrap(), Some((FILE_A.into(), 5)));
+        assert_eq!(read_file(&mut reader, 5).await.unwrap(), b"hello");
+        assert_eq!(reader.next_file().await.unwrap(), Some((FILE_B.into(), 0)));
+        assert_eq!(reader.next_file().await.unwrap(), Some((FILE_A.into(), 3)));
+        assert_eq!(read_file(&mut reader, 3).await.unwrap(), b"abc");
+        assert_eq!(reader.next_file().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn empty_batch_has_no_files() {
+        let mut reader = BatchReader::new(batch_body(Vec::new()));
+        assert_eq!(reader.next_file().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_header_is_refused() {
+        let header = batch_file(FILE_A, 5, b"");
+        let mut reader = BatchReader::new(batch_body(vec![header[..20].to_vec()]));
+        assert!(reader.next_file().await.is_err());
+
+        let mut reader =
+            BatchReader::new(batch_body(vec![header[..BATCH_HEADER_LEN - 1].to_vec()]));
+        assert!(reader.next_file().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_file_id_is_refused() {
+        let entry = batch_file("../../../../../../../../etc/passwd00", 5, b"hello");
+        let mut reader = BatchReader::new(batch_body(vec![entry]));
+        assert!(reader.next_file().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn short_body_is_refused() {
+        let mut reader = BatchReader::new(batch_body(vec![batch_file(FILE_A, 10, b"hello")]));
+
+        assert_eq!(reader.next_file().await.unwrap(), Some((FILE_A.into(), 10)));
+        assert!(read_file(&mut reader, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn file_never_reads_past_its_size() {
+        // announced smaller than what was sent, the rest is read as the next header
+        let mut body = batch_file(FILE_A, 3, b"hello");
+        body.extend(batch_file(FILE_B, 1, b"x"));
+        let mut reader = BatchReader::new(batch_body(vec![body]));
+
+        assert_eq!(reader.next_file().await.unwrap(), Some((FILE_A.into(), 3)));
+        assert_eq!(read_file(&mut reader, 3).await.unwrap(), b"hel");
+        assert!(reader.next_file().await.is_err());
+    }
+
+    fn transfer_request(files: &[(&str, &str, Option<&str>)]) -> FileTransferRequest {
+        FileTransferRequest {
+            files_info: files
+                .iter()
+                .map(|&(file_id, name, relative_path)| FilesInfo {
+                    name: name.into(),
+                    size: 1,
+                    relative_path: relative_path.map(String::from),
+                    file_id: file_id.into(),
+                    hash: String::new(),
+                })
+                .collect(),
+            sys_info: DeviceInfo {
+                hostname: "sender".into(),
+                os_type: "test".into(),
+            },
+            receiver_info: String::new(),
+            device_id: None,
+            port: None,
+            compression: Vec::new(),
+        }
+    }
+
+    // Accept a transfer of one file, returns the upload of it that is approved
+    fn approved_upload(tranzit: &Tranzit) -> UploadParams {
+        let request = transfer_request(&[(FILE_A, "a.txt", Some("photos/a.txt"))]);
+        let TransferResponse::Accepted {
+            transfer_id, token, ..
+        } = tranzit.approve_transfer(&request, &renames(&[(FILE_A, None)]), None)
+        else {
+            panic!("transfer wasn't accepted");
+        };
+
+        UploadParams {
+            transfer_id,
+            token,
+            file_id: FILE_A.into(),
+            offset: 0,
+            size: 1,
+            path: Some("photos/a.txt".into()),
+            length: None,
+            compression: None,
+        }
+    }
+
+    fn is_forbidden(result: Result<(), Rejection>) -> bool {
+        result.is_err_and(|err| err.find::<Forbidden>().is_some())
+    }
+
+    #[tokio::test]
+    async fn approved_upload_is_let_through() {
+        let (tranzit, _events, _dir) = instance();
+        let params = approved_upload(&tranzit);
+
+        assert!(tranzit.check_upload(&params, "a.txt").is_ok());
+    }
+
+    #[tokio::test]
+    async fn uploads_not_matching_the_request_are_refused() {
+        let (tranzit, _events, _dir) = instance();
+        let params = approved_upload(&tranzit);
+
+        let mut token = params.token.clone().into_bytes();
+        token[0] ^= 1;
+        let wrong_token = UploadParams {
+            token: String::from_utf8(token).unwrap(),
+            ..params.clone()
+        };
+        assert!(is_forbidden(tranzit.check_upload(&wrong_token, "a.txt")));
+        let short_token = UploadParams {
+            token: params.token[..10].to_string(),
+            ..params.clone()
+        };
+        assert!(is_forbidden(tranzit.check_upload(&short_token, "a.txt")));
+
+        assert!(is_forbidden(tranzit.check_upload(&params, "b.txt")));
+
+        let wrong_size = UploadParams {
+            size: 2,
+            ..params.clone()
+        };
+        assert!(is_forbidden(tranzit.check_upload(&wrong_size, "a.txt")));
+
+        for path in [Some("other/a.txt".to_string()), None] {
+            let wrong_path = UploadParams {
+                path,
+                ..params.clone()
+            };
+            assert!(is_forbidden(tranzit.check_upload(&wrong_path, "a.txt")));
+        }
+
+        let other_file = UploadParams {
+            file_id: FILE_B.into(),
+            ..params.clone()
+        };
+        assert!(is_forbidden(tranzit.check_upload(&other_file, "a.txt")));
+    }
+
+    #[tokio::test]
+    async fn received_file_cant_be_uploaded_again() {
+        let (tranzit, _events, _dir) = instance();
+        let params = approved_upload(&tranzit);
+
+        tranzit.mark_received(&params);
+        assert!(is_forbidden(tranzit.check_upload(&params, "a.txt")));
+    }
+
+    #[tokio::test]
+    async fn uploads_of_a_cancelled_transfer_are_refused() {
+        let (tranzit, _events, _dir) = instance();
+        let params = approved_upload(&tranzit);
+
+        tranzit.cancel_locally(&params.transfer_id).await;
+        assert!(tranzit
+            .check_upload(&params, "a.txt")
+            .is_err_and(|err| err.find::<Cancelled>().is_some()));
+    }
+
+    fn choice(file_id: &str, name: Option<&str>) -> FileChoice {
+        FileChoice {
+            file_id: file_id.into(),
+            name: name.map(String::from),
+        }
+    }
+
+    fn renames(pairs: &[(&str, Option<&str>)]) -> HashMap<String, Option<String>> {
+        pairs
+            .iter()
+            .map(|&(file_id, name)| (file_id.into(), name.map(String::from)))
+            .collect()
+    }
+
+    #[test]
+    fn every_file_is_accepted_without_choices() {
+        let request = transfer_request(&[("1", "a.txt", None), ("2", "b.txt", None)]);
+        assert_eq!(
+            accepted_files(&request, None),
+            renames(&[("1", None), ("2", None)])
+        );
+    }
+
+    #[test]
+    fn unticked_files_are_left_out() {
+        let request = transfer_request(&[("1", "a.txt", None), ("2", "b.txt", None)]);
+        assert_eq!(
+            accepted_files(&request, Some(vec![choice("2", None)])),
+            renames(&[("2", None)])
+        );
+        assert!(accepted_files(&request, Some(Vec::new())).is_empty());
+        // files that weren't in the request can't be picked
+        assert_eq!(
+            accepted_files(&request, Some(vec![choice("3", None), choice("1", None)])),
+            renames(&[("1", None)])
+        );
+    }
+
+    #[test]
+    fn renames_stay_in_their_folder() {
+        let request =
+            transfer_request(&[("1", "a.txt", None), ("2", "b.txt", Some("photos/b.txt"))]);
+        let accepted = accepted_files(
+            &request,
+            Some(vec![
+                choice("1", Some("../../etc/passwd")),
+                choice("2", Some("sub\\dir\\c.txt")),
+            ]),
+        );
+
+        for rename in accepted.values() {
+            let rename = rename.as_deref().unwrap();
+            assert!(
+                !rename.contains(['/', '\\']) && rename != "..",
+                "{} could point elsewhere",
+                rename
+            );
+        }
+    }
+
+    #[test]
+    fn blank_renames_keep_the_original_name() {
+        let request = transfer_request(&[("1", "a.txt", None), ("2", "b.txt", None)]);
+        assert_eq!(
+            accepted_files(
+                &request,
+                Some(vec![choice("1", Some("  ")), choice("2", Some("/"))])
+            ),
+            renames(&[("1", None), ("2", None)])
+        );
+    }
+
+    #[test]
+    fn renames_to_the_same_name_are_told_apart() {
+        let request = transfer_request(&[
+            ("1", "a.txt", None),
+            ("2", "b.txt", None),
+            ("3", "c.txt", None),
+        ]);
+        let accepted = accepted_files(
+            &request,
+            Some(vec![
+                choice("1", Some("same.txt")),
+                choice("2", Some("same.txt")),
+                choice("3", Some("SAME.txt")),
+            ]),
+        );
+        assert_eq!(
+            accepted,
+            renames(&[
+                ("1", Some("same.txt")),
+                ("2", Some("same (1).txt")),
+                ("3", Some("SAME (2).txt")),
+            ])
+        );
+    }
+
+    #[test]
+    fn renames_to_the_name_of_another_file_are_told_apart() {
+        let request = transfer_request(&[("1", "a.txt", None), ("2", "b.txt", None)]);
+        let accepted = accepted_files(
+            &request,
+            Some(vec![choice("1", None), choice("2", Some("a.txt"))]),
+        );
+        assert_eq!(accepted, renames(&[("1", None), ("2", Some("a (1).txt"))]));
+    }
+
+    #[test]
+    fn same_names_in_different_folders_are_kept() {
+        let request = transfer_request(&[
+            ("1", "a.txt", Some("one/a.txt")),
+            ("2", "b.txt", Some("two/b.txt")),
+            ("3", "a.txt", None),
+        ]);
+        let accepted = accepted_files(
+            &request,
+            Some(vec![
+                choice("1", None),
+                choice("2", Some("a.txt")),
+                choice("3", None),
+            ]),
+        );
+        assert_eq!(
+            accepted,
+            renames(&[("1", None), ("2", Some("a.txt")), ("3", None)])
+        );
+    }
+
+    #[test]
+    fn numbered_names_keep_the_extension() {
+        assert_eq!(numbered_name("photo.jpg", 2), "photo (2).jpg");
+        assert_eq!(numbered_name("archive.tar.gz", 1), "archive.tar (1).gz");
+        assert_eq!(numbered_name("README", 3), "README (3)");
+    }
+
+    // Have the receiver pin the certificate of the sender and answer its requests
+    // with the given policy
+    fn trust(receiver: &Tranzit, sender: &Tranzit, policy: AutoAcceptPolicy) {
+        let identity = sender.identity();
+        receiver
+            .paired_devices()
+            .pin(&identity.device_id, "sender", &identity.fingerprint);
+        receiver
+            .settings()
+            .set(Settings {
+                trusted_devices: vec![TrustedDevice {
+                    device_id: identity.device_id.clone(),
+                    policy,
+                    max_size: None,
+                    mime_types: Vec::new(),
+                }],
+                ..Settings::default()
+            })
+            .unwrap();
+    }
+
+    // Request of a device claiming the given id, connected back to on the given port
+    fn claimed_request(device_id: &str, port: u16) -> FileTransferRequest {
+        FileTransferRequest {
+            device_id: Some(device_id.into()),
+            port: Some(port),
+            ..transfer_request(&[(FILE_A, "a.txt", None)])
+        }
+    }
+
+    const REMOTE: &str = "127.0.0.1:50000";
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn trusted_device_is_answered_once_it_proves_who_it_is() {
+        let (receiver, _receiver_events, _receiver_dir) = instance();
+        let (sender, _sender_events, _sender_dir) = instance();
+        let port = sender.start_http_server().await.unwrap().port();
+        let request = claimed_request(&sender.identity().device_id, port);
+
+        for (policy, answer) in [
+            (AutoAcceptPolicy::Accept, Some(true)),
+            (AutoAcceptPolicy::Reject, Some(false)),
+            (AutoAcceptPolicy::Ask, None),
+        ] {
+            trust(&receiver, &sender, policy);
+            assert_eq!(
+                receiver
+                    .auto_answer(&request, Some(REMOTE.parse().unwrap()))
+                    .await,
+                answer
+            );
+        }
+
+        sender.shutdown();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn device_claiming_a_trusted_id_is_asked_about() {
+        let (receiver, _receiver_events, _receiver_dir) = instance();
+        let (trusted, _trusted_events, _trusted_dir) = instance();
+        let (impostor, _impostor_events, _impostor_dir) = instance();
+        // connecting back reaches the impostor, whose certificate isn't the pinned one
+        let port = impostor.start_http_server().await.unwrap().port();
+        let request = claimed_request(&trusted.identity().device_id, port);
+
+        // neither accepted nor turned down in the name of the trusted device
+        for policy in [AutoAcceptPolicy::Accept, AutoAcceptPolicy::Reject] {
+            trust(&receiver, &trusted, policy);
+            assert_eq!(
+                receiver
+                    .auto_answer(&request, Some(REMOTE.parse().unwrap()))
+                    .await,
+                None
+            );
+        }
+
+        impostor.shutdown();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sender_that_cant_be_connected_back_to_is_asked_about() {
+        let (receiver, _receiver_events, _receiver_dir) = instance();
+        let (sender, _sender_events, _sender_dir) = instance();
+        trust(&receiver, &sender, AutoAcceptPolicy::Accept);
+
+        // nothing listens on the port it gave
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let request = claimed_request(&sender.identity().device_id, port);
+        assert_eq!(
+            receiver
+                .auto_answer(&request, Some(REMOTE.parse().unwrap()))
+                .await,
+            None
+        );
+        // nor without knowing where the request came from
+        assert_eq!(receiver.auto_answer(&request, None).await, None);
     }
 }
@@ -1,76 +1,317 @@
+use crate::compression;
 use crate::debug_log;
+use crate::integrity;
+use crate::progress::{Direction, TransferProgress};
+use crate::tranzit::Tranzit;
 use bytes::Buf;
-use dirs_next::download_dir;
-use futures_util::{StreamExt, TryStreamExt};
-use rcgen::generate_simple_self_signed;
+use futures_util::{Stream, StreamExt, TryStreamExt};
 use sanitize_filename::sanitize;
-use std::path::{Path, PathBuf};
-use tokio::{fs, io::AsyncWriteExt};
+use sha2::Digest;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
 use warp::{self, http::StatusCode, multipart::FormData, Rejection, Reply};
 
-// Find the downloads directory path for each OS and save files in the downloads directory
-pub async fn save_file(mut form: FormData) -> Result<impl Reply, Rejection> {
-    let upload_dir: PathBuf = download_dir().expect("Could not locate the Downloads directory");
+#[cfg(target_os = "android")]
+use super::utils_android::finish_file;
 
-    fs::create_dir_all(&upload_dir).await.map_err(|e| {
-        debug_log!("Failed to create directory: {}", e);
-        warp::reject::custom(super::InternalServerError)
-    })?;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+use super::utils_desktop::finish_file;
 
-    while let Ok(Some(part)) = form.try_next().await {
-        if let Some(filename) = part.filename() {
-            let safe_filename = sanitize(filename);
-            let mut unique_filepath = upload_dir.join(&safe_filename);
-            let mut counter = 1;
-
-            while fs::metadata(&unique_filepath).await.is_ok() {
-                let stem = Path::new(&safe_filename)
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("file");
-                let ext = Path::new(&safe_filename)
-                    .extension()
-                    .and_then(|e| e.to_str())
-                    .unwrap_or("");
-
-                let new_filename = if ext.is_empty() {
-                    format!("{} ({})", stem, counter)
-                } else {
-                    format!("{} ({}).{}", stem, counter, ext)
-                };
+// Path of the partial file for a given transfer and file id
+// e.g. ~/.cache/tranzit/partial/<transfer_id>/<file_id>.part
+fn partial_file_path(
+    tranzit: &Tranzit,
+    transfer_id: &str,
+    file_id: &str,
+) -> Result<PathBuf, Rejection> {
+    if !super::is_valid_id(transfer_id) || !super::is_valid_id(file_id) {
+        debug_log!("Invalid transfer id {} or file id {}", transfer_id, file_id);
+        return Err(warp::reject::custom(super::BadRequest));
+    }
+
+    Ok(tranzit
+        .config()
+        .partials_dir
+        .join(transfer_id)
+        .join(format!("{}.part", file_id)))
+}
+
+// Bytes of a chunk counted towards the progress of its file while they come in.
+// They are taken back unless the chunk gets recorded, the sender sends it again.
+struct ChunkProgress<'a> {
+    tranzit: &'a Tranzit,
+    progress: &'a TransferProgress,
+    file_id: &'a str,
+    counted: u64,
+    recorded: bool,
+}
+
+impl ChunkProgress<'_> {
+    fn advance(&mut self, bytes: u64) {
+        self.counted += bytes;
+        // other connections add their chunks of the file too
+        self.progress.advance(self.tranzit, self.file_id, bytes);
+    }
+}
+
+impl Drop for ChunkProgress<'_> {
+    fn drop(&mut self) {
+        if !self.recorded && self.counted > 0 {
+            let counted = self.counted;
+            self.progress
+                .update_with(self.tranzit, self.file_id, |bytes| {
+                    bytes.saturating_sub(counted)
+                });
+        }
+    }
+}
+
+// Report how many bytes of a file have already been received, so the sender
+// can resume an interrupted upload instead of starting over
+pub async fn upload_offset(
+    transfer_id: String,
+    file_id: String,
+    params: super::TokenParams,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    tranzit.authorize(&transfer_id, &params.token)?;
+    let partial_path = partial_file_path(&tranzit, &transfer_id, &file_id)?;
 
-                unique_filepath = upload_dir.join(new_filename);
-                counter += 1;
+    if tranzit.is_cancelled(&transfer_id) {
+        return Err(warp::reject::custom(super::Cancelled));
+    }
+
+    // the file was already received completely, but the sender might have missed the response
+    if fs::metadata(partial_path.with_extension("done"))
+        .await
+        .is_ok()
+    {
+        return Ok(warp::reply::json(&super::UploadStatus {
+            offset: 0,
+            complete: true,
+            received: Vec::new(),
+        }));
+    }
+
+    let offset = fs::metadata(&partial_path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+
+    Ok(warp::reply::json(&super::UploadStatus {
+        offset,
+        complete: false,
+        received: tranzit.received_chunks(&transfer_id, &file_id),
+    }))
+}
+
+// Save received files in the directory picked by the settings, the downloads directory by default.
+// Data is appended to a partial file first, which is moved to where it belongs
+// once all the bytes have been received.
+pub async fn save_file(
+    params: super::UploadParams,
+    mut form: FormData,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    // files sent as part of a folder keep their place in it
+    let relative_path = match &params.path {
+        Some(path) => Some(super::safe_relative_path(path).ok_or_else(|| {
+            debug_log!("Refusing unsafe path {}", path);
+            warp::reject::custom(super::BadRequest)
+        })?),
+        None => None,
+    };
+
+    // only files of an accepted transfer request are received
+    tranzit.authorize(&params.transfer_id, &params.token)?;
+
+    // uploads of a cancelled transfer are refused, which stops the sender
+    let cancelled = tranzit.receiving_token(&params.transfer_id);
+    if cancelled.is_cancelled() {
+        return Err(warp::reject::custom(super::Cancelled));
+    }
+
+    let partial_path = partial_file_path(&tranzit, &params.transfer_id, &params.file_id)?;
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent).await.map_err(|e| {
+            debug_log!("Failed to create partials directory: {}", e);
+            warp::reject::custom(super::InternalServerError)
+        })?;
+    }
+
+    loop {
+        // a form that breaks off isn't taken for an upload that went through
+        let part = match form.try_next().await {
+            Ok(Some(part)) => part,
+            Ok(None) => break,
+            Err(e) => {
+                debug_log!("Failed to read upload form: {}", e);
+                return Err(warp::reject::custom(super::BadRequest));
             }
+        };
+        if let Some(filename) = part.filename() {
+            let safe_filename = sanitize(filename);
+            tranzit.check_upload(&params, filename)?;
+            let mime_type = super::content_type(&part, filename);
 
-            debug_log!("Saving uploaded file to: {}", unique_filepath.display());
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(false)
+                .open(&partial_path)
+                .await
+                .map_err(|e| {
+                    debug_log!(
+                        "Failed to open partial file '{}': {}",
+                        partial_path.display(),
+                        e
+                    );
+                    warp::reject::custom(super::InternalServerError)
+                })?;
 
-            let mut file = fs::File::create(&unique_filepath).await.map_err(|e| {
+            let received = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+            if params.offset > received {
                 debug_log!(
-                    "Failed to create file '{}': {}",
-                    unique_filepath.display(),
-                    e
+                    "Upload offset {} is past the {} bytes received for {}",
+                    params.offset,
+                    received,
+                    safe_filename
                 );
+                return Err(warp::reject::custom(super::OffsetMismatch));
+            }
+
+            // drop anything past the offset the sender is resuming from
+            file.set_len(params.offset).await.map_err(|e| {
+                debug_log!("Failed to truncate partial file: {}", e);
                 warp::reject::custom(super::InternalServerError)
             })?;
 
-            let mut part_stream = part.stream();
-            while let Some(chunk_result) = part_stream.next().await {
-                let mut chunk = chunk_result.map_err(|e| {
-                    debug_log!("Failed to read chunk from stream: {}", e);
+            // hash what was received earlier, so the whole file can be checked at the end
+            file.seek(SeekFrom::Start(0)).await.map_err(|e| {
+                debug_log!("Failed to seek partial file: {}", e);
+                warp::reject::custom(super::InternalServerError)
+            })?;
+            let mut hasher = integrity::hash_reader(&mut file, params.offset)
+                .await
+                .map_err(|e| {
+                    debug_log!("Failed to hash partial file: {}", e);
                     warp::reject::custom(super::InternalServerError)
                 })?;
 
+            file.seek(SeekFrom::Start(params.offset))
+                .await
+                .map_err(|e| {
+                    debug_log!("Failed to seek partial file: {}", e);
+                    warp::reject::custom(super::InternalServerError)
+                })?;
+
+            debug_log!(
+                "Receiving {} from offset {} into: {}",
+                safe_filename,
+                params.offset,
+                partial_path.display()
+            );
+
+            let mut written = params.offset;
+            // compressed uploads are unpacked before they are hashed and written,
+            // the speed limits count the bytes as they come in
+            let throttle = tranzit.receive_throttle(&params.transfer_id);
+            let progress = tranzit.transfer_progress(&params.transfer_id, Direction::Receive);
+            let mut part_stream =
+                compression::decompress_stream(throttle.limit(part.stream()), params.compression);
+            let mut stream_result = Ok(());
+
+            loop {
+                let chunk_result = tokio::select! {
+                    _ = cancelled.cancelled() => {
+                        stream_result = Err(warp::reject::custom(super::Cancelled));
+                        break;
+                    }
+                    chunk_result = part_stream.next() => match chunk_result {
+                        Some(chunk_result) => chunk_result,
+                        None => break,
+                    },
+                };
+                let mut chunk = match chunk_result {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        debug_log!("Failed to read chunk from stream: {}", e);
+                        stream_result = Err(warp::reject::custom(super::InternalServerError));
+                        break;
+                    }
+                };
+
+                // never take more than the approved size
+                if written + chunk.remaining() as u64 > params.size {
+                    debug_log!(
+                        "Received more than the {} bytes of {}",
+                        params.size,
+                        safe_filename
+                    );
+                    stream_result = Err(warp::reject::custom(super::Forbidden));
+                    break;
+                }
+
                 while chunk.has_remaining() {
                     let bytes = chunk.chunk();
+                    hasher.update(bytes);
                     file.write_all(bytes).await.map_err(|e| {
                         debug_log!("Failed to write chunk to file: {}", e);
                         warp::reject::custom(super::InternalServerError)
                     })?;
                     let len = bytes.len();
+                    written += len as u64;
                     chunk.advance(len);
                 }
+                progress.update(&tranzit, &params.file_id, written);
+            }
+
+            // keep whatever was received so far, the sender can resume from there
+            file.flush().await.map_err(|e| {
+                debug_log!("Failed to flush partial file: {}", e);
+                warp::reject::custom(super::InternalServerError)
+            })?;
+            if cancelled.is_cancelled() {
+                // the partials might have been removed before this file was created
+                drop(file);
+                let _ = fs::remove_file(&partial_path).await;
+                return Err(warp::reject::custom(super::Cancelled));
+            }
+            stream_result?;
+
+            if written < params.size {
+                debug_log!(
+                    "Received {}/{} bytes of {}, waiting for the rest",
+                    written,
+                    params.size,
+                    safe_filename
+                );
+                return Err(warp::reject::custom(super::IncompleteUpload));
+            }
+
+            drop(file);
+
+            // corrupted files are dropped, the sender starts over when it retries
+            if !tranzit.verify_hash(&params, hasher) {
+                let _ = fs::remove_file(&partial_path).await;
+                return Err(warp::reject::custom(super::HashMismatch));
             }
+
+            finish_file(
+                &tranzit,
+                &params,
+                relative_path.as_deref(),
+                &safe_filename,
+                &mime_type,
+                &partial_path,
+            )
+            .await?;
+            tranzit.mark_received(&params);
         }
     }
 
@@ -80,29 +321,380 @@ pub async fn save_file(mut form: FormData) -> Result<impl Reply, Rejection> {
     ))
 }
 
-// Generate TLS certificates required for warp https server and save them to disk
-pub fn generate_tls_certs_to_disk() -> Result<(PathBuf, PathBuf), Box<dyn std::error::Error>> {
-    // Generate a self-signed certificates
-    let subject_alt_names = vec!["localhost".to_string()];
-    let cert_key = generate_simple_self_signed(subject_alt_names)?;
+// Write a chunk of a file sent over several connections at its place in the partial file.
+// The chunk that completes the file checks the hash of the whole file and moves it
+// to where it belongs.
+pub async fn save_chunk(
+    params: super::UploadParams,
+    mut form: FormData,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection> {
+    let relative_path = match &params.path {
+        Some(path) => Some(super::safe_relative_path(path).ok_or_else(|| {
+            debug_log!("Refusing unsafe path {}", path);
+            warp::reject::custom(super::BadRequest)
+        })?),
+        None => None,
+    };
 
-    // Export to PEM format
-    let cert_pem = cert_key.cert.pem();
-    let key_pem = cert_key.signing_key.serialize_pem(); // Correct method
+    tranzit.authorize(&params.transfer_id, &params.token)?;
 
-    // Write to ./certs next to executable
-    let exe_dir = std::env::temp_dir();
-    let certs_dir = exe_dir.join("certs");
-    std::fs::create_dir_all(&certs_dir)?;
+    let cancelled = tranzit.receiving_token(&params.transfer_id);
+    if cancelled.is_cancelled() {
+        return Err(warp::reject::custom(super::Cancelled));
+    }
 
-    let cert_path = certs_dir.join("cert.pem");
-    let key_path = certs_dir.join("key.pem");
+    let start = params.offset;
+    let end = params
+        .length
+        .and_then(|length| start.checked_add(length))
+        .filter(|&end| end > start && end <= params.size)
+        .ok_or_else(|| {
+            debug_log!("Invalid chunk at {} of {}", start, params.file_id);
+            warp::reject::custom(super::BadRequest)
+        })?;
+
+    // chunks are written straight to their place, so they get a partial file of their own
+    let partial_path =
+        partial_file_path(&tranzit, &params.transfer_id, &params.file_id)?.with_extension("chunks");
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent).await.map_err(|e| {
+            debug_log!("Failed to create partials directory: {}", e);
+            warp::reject::custom(super::InternalServerError)
+        })?;
+    }
 
-    std::fs::write(&cert_path, cert_pem)?;
-    std::fs::write(&key_path, key_pem)?;
+    while let Ok(Some(part)) = form.try_next().await {
+        if let Some(filename) = part.filename() {
+            let safe_filename = sanitize(filename);
+            tranzit.check_upload(&params, filename)?;
+            let mime_type = super::content_type(&part, filename);
 
-    debug_log!("🔐 TLS certificate written to: {}", cert_path.display());
-    debug_log!("🔑 TLS private key written to: {}", key_path.display());
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(&partial_path)
+                .await
+                .map_err(|e| {
+                    debug_log!(
+                        "Failed to open partial file '{}': {}",
+                        partial_path.display(),
+                        e
+                    );
+                    warp::reject::custom(super::InternalServerError)
+                })?;
+
+            file.seek(SeekFrom::Start(start)).await.map_err(|e| {
+                debug_log!("Failed to seek partial file: {}", e);
+                warp::reject::custom(super::InternalServerError)
+            })?;
+
+            let mut written = start;
+            // compressed uploads are unpacked before they are hashed and written,
+            // the speed limits count the bytes as they come in
+            let throttle = tranzit.receive_throttle(&params.transfer_id);
+            let progress = tranzit.transfer_progress(&params.transfer_id, Direction::Receive);
+            let mut chunk_progress = ChunkProgress {
+                tranzit: &tranzit,
+                progress: &progress,
+                file_id: &params.file_id,
+                counted: 0,
+                recorded: false,
+            };
+            let mut part_stream =
+                compression::decompress_stream(throttle.limit(part.stream()), params.compression);
+            let mut stream_result = Ok(());
+
+            loop {
+                let chunk_result = tokio::select! {
+                    _ = cancelled.cancelled() => {
+                        stream_result = Err(warp::reject::custom(super::Cancelled));
+                        break;
+                    }
+                    chunk_result = part_stream.next() => match chunk_result {
+                        Some(chunk_result) => chunk_result,
+                        None => break,
+                    },
+                };
+                let mut chunk = match chunk_result {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        debug_log!("Failed to read chunk from stream: {}", e);
+                        stream_result = Err(warp::reject::custom(super::InternalServerError));
+                        break;
+                    }
+                };
+
+                // never write past the end of the chunk
+                if written + chunk.remaining() as u64 > end {
+                    debug_log!(
+                        "Received more than the {} bytes of the chunk of {}",
+                        end - start,
+                        safe_filename
+                    );
+                    stream_result = Err(warp::reject::custom(super::Forbidden));
+                    break;
+                }
 
-    Ok((cert_path, key_path))
+                while chunk.has_remaining() {
+                    let bytes = chunk.chunk();
+                    file.write_all(bytes).await.map_err(|e| {
+                        debug_log!("Failed to write chunk to file: {}", e);
+                        warp::reject::custom(super::InternalServerError)
+                    })?;
+                    let len = bytes.len();
+                    written += len as u64;
+                    chunk.advance(len);
+                    chunk_progress.advance(len as u64);
+                }
+            }
+
+            file.flush().await.map_err(|e| {
+                debug_log!("Failed to flush partial file: {}", e);
+                warp::reject::custom(super::InternalServerError)
+            })?;
+            drop(file);
+            if cancelled.is_cancelled() {
+                let _ = fs::remove_file(&partial_path).await;
+                return Err(warp::reject::custom(super::Cancelled));
+            }
+            stream_result?;
+
+            // an incomplete chunk isn't recorded, the sender sends all of it again
+            if written < end {
+                debug_log!(
+                    "Received {} of {} bytes of the chunk of {}",
+                    written - start,
+                    end - start,
+                    safe_filename
+                );
+                return Err(warp::reject::custom(super::BadRequest));
+            }
+
+            if !tranzit.record_chunk(&params, start, end) {
+                chunk_progress.recorded = true;
+                continue;
+            }
+
+            // every chunk is in, check the whole file. The chunk is forgotten again when
+            // that fails, so the file gets finished once the sender retries it.
+            let finished = async {
+                let mut file = fs::File::open(&partial_path).await.map_err(|e| {
+                    debug_log!("Failed to open partial file: {}", e);
+                    warp::reject::custom(super::InternalServerError)
+                })?;
+                let hasher = integrity::hash_reader(&mut file, params.size)
+                    .await
+                    .map_err(|e| {
+                        debug_log!("Failed to hash partial file: {}", e);
+                        warp::reject::custom(super::InternalServerError)
+                    })?;
+                drop(file);
+
+                if !tranzit.verify_hash(&params, hasher) {
+                    tranzit.reset_chunks(&params);
+                    progress.update(&tranzit, &params.file_id, 0);
+                    let _ = fs::remove_file(&partial_path).await;
+                    return Err(warp::reject::custom(super::HashMismatch));
+                }
+
+                finish_file(
+                    &tranzit,
+                    &params,
+                    relative_path.as_deref(),
+                    &safe_filename,
+                    &mime_type,
+                    &partial_path,
+                )
+                .await
+            }
+            .await;
+            if let Err(e) = finished {
+                tranzit.forget_chunk(&params, start, end);
+                return Err(e);
+            }
+
+            chunk_progress.recorded = true;
+            tranzit.mark_received(&params);
+        }
+    }
+
+    Ok(warp::reply::with_status(
+        "Chunk uploaded successfully.",
+        StatusCode::CREATED,
+    ))
+}
+
+// Unpack a batch of small files sent as one stream. Every file is checked like a
+// single upload and moved to where it belongs as soon as all of its bytes are in,
+// files that were already received are skipped. Replies with the files we have.
+pub async fn save_batch<S, B>(
+    params: super::BatchParams,
+    body: S,
+    tranzit: Tranzit,
+) -> Result<impl Reply, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf + Send,
+{
+    tranzit.authorize(&params.transfer_id, &params.token)?;
+
+    let cancelled = tranzit.receiving_token(&params.transfer_id);
+    if cancelled.is_cancelled() {
+        return Err(warp::reject::custom(super::Cancelled));
+    }
+
+    tranzit.check_compression(&params.transfer_id, params.compression)?;
+
+    let body = tranzit
+        .receive_throttle(&params.transfer_id)
+        .limit(Box::pin(body));
+    let body = compression::decompress_stream(body, params.compression);
+    let mut reader = super::BatchReader::new(body);
+    let progress = tranzit.transfer_progress(&params.transfer_id, Direction::Receive);
+    let mut received = Vec::new();
+
+    while let Some((file_id, size)) = reader.next_file().await? {
+        let approved = tranzit
+            .approved_file(&params.transfer_id, &file_id)
+            .filter(|file| file.size == size)
+            .ok_or_else(|| {
+                debug_log!("⛔ Refusing batched file {} ({} bytes)", file_id, size);
+                warp::reject::custom(super::Forbidden)
+            })?;
+
+        let upload = super::UploadParams {
+            transfer_id: params.transfer_id.clone(),
+            token: params.token.clone(),
+            file_id: file_id.clone(),
+            offset: 0,
+            size,
+            path: approved.relative_path.clone(),
+            length: None,
+            compression: None,
+        };
+
+        // the sender didn't get our answer last time, drop the bytes
+        if approved.received {
+            let mut remaining = size;
+            while remaining > 0 {
+                remaining -= reader.read(remaining).await?.len() as u64;
+            }
+            received.push(file_id);
+            continue;
+        }
+        tranzit.check_upload(&upload, &approved.name)?;
+
+        let relative_path = match &upload.path {
+            Some(path) => Some(super::safe_relative_path(path).ok_or_else(|| {
+                debug_log!("Refusing unsafe path {}", path);
+                warp::reject::custom(super::BadRequest)
+            })?),
+            None => None,
+        };
+        let safe_filename = sanitize(&approved.name);
+
+        let partial_path = partial_file_path(&tranzit, &params.transfer_id, &file_id)?;
+        if let Some(parent) = partial_path.parent() {
+            fs::create_dir_all(parent).await.map_err(|e| {
+                debug_log!("Failed to create partials directory: {}", e);
+                warp::reject::custom(super::InternalServerError)
+            })?;
+        }
+        let mut file = fs::File::create(&partial_path).await.map_err(|e| {
+            debug_log!(
+                "Failed to create partial file '{}': {}",
+                partial_path.display(),
+                e
+            );
+            warp::reject::custom(super::InternalServerError)
+        })?;
+
+        let mut hasher = integrity::FileHasher::new();
+        let mut remaining = size;
+        while remaining > 0 {
+            let bytes = tokio::select! {
+                _ = cancelled.cancelled() => {
+                    drop(file);
+                    let _ = fs::remove_file(&partial_path).await;
+                    return Err(warp::reject::custom(super::Cancelled));
+                }
+                bytes = reader.read(remaining) => bytes?,
+            };
+
+            hasher.update(&bytes);
+            file.write_all(&bytes).await.map_err(|e| {
+                debug_log!("Failed to write chunk to file: {}", e);
+                warp::reject::custom(super::InternalServerError)
+            })?;
+            remaining -= bytes.len() as u64;
+            progress.update(&tranzit, &file_id, size - remaining);
+        }
+
+        file.flush().await.map_err(|e| {
+            debug_log!("Failed to flush partial file: {}", e);
+            warp::reject::custom(super::InternalServerError)
+        })?;
+        drop(file);
+
+        // a corrupted file is left out of the reply, so the sender sends it again
+        if !tranzit.verify_hash(&upload, hasher) {
+            let _ = fs::remove_file(&partial_path).await;
+            continue;
+        }
+
+        let mime_type = mime_guess::from_path(&approved.name)
+            .first_or_octet_stream()
+            .to_string();
+        finish_file(
+            &tranzit,
+            &upload,
+            relative_path.as_deref(),
+            &safe_filename,
+            &mime_type,
+            &partial_path,
+        )
+        .await?;
+        tranzit.mark_received(&upload);
+        received.push(file_id);
+    }
+
+    debug_log!("📦 Received a batch of {} files", received.len());
+
+    Ok(warp::reply::json(&super::BatchStatus { received }))
+}
+
+// Remove the partial files of a cancelled transfer
+pub async fn remove_partials(tranzit: &Tranzit, transfer_id: &str) {
+    let dir = tranzit.config().partials_dir.join(transfer_id);
+    if fs::remove_dir_all(&dir).await.is_ok() {
+        debug_log!("Removed partial files of transfer {}", transfer_id);
+    }
+}
+
+// Remove partial files no accepted transfer refers to. Resuming is meant for a
+// connection that dropped while both ends keep running, resuming across a restart of
+// the receiver is out of scope: approvals and their tokens only live in memory, so
+// uploads of a transfer accepted before the restart are refused and the sender has
+// to send a new request, which gets a new transfer id and none of the old partials.
+// As it runs before the server takes requests, every partial file is left over.
+pub async fn remove_orphaned_partials(tranzit: &Tranzit) {
+    let mut entries = match fs::read_dir(&tranzit.config().partials_dir).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let transfer_id = entry.file_name().to_string_lossy().into_owned();
+        if tranzit.is_approved(&transfer_id) {
+            continue;
+        }
+
+        debug_log!(
+            "Removing partial files of a transfer that can't be resumed: {}",
+            entry.path().display()
+        );
+        let _ = fs::remove_dir_all(entry.path()).await;
+    }
 }
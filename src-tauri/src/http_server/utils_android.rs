@@ -1,14 +1,20 @@
 use crate::debug_log;
+use crate::tranzit::Tranzit;
 use crate::APP_HANDLE;
-use bytes::Buf;
-use futures_util::{StreamExt, TryStreamExt};
-use rcgen::generate_simple_self_signed;
-use sanitize_filename::sanitize;
-use tauri_plugin_android_fs::{AndroidFsExt, PrivateDir, PublicGeneralPurposeDir};
-use warp::{self, http::StatusCode, multipart::FormData, Rejection, Reply};
-
-// Find the downloads directory path and save files in the downloads directory
-pub async fn save_file(mut form: FormData) -> Result<impl Reply, Rejection> {
+use std::path::Path;
+use tauri_plugin_android_fs::{AndroidFsExt, FileAccessMode, PublicGeneralPurposeDir};
+use tokio::{fs, io::AsyncWriteExt};
+use warp::{self, Rejection};
+
+// Copy a completely received and verified file from its partial file to the public Downloads folder
+pub(super) async fn finish_file(
+    tranzit: &Tranzit,
+    params: &super::UploadParams,
+    relative_path: Option<&[String]>,
+    safe_filename: &str,
+    mime_type: &str,
+    partial_path: &Path,
+) -> Result<(), Rejection> {
     let app = APP_HANDLE.get().ok_or_else(|| {
         debug_log!("App handle not initialized");
         warp::reject::custom(super::InternalServerError)
@@ -17,84 +23,69 @@ pub async fn save_file(mut form: FormData) -> Result<impl Reply, Rejection> {
     let api = app.android_fs();
     let storage = api.public_storage();
 
-    while let Ok(Some(part)) = form.try_next().await {
-        if let Some(filename) = part.filename() {
-            let safe_filename = sanitize(filename);
-            let mime_type = part
-                .content_type()
-                .map(|ct| ct.to_string())
-                .unwrap_or("application/octet-stream".into());
-
-            // Create file in Android public Downloads folder
-            let uri = storage
-                .create_file(
-                    PublicGeneralPurposeDir::Download,
-                    &safe_filename,
-                    Some(&mime_type),
-                )
-                .map_err(|e| {
-                    debug_log!("Failed to create MediaStore file: {:?}", e);
-                    warp::reject::custom(super::InternalServerError)
-                })?;
-
-            // Read the file data from the stream
-            let mut file_data = Vec::new();
-            let mut part_stream = part.stream();
-            while let Some(chunk_result) = part_stream.next().await {
-                let mut chunk = chunk_result.map_err(|e| {
-                    debug_log!("Stream read failed: {}", e);
-                    warp::reject::custom(super::InternalServerError)
-                })?;
-
-                while chunk.has_remaining() {
-                    let bytes = chunk.chunk();
-                    file_data.extend_from_slice(bytes);
-                    let len = bytes.len();
-                    chunk.advance(len);
-                }
-            }
-
-            // Write data to the file via plugin API
-            api.write(&uri, &file_data).map_err(|e| {
-                debug_log!("Failed to write file via android-fs: {:?}", e);
-                warp::reject::custom(super::InternalServerError)
-            })?;
-        }
+    // Create file in Android public Downloads folder, folders in the
+    // relative path are created by the media store as needed.
+    // Directories from the settings are folders inside Downloads here.
+    let mut components = tranzit
+        .destination_dir(&params.transfer_id, mime_type)
+        .and_then(|dir| {
+            super::safe_relative_path(dir.to_string_lossy().trim_start_matches(['/', '\\']))
+        })
+        .unwrap_or_default();
+    match relative_path {
+        Some(path) => components.extend(path.iter().cloned()),
+        None => components.push(safe_filename.to_string()),
     }
+    // the user may have renamed the file when accepting it
+    if let Some(renamed) = tranzit.renamed_file(&params.transfer_id, &params.file_id) {
+        components.pop();
+        components.push(renamed);
+    }
+    let target_path = components.join("/");
+    let uri = storage
+        .create_file(
+            PublicGeneralPurposeDir::Download,
+            &target_path,
+            Some(mime_type),
+        )
+        .map_err(|e| {
+            debug_log!("Failed to create MediaStore file: {:?}", e);
+            warp::reject::custom(super::InternalServerError)
+        })?;
+
+    // Stream the partial file into the media store file so large files
+    // never have to fit in memory
+    let target = api
+        .open_file(&uri, FileAccessMode::WriteTruncate)
+        .map_err(|e| {
+            debug_log!("Failed to open MediaStore file: {:?}", e);
+            warp::reject::custom(super::InternalServerError)
+        })?;
+    let mut target = fs::File::from_std(target);
+
+    let mut source = fs::File::open(partial_path).await.map_err(|e| {
+        debug_log!("Failed to open partial file: {}", e);
+        warp::reject::custom(super::InternalServerError)
+    })?;
 
-    Ok(warp::reply::with_status(
-        "File(s) uploaded successfully.",
-        StatusCode::CREATED,
-    ))
-}
-
-// Generate TLS certificates required for warp https server
-// and save them to apps private cache directory
-pub fn generate_tls_certs_to_disk(
-    app: &tauri::AppHandle,
-) -> Result<(std::path::PathBuf, std::path::PathBuf), Box<dyn std::error::Error>> {
-    // Generate the cert
-    let subject_alt_names = vec!["localhost".to_string()];
-    let cert_key = generate_simple_self_signed(subject_alt_names)?;
-
-    let cert_pem = cert_key.cert.pem();
-    let key_pem = cert_key.signing_key.serialize_pem();
-
-    // Use Tauri plugin FS to get app-private cache dir
-    let fs = app.android_fs();
-    let base_dir = fs.private_storage().resolve_path(PrivateDir::Cache)?;
-    let certs_dir = base_dir.join("certs");
-
-    std::fs::create_dir_all(&certs_dir)?;
-
-    let cert_path = certs_dir.join("cert.pem");
-    let key_path = certs_dir.join("key.pem");
+    let copied = tokio::io::copy(&mut source, &mut target)
+        .await
+        .and(target.flush().await);
+    if let Err(e) = copied {
+        debug_log!("Failed to write file via android-fs: {}", e);
+        let _ = api.remove_file(&uri);
+        return Err(warp::reject::custom(super::InternalServerError));
+    }
 
-    std::fs::write(&cert_path, cert_pem)?;
-    std::fs::write(&key_path, key_pem)?;
+    let _ = fs::remove_file(partial_path).await;
 
-    debug_log!("📱 [Android] TLS cert written to: {}", cert_path.display());
-    debug_log!("📱 [Android] TLS key written to: {}", key_path.display());
+    // remember that this file is complete in case the sender retries
+    let _ = fs::write(partial_path.with_extension("done"), b"").await;
+    tranzit.record_received(
+        &params.transfer_id,
+        &params.file_id,
+        Path::new("Download").join(&target_path),
+    );
 
-    Ok((cert_path, key_path))
+    Ok(())
 }
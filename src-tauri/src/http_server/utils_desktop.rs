@@ -0,0 +1,112 @@
+use crate::debug_log;
+use crate::tranzit::Tranzit;
+use dirs_next::download_dir;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use warp::{self, Rejection};
+
+// Find a file path in the given directory that doesn't exist yet by appending
+// a counter to the file name, e.g. "photo (1).jpg"
+async fn unique_file_path(dir: &Path, filename: &str) -> PathBuf {
+    let mut unique_filepath = dir.join(filename);
+    let mut counter = 1;
+
+    while fs::metadata(&unique_filepath).await.is_ok() {
+        unique_filepath = dir.join(super::numbered_name(filename, counter));
+        counter += 1;
+    }
+
+    unique_filepath
+}
+
+// Move a file, falling back to copy + delete when source and destination
+// are on different filesystems
+async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(from, to).await?;
+    fs::remove_file(from).await
+}
+
+// Create the folders of a file sent as part of a folder inside the download directory.
+// The components are already sanitized, the canonical path is checked as well in case
+// one of the folders is a symlink pointing somewhere else.
+async fn create_target_dir(upload_dir: &Path, dirs: &[String]) -> Result<PathBuf, Rejection> {
+    let target_dir = dirs
+        .iter()
+        .fold(upload_dir.to_path_buf(), |dir, c| dir.join(c));
+
+    fs::create_dir_all(&target_dir).await.map_err(|e| {
+        debug_log!("Failed to create directory: {}", e);
+        warp::reject::custom(super::InternalServerError)
+    })?;
+
+    let (Ok(canonical_upload_dir), Ok(canonical_target_dir)) = (
+        fs::canonicalize(upload_dir).await,
+        fs::canonicalize(&target_dir).await,
+    ) else {
+        return Err(warp::reject::custom(super::InternalServerError));
+    };
+
+    if !canonical_target_dir.starts_with(&canonical_upload_dir) {
+        debug_log!(
+            "Refusing to write outside the download directory: {}",
+            canonical_target_dir.display()
+        );
+        return Err(warp::reject::custom(super::BadRequest));
+    }
+
+    Ok(target_dir)
+}
+
+// Move a completely received and verified file from its partial file to where it belongs
+pub(super) async fn finish_file(
+    tranzit: &Tranzit,
+    params: &super::UploadParams,
+    relative_path: Option<&[String]>,
+    safe_filename: &str,
+    mime_type: &str,
+    partial_path: &Path,
+) -> Result<(), Rejection> {
+    let upload_dir = tranzit
+        .destination_dir(&params.transfer_id, mime_type)
+        .or_else(download_dir)
+        .ok_or_else(|| {
+            debug_log!("Could not locate the Downloads directory");
+            warp::reject::custom(super::InternalServerError)
+        })?;
+    fs::create_dir_all(&upload_dir).await.map_err(|e| {
+        debug_log!("Failed to create directory: {}", e);
+        warp::reject::custom(super::InternalServerError)
+    })?;
+
+    let (target_dir, target_name) = match relative_path {
+        Some([dirs @ .., name]) => (create_target_dir(&upload_dir, dirs).await?, name.as_str()),
+        _ => (upload_dir.clone(), safe_filename),
+    };
+    // the user may have renamed the file when accepting it
+    let renamed = tranzit.renamed_file(&params.transfer_id, &params.file_id);
+    let target_name = renamed.as_deref().unwrap_or(target_name);
+    let unique_filepath = unique_file_path(&target_dir, target_name).await;
+
+    debug_log!("Saving uploaded file to: {}", unique_filepath.display());
+
+    move_file(partial_path, &unique_filepath)
+        .await
+        .map_err(|e| {
+            debug_log!(
+                "Failed to move file to '{}': {}",
+                unique_filepath.display(),
+                e
+            );
+            warp::reject::custom(super::InternalServerError)
+        })?;
+
+    // remember that this file is complete in case the sender retries
+    let _ = fs::write(partial_path.with_extension("done"), b"").await;
+    tranzit.record_received(&params.transfer_id, &params.file_id, unique_filepath);
+
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use crate::debug_log;
+use base64::{engine::general_purpose, Engine as _};
+use rcgen::generate_simple_self_signed;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::Path;
+use uuid::Uuid;
+
+// Persistent identity of this install, the device id is advertised over mDNS
+// and the certificate is served by the https server. Peers pin the certificate
+// fingerprint when pairing, so both have to survive restarts.
+#[derive(Debug)]
+pub struct Identity {
+    pub device_id: String,
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub fingerprint: String,
+}
+
+// Load the identity from the given directory, or create one on first launch
+pub fn load_or_create(data_dir: &Path) -> Result<Identity, String> {
+    let identity_dir = data_dir.join("identity");
+    let device_id_path = identity_dir.join("device_id");
+    let cert_path = identity_dir.join("cert.pem");
+    let key_path = identity_dir.join("key.pem");
+
+    // a new identity is only made on first launch, replacing one that got damaged
+    // would break the pin of every paired device without anyone noticing
+    let paths = [&device_id_path, &cert_path, &key_path];
+    let existing = paths.iter().filter(|path| path.exists()).count();
+    if existing == paths.len() {
+        let read = |path: &Path| {
+            std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+        };
+        let device_id = read(&device_id_path)?;
+        let cert_pem = read(&cert_path)?;
+        let key_pem = read(&key_path)?;
+
+        let fingerprint = pem_fingerprint(&cert_pem)?;
+        debug_log!("🪪 Loaded identity {} ({})", device_id.trim(), fingerprint);
+
+        return Ok(Identity {
+            device_id: device_id.trim().to_string(),
+            cert_pem,
+            key_pem,
+            fingerprint,
+        });
+    }
+    if existing > 0 {
+        return Err(format!(
+            "The identity in {} is incomplete, restore the missing files or remove the folder to create a new one",
+            identity_dir.display()
+        ));
+    }
+
+    let device_id = Uuid::new_v4().to_string();
+
+    // Generate a self-signed certificate, rcgen defaults to a validity period
+    // long enough to never expire during the lifetime of an install
+    let subject_alt_names = vec!["localhost".to_string()];
+    let cert_key = generate_simple_self_signed(subject_alt_names)
+        .map_err(|e| format!("Failed to generate certificate: {}", e))?;
+
+    let cert_pem = cert_key.cert.pem();
+    let key_pem = cert_key.signing_key.serialize_pem();
+    let fingerprint = fingerprint(cert_key.cert.der());
+
+    // the files are written next to the identity folder and moved in together, a
+    // crash halfway through leaves a temp folder behind instead of an incomplete identity
+    let temp_dir = data_dir.join(format!("identity.tmp-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&temp_dir)
+        .map_err(|e| format!("Failed to create identity directory: {}", e))?;
+
+    std::fs::write(temp_dir.join("cert.pem"), &cert_pem)
+        .map_err(|e| format!("Failed to write certificate: {}", e))?;
+    write_private(&temp_dir.join("key.pem"), &key_pem)
+        .map_err(|e| format!("Failed to write private key: {}", e))?;
+    std::fs::write(temp_dir.join("device_id"), &device_id)
+        .map_err(|e| format!("Failed to write device id: {}", e))?;
+
+    // an empty identity folder is what older versions left behind when they crashed
+    // before writing anything, only an empty one can be removed
+    if identity_dir.exists() {
+        std::fs::remove_dir(&identity_dir)
+            .map_err(|e| format!("Failed to replace empty identity directory: {}", e))?;
+    }
+    std::fs::rename(&temp_dir, &identity_dir)
+        .map_err(|e| format!("Failed to move identity into place: {}", e))?;
+
+    debug_log!("🪪 Created identity {} ({})", device_id, fingerprint);
+
+    Ok(Identity {
+        device_id,
+        cert_pem,
+        key_pem,
+        fingerprint,
+    })
+}
+
+// Write a file only the current user can read, it is created that way rather
+// than restricted afterwards
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()
+}
+
+// SHA-256 fingerprint of a DER encoded certificate as lowercase hex
+pub fn fingerprint(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// SHA-256 fingerprint of a PEM encoded certificate
+fn pem_fingerprint(cert_pem: &str) -> Result<String, String> {
+    let base64_body: String = cert_pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+
+    let der = general_purpose::STANDARD
+        .decode(base64_body.trim())
+        .map_err(|e| format!("Failed to decode certificate: {}", e))?;
+
+    Ok(fingerprint(&der))
+}
+
+// Commitment to a pairing nonce, sent ahead of the nonce itself so the device that
+// starts pairing can't pick its nonce after seeing the one of the other device
+pub fn commitment(nonce: &str) -> String {
+    fingerprint(nonce.as_bytes())
+}
+
+// Short code shown on both screens while pairing so users can confirm nobody is
+// sitting in between the two devices. It covers both certificates and a nonce of
+// each side, with the nonce of the initiator committed to up front someone in
+// between gets one guess at matching codes instead of trying certificates until
+// they match.
+pub fn verification_code(
+    initiator_fingerprint: &str,
+    responder_fingerprint: &str,
+    initiator_nonce: &str,
+    responder_nonce: &str,
+) -> String {
+    let input = [
+        initiator_fingerprint,
+        responder_fingerprint,
+        initiator_nonce,
+        responder_nonce,
+    ]
+    .join("\n");
+    let digest = Sha256::digest(input.as_bytes());
+    let number = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+
+    format!("{:06}", number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn identity_survives_restarts() {
+        let dir = TempDir::new();
+        let created = load_or_create(&dir.0).unwrap();
+        let loaded = load_or_create(&dir.0).unwrap();
+
+        assert_eq!(loaded.device_id, created.device_id);
+        assert_eq!(loaded.fingerprint, created.fingerprint);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = std::fs::metadata(dir.join("identity/key.pem")).unwrap();
+            assert_eq!(key.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn incomplete_identity_is_kept() {
+        let dir = TempDir::new();
+        let created = load_or_create(&dir.0).unwrap();
+        let key_path = dir.join("identity/key.pem");
+        std::fs::remove_file(&key_path).unwrap();
+
+        assert!(load_or_create(&dir.0).is_err());
+        // nothing was replaced
+        assert!(!key_path.exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("identity/device_id")).unwrap(),
+            created.device_id
+        );
+    }
+
+    #[test]
+    fn leftover_temp_identity_is_ignored() {
+        let dir = TempDir::new();
+        // what a crash in the middle of creating the identity leaves behind
+        let leftover = dir.join(format!("identity.tmp-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&leftover).unwrap();
+        std::fs::write(leftover.join("cert.pem"), "not a certificate").unwrap();
+
+        let created = load_or_create(&dir.0).unwrap();
+        let loaded = load_or_create(&dir.0).unwrap();
+        assert_eq!(loaded.device_id, created.device_id);
+        assert_eq!(loaded.fingerprint, created.fingerprint);
+    }
+
+    #[test]
+    fn verification_code_covers_every_input() {
+        let inputs = ["fingerprint-a", "fingerprint-b", "nonce-a", "nonce-b"];
+        let code = |i: [&str; 4]| verification_code(i[0], i[1], i[2], i[3]);
+
+        assert_eq!(code(inputs), code(inputs));
+        for changed in 0..inputs.len() {
+            let mut other = inputs;
+            other[changed] = "something else";
+            assert_ne!(code(inputs), code(other), "input {} is left out", changed);
+        }
+        // the roles are part of it too
+        assert_ne!(
+            code(inputs),
+            code(["fingerprint-b", "fingerprint-a", "nonce-b", "nonce-a"])
+        );
+    }
+
+    #[test]
+    fn commitment_binds_the_nonce() {
+        assert_eq!(commitment("nonce"), commitment("nonce"));
+        assert_ne!(commitment("nonce"), commitment("nonce2"));
+        assert_eq!(commitment("nonce").len(), 64);
+    }
+
+    #[test]
+    fn verification_code_has_six_digits() {
+        let codes: Vec<String> = (0..1000)
+            .map(|i| verification_code("a", "b", &format!("a{}", i), &format!("b{}", i)))
+            .collect();
+
+        for code in &codes {
+            assert_eq!(code.len(), 6, "{}", code);
+            assert!(code.chars().all(|c| c.is_ascii_digit()), "{}", code);
+        }
+        // small numbers are padded with zeros
+        assert!(codes.iter().any(|code| code.starts_with('0')));
+    }
+}
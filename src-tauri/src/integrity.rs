@@ -0,0 +1,76 @@
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Files are hashed with SHA-256 on both ends of a transfer, the sender puts the
+// hash in the transfer request and the receiver checks it once all bytes are in
+pub type FileHasher = Sha256;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// Feed up to `limit` bytes of a reader into a new hasher
+pub async fn hash_reader<R: AsyncRead + Unpin>(
+    reader: R,
+    limit: u64,
+) -> std::io::Result<FileHasher> {
+    hash_reader_with_progress(reader, limit, |_| {}).await
+}
+
+// Same as hash_reader, `progress` is told how many bytes were hashed after every read
+pub async fn hash_reader_with_progress<R: AsyncRead + Unpin>(
+    mut reader: R,
+    limit: u64,
+    mut progress: impl FnMut(u64),
+) -> std::io::Result<FileHasher> {
+    let mut hasher = FileHasher::new();
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    let mut remaining = limit;
+
+    while remaining > 0 {
+        let to_read = remaining.min(READ_BUFFER_SIZE as u64) as usize;
+        let n = reader.read(&mut buf[..to_read]).await?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+        remaining -= n as u64;
+        progress(n as u64);
+    }
+
+    Ok(hasher)
+}
+
+// Lowercase hex of the final hash
+pub fn to_hex(hasher: FileHasher) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Whether a hash sent by a peer is spelled the way to_hex spells a SHA-256
+pub fn is_valid_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_are_lowercase_sha256_hex() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(is_valid_hex(hash));
+
+        for hash in [
+            "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855",
+            &hash[..63],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85g",
+            "é3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85",
+            "",
+        ] {
+            assert!(!is_valid_hex(hash), "{} was accepted", hash);
+        }
+    }
+}
@@ -1,15 +1,37 @@
-mod http_requests;
-mod http_server;
+mod clipboard;
+mod commands;
+pub mod compression;
+pub mod events;
+pub mod history;
+pub mod http_requests;
+pub mod http_server;
+pub mod identity;
+pub mod integrity;
 mod logger;
-mod mdns;
-mod sysinfo;
-mod tcp_heartbeat;
+pub mod mdns;
+pub mod messages;
+pub mod network;
+pub mod pairing;
+pub mod progress;
+pub mod settings;
+mod storage;
+pub mod sysinfo;
+pub mod tcp_heartbeat;
+#[cfg(test)]
+mod test_support;
+pub mod throttle;
+pub mod transfers;
+pub mod tranzit;
 mod util;
+#[cfg(target_os = "android")]
 mod util_android;
 
 use once_cell::sync::OnceCell;
+use std::sync::Arc;
 use tauri::AppHandle;
 use tauri::Builder;
+use tauri::Manager;
+pub use tranzit::{Tranzit, TranzitConfig, DEFAULT_HTTPS_PORT};
 
 #[cfg(target_os = "android")]
 use crate::util_android::get_file_infos_with_previews;
@@ -21,7 +43,7 @@ static APP_HANDLE: OnceCell<AppHandle> = OnceCell::new();
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub async fn run() {
-    let mut app = Builder::default()
+    let app = Builder::default()
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_store::Builder::new().build())
@@ -29,36 +51,64 @@ pub async fn run() {
         .plugin(tauri_plugin_android_fs::init())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_notification::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
         .plugin(util::prevent_default())
         .invoke_handler(tauri::generate_handler![
-            sysinfo::get_sys_info,
-            mdns::start_mdns_responder,
-            mdns::stop_mdns_responder,
-            mdns::restart_mdns_responder,
-            mdns::discover_mdns_services,
-            http_server::start_http_server,
-            http_server::respond_to_request,
-            util::get_primary_ipv4,
+            commands::get_sys_info,
+            commands::start_mdns_responder,
+            commands::stop_mdns_responder,
+            commands::restart_mdns_responder,
+            commands::discover_mdns_services,
+            commands::start_http_server,
+            commands::respond_to_request,
             get_file_infos_with_previews,
-            tcp_heartbeat::add_device,
-            tcp_heartbeat::remove_device,
-            http_requests::assisted_discovery,
-            http_requests::file_transfer_request,
-            http_requests::upload_files
+            commands::add_device,
+            commands::remove_device,
+            commands::assisted_discovery,
+            commands::file_transfer_request,
+            commands::upload_files,
+            commands::cancel_transfer,
+            commands::pause_transfer,
+            commands::resume_transfer,
+            commands::set_transfer_rate_limit,
+            commands::pair_device,
+            commands::get_paired_devices,
+            commands::unpair_device,
+            commands::get_settings,
+            commands::get_settings_load_error,
+            commands::set_settings,
+            commands::get_transfer_history,
+            commands::delete_transfer_history,
+            commands::clear_transfer_history,
+            commands::text_message,
+            commands::send_message,
+            clipboard::read_clipboard,
+            clipboard::write_clipboard
         ])
         .setup(|app| {
             // Store the actual app handle in your global
             APP_HANDLE.set(app.handle().clone()).unwrap();
 
-            tcp_heartbeat::start_heartbeat_responder();
+            // load the persistent identity and paired devices of this install,
+            // events of the instance are forwarded to the webview
+            let config = TranzitConfig::new(app.path().app_data_dir()?);
+            // partial files are kept in the private cache of the app
+            #[cfg(target_os = "android")]
+            let config = TranzitConfig {
+                partials_dir: app.path().app_cache_dir()?.join("partial"),
+                ..config
+            };
+            let tranzit = Tranzit::new(config, Arc::new(app.handle().clone()))?;
+            app.manage(tranzit.clone());
 
-            let app_handle = app.handle().clone();
             tauri::async_runtime::spawn(async move {
-                tcp_heartbeat::start_heartbeat(app_handle).await;
+                if let Err(e) = tranzit.start_heartbeat_responder().await {
+                    debug_log!("{}", e);
+                }
+                tranzit.start_heartbeat();
+                tranzit.listen_for_mdns_services();
             });
 
-            mdns::listen_for_mdns_services(app.handle().clone());
-
             Ok(())
         });
 
@@ -67,9 +117,7 @@ pub async fn run() {
         any(target_os = "linux", target_os = "macos", target_os = "windows"),
         not(debug_assertions)
     ))]
-    {
-        app = app.plugin(tauri_plugin_single_instance::init(|app, args, cwd| {}));
-    }
+    let app = app.plugin(tauri_plugin_single_instance::init(|_app, _args, _cwd| {}));
 
     app.run(tauri::generate_context!())
         .expect("error while running tauri application");
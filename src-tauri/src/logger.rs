@@ -11,6 +11,9 @@ macro_rules! debug_log {
 #[macro_export]
 macro_rules! debug_log {
     ($($arg:tt)*) => {
-        () // no-op in release builds
+        // no-op in release builds, the arguments still count as used
+        {
+            let _ = format_args!($($arg)*);
+        }
     };
 }
@@ -1,296 +1,473 @@
 use crate::debug_log;
-use crate::sysinfo;
-use libmdns::Responder;
-use mdns_sd::{ServiceDaemon, ServiceEvent};
-use once_cell::sync::Lazy;
+use crate::network;
+use crate::tranzit::Tranzit;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    collections::HashMap,
     hash::{Hash, Hasher},
-    net::IpAddr,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
     },
     thread,
-    thread::JoinHandle,
-    time::{Duration, Instant},
+    time::Duration,
 };
-use tauri::{AppHandle, Emitter};
-use uuid::Uuid;
-
-pub static APP_UUID: Lazy<String> = Lazy::new(|| Uuid::new_v4().to_string());
-
-// Globals to manage mDNS responder thread and running state
-static MDNS_THREAD: Lazy<Mutex<Option<JoinHandle<()>>>> = Lazy::new(|| Mutex::new(None));
-static SHOULD_RUN: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
-
-// Starts the mDNS responder in a background thread
-#[tauri::command]
-pub fn start_mdns_responder() {
-    let mut thread_handle = match MDNS_THREAD.lock() {
-        Ok(guard) => guard,
-        Err(e) => {
-            debug_log!("Failed to lock MDNS_THREAD mutex: {}", e);
-            return;
+
+const SERVICE_TYPE: &str = "_localdrop._tcp.local.";
+// how long a discovery waits for peers to answer
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+// how long the goodbye of the service gets to go out when it's stopped
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// One mDNS daemon per instance, it advertises the instance and browses for peers.
+// Several daemons on one machine fight over port 5353 on some systems.
+#[derive(Default)]
+pub(crate) struct MdnsState {
+    daemon: Mutex<Option<ServiceDaemon>>,
+    // full name of the service while it's registered
+    registered: Mutex<Option<String>>,
+    browsing: AtomicBool,
+    // peers seen by the browser, keyed on device id
+    peers: Mutex<HashMap<String, Peer>>,
+    // latest probe of the addresses of every service being resolved, keyed on its
+    // full name. A newer resolution or the service going away makes older ones stale.
+    probes: Mutex<HashMap<String, u64>>,
+    next_probe: AtomicU64,
+}
+
+impl Tranzit {
+    // The mDNS daemon of the instance, started on first use
+    fn mdns_daemon(&self) -> Result<ServiceDaemon, String> {
+        if self.inner.shutdown.is_cancelled() {
+            return Err("Instance is shutting down".into());
         }
-    };
 
-    if SHOULD_RUN.load(Ordering::SeqCst) {
-        debug_log!("mDNS responder already running!");
-        return;
+        let mut daemon = self.inner.mdns.daemon.lock().unwrap();
+        match &*daemon {
+            Some(daemon) => Ok(daemon.clone()),
+            None => {
+                let created =
+                    ServiceDaemon::new().map_err(|e| format!("Failed to create daemon: {}", e))?;
+                *daemon = Some(created.clone());
+                Ok(created)
+            }
+        }
     }
 
-    SHOULD_RUN.store(true, Ordering::SeqCst);
-    let should_run = Arc::clone(&SHOULD_RUN);
+    // The service advertising this instance, with the current system info in its TXT records
+    fn service_info(&self) -> Result<ServiceInfo, String> {
+        let sys_info = self.sys_info();
+        let device_id = &self.identity().device_id;
+
+        let properties = HashMap::from([
+            ("version".to_string(), "1.0".to_string()),
+            (
+                "os".to_string(),
+                sys_info.os_type.unwrap_or_else(|| "unknown".into()),
+            ),
+            (
+                "hostname".to_string(),
+                sys_info.hostname.unwrap_or_else(|| "unknown".into()),
+            ),
+            ("arch".to_string(), std::env::consts::ARCH.to_string()),
+            ("id".to_string(), device_id.clone()),
+        ]);
+
+        // the device id keeps the name unique on the network, whatever the hostname is
+        let host = format!("{}.local.", device_id);
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            device_id,
+            &host,
+            "",
+            self.https_port(),
+            properties,
+        )
+        .map(ServiceInfo::enable_addr_auto)
+        .map_err(|e| format!("Invalid service info: {}", e))
+    }
 
-    *thread_handle = Some(thread::spawn(move || {
-        let responder = match Responder::new() {
-            Ok(r) => r,
-            Err(e) => {
-                debug_log!("Failed to start responder: {}", e);
-                SHOULD_RUN.store(false, Ordering::SeqCst);
-                return;
+    // Advertise this instance on every interface. Calling it again while advertising
+    // announces the current TXT records, e.g. after the hostname changed.
+    pub fn start_mdns_responder(&self) {
+        let registered = self.service_info().and_then(|info| {
+            let fullname = info.get_fullname().to_string();
+            self.mdns_daemon()?
+                .register(info)
+                .map_err(|e| format!("Failed to register service: {}", e))?;
+            Ok(fullname)
+        });
+
+        match registered {
+            Ok(fullname) => {
+                debug_log!("mDNS service registered as {}", fullname);
+                *self.inner.mdns.registered.lock().unwrap() = Some(fullname);
             }
-        };
-
-        let sys_info = sysinfo::get_sys_info();
+            Err(e) => debug_log!("Failed to start responder: {}", e),
+        }
+    }
 
-        let os_type = sys_info.os_type.unwrap_or_else(|| "unknown".into());
-        let hostname = sys_info.hostname.unwrap_or_else(|| "unknown".into());
+    /// Stops advertising, peers are told the instance is gone
+    pub fn stop_mdns_responder(&self) {
+        let Some(fullname) = self.inner.mdns.registered.lock().unwrap().take() else {
+            debug_log!("mDNS responder was not running");
+            return;
+        };
+        let Some(daemon) = self.inner.mdns.daemon.lock().unwrap().clone() else {
+            return;
+        };
 
-        let txt_records = vec![
-            "version=1.0".to_string(),
-            format!("os={}", os_type),
-            format!("hostname={}", hostname),
-            format!("arch={}", std::env::consts::ARCH),
-            format!("id={}", APP_UUID.to_string()),
-        ];
+        match daemon.unregister(&fullname) {
+            Ok(status) => {
+                // the goodbye is sent by the daemon thread
+                let _ = status.recv_timeout(GOODBYE_TIMEOUT);
+                debug_log!("mDNS responder stopped");
+            }
+            Err(e) => debug_log!("Failed to unregister {}: {}", fullname, e),
+        }
+    }
 
-        let _svc = responder.register(
-            "_localdrop._tcp".to_string(),
-            "LocalDrop Peer".to_string(),
-            21212,
-            &txt_records.iter().map(|s| &**s).collect::<Vec<&str>>(),
-        );
+    /// Announce the current TXT records of this instance, there is nothing to restart
+    pub fn restart_mdns_responder(&self) {
+        self.start_mdns_responder();
+    }
 
-        debug_log!("mDNS service registered");
+    // Stop advertising and browsing for good, the daemon is shut down with the instance
+    pub(crate) fn shutdown_mdns(&self) {
+        self.stop_mdns_responder();
 
-        while should_run.load(Ordering::SeqCst) {
-            thread::sleep(Duration::from_secs(2));
+        if let Some(daemon) = self.inner.mdns.daemon.lock().unwrap().take() {
+            if let Ok(status) = daemon.shutdown() {
+                let _ = status.recv_timeout(GOODBYE_TIMEOUT);
+            }
         }
+    }
+}
 
-        debug_log!("mDNS responder thread exiting");
-    }));
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Peer {
+    pub name: String,
+    // best reachable of the addresses, scoped when it's an IPv6 link-local one
+    pub ip: String,
+    // every address the peer can be tried at, best first
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    pub port: u16,
+    pub hostname: String,
+    pub service_type: String,
+    pub os: String,
+    pub id: String,
 }
 
-/// Stops the mDNS responder thread
-#[tauri::command]
-pub fn stop_mdns_responder() {
-    SHOULD_RUN.store(false, Ordering::SeqCst);
-
-    match MDNS_THREAD.lock() {
-        Ok(mut guard) => {
-            if let Some(handle) = guard.take() {
-                if handle.join().is_ok() {
-                    debug_log!("mDNS responder stopped");
-                } else {
-                    debug_log!("Failed to join mDNS responder thread");
-                }
-            } else {
-                debug_log!("mDNS responder was not running");
-            }
-        }
-        Err(e) => {
-            debug_log!("Failed to lock MDNS_THREAD mutex: {}", e);
+impl Peer {
+    // What identifies the device, the same over every interface and after its address
+    // changes. Versions that don't advertise an id only have their address.
+    pub fn key(&self) -> String {
+        if self.id == "unknown" {
+            format!("{}:{}", self.ip, self.port)
+        } else {
+            self.id.clone()
         }
     }
-}
 
-/// Restart mDNS responder (stop then start)
-#[tauri::command]
-pub fn restart_mdns_responder() {
-    stop_mdns_responder();
-    start_mdns_responder();
-}
+    // Take in what another sighting of the same device knows, its addresses go first
+    // as they are the most recent. Returns whether anything changed.
+    pub fn merge(&mut self, other: &Peer) -> bool {
+        let addresses = network::merge_addresses(&other.addresses, &self.addresses);
+        let changed = self.ip != other.ip || self.addresses != addresses;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct Peer {
-    name: String,
-    ip: IpAddr,
-    port: u16,
-    hostname: String,
-    service_type: String,
-    os: String,
-    id: String,
+        *self = Peer {
+            addresses,
+            ..other.clone()
+        };
+        changed
+    }
 }
 
-// Implement hash + equality based on IP + port to avoid duplicates
+// Implement hash + equality based on the device id, one device can be seen at
+// several addresses
 impl PartialEq for Peer {
     fn eq(&self, other: &Self) -> bool {
-        self.ip == other.ip && self.port == other.port
+        self.key() == other.key()
     }
 }
 impl Eq for Peer {}
 impl Hash for Peer {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.ip.hash(state);
-        self.port.hash(state);
+        self.key().hash(state);
     }
 }
 
-/// Asynchronous mDNS discovery that runs blocking code on Tauri's async runtime
-#[tauri::command]
-pub async fn discover_mdns_services() -> Result<Vec<Peer>, String> {
-    tauri::async_runtime::spawn_blocking(|| {
-        debug_log!("🔍 Starting mDNS discovery for _localdrop._tcp.local.");
-
-        let mdns = ServiceDaemon::new().map_err(|e| format!("Failed to create daemon: {}", e))?;
-        let receiver = mdns
-            .browse("_localdrop._tcp.local.")
-            .map_err(|e| format!("Failed to browse: {}", e))?;
-
-        let timeout = Duration::from_secs(2);
-        let start = Instant::now();
-        let mut discovered_peers = HashSet::new();
-
-        while start.elapsed() < timeout {
-            match receiver.recv_timeout(Duration::from_millis(500)) {
-                Ok(ServiceEvent::ServiceResolved(info)) => {
-                    let props = info.get_properties();
-                    let os = props
-                        .get("os")
-                        .map(|p| p.val_str().to_string())
-                        .unwrap_or_else(|| "unknown".into());
-                    let hostname = props
-                        .get("hostname")
-                        .map(|p| p.val_str().to_string())
-                        .unwrap_or_else(|| "unknown".into());
-                    let id = props
-                        .get("id")
-                        .map(|p| p.val_str().to_string())
-                        .unwrap_or_else(|| "unknown".into());
-
-                    for ip in info.get_addresses() {
-                        if ip.is_ipv4() {
-                            let peer = Peer {
-                                name: info.get_fullname().to_string(),
-                                ip: *ip,
-                                port: info.get_port(),
-                                hostname: hostname.clone(),
-                                service_type: info.get_type().to_string(),
-                                os: os.clone(),
-                                id: id.clone(),
-                            };
-                            debug_log!("🗺️ Discovered peer: {:?}", peer);
-                            discovered_peers.insert(peer);
-                        }
-                    }
-                }
-                Ok(_) => {}
-                Err(e) => {
-                    debug_log!("Error receiving mDNS event: {}", e);
-                }
-            }
-        }
-
-        let discovered_vec: Vec<Peer> = discovered_peers.iter().cloned().collect();
-
-        debug_log!(
-            "Discovery complete. Found {} unique peer(s).",
-            discovered_vec.len()
-        );
+// Peer of a resolved service, reached at the best of every address it advertises
+// on every interface
+fn resolved_peer(info: &ServiceInfo) -> Option<Peer> {
+    let props = info.get_properties();
+    let prop = |key: &str| {
+        props
+            .get(key)
+            .map(|p| p.val_str().to_string())
+            .unwrap_or_else(|| "unknown".into())
+    };
 
-        Ok(discovered_vec)
+    let port = info.get_port();
+    let addresses = network::candidates(info.get_addresses().iter().copied());
+    let ip = network::pick_reachable(&addresses, port)?;
+
+    Some(Peer {
+        name: info.get_fullname().to_string(),
+        ip,
+        addresses,
+        port,
+        hostname: prop("hostname"),
+        service_type: info.get_type().to_string(),
+        os: prop("os"),
+        id: prop("id"),
     })
-    .await
-    .map_err(|e| format!("Thread panicked: {}", e))?
 }
 
-/// Passive mDNS listener that emits discovered peers as events to frontend
-#[tauri::command]
-pub fn listen_for_mdns_services(app_handle: AppHandle) {
-    std::thread::spawn(move || {
-        debug_log!("👂 Passive mDNS listener started.");
+impl Tranzit {
+    /// Peers on the network. Starts browsing if the instance isn't yet and gives
+    /// peers a moment to answer.
+    pub async fn discover_mdns_services(&self) -> Result<Vec<Peer>, String> {
+        debug_log!("🔍 Starting mDNS discovery for {}", SERVICE_TYPE);
+        self.browse_mdns_services()?;
+        tokio::time::sleep(DISCOVERY_TIMEOUT).await;
+
+        let own_id = &self.identity().device_id;
+        let peers: Vec<Peer> = self
+            .inner
+            .mdns
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| &peer.id != own_id)
+            .cloned()
+            .collect();
+
+        debug_log!("Discovery complete. Found {} unique peer(s).", peers.len());
+
+        Ok(peers)
+    }
 
-        let mdns = match ServiceDaemon::new() {
-            Ok(daemon) => daemon,
-            Err(e) => {
-                debug_log!("Failed to create mDNS daemon: {}", e);
-                return;
-            }
-        };
+    /// Passive mDNS listener that emits discovered peers as events to frontend
+    pub fn listen_for_mdns_services(&self) {
+        if let Err(e) = self.browse_mdns_services() {
+            debug_log!("{}", e);
+        }
+    }
 
-        let receiver = match mdns.browse("_localdrop._tcp.local.") {
-            Ok(r) => r,
+    // Browse for peers on the shared daemon, once per instance. A daemon has one
+    // browser per service type, browsing again would replace it.
+    fn browse_mdns_services(&self) -> Result<(), String> {
+        if self.inner.mdns.browsing.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let receiver = match self.mdns_daemon().and_then(|mdns| {
+            mdns.browse(SERVICE_TYPE)
+                .map_err(|e| format!("Failed to browse: {}", e))
+        }) {
+            Ok(receiver) => receiver,
             Err(e) => {
-                debug_log!("Failed to browse: {}", e);
-                return;
+                self.inner.mdns.browsing.store(false, Ordering::SeqCst);
+                return Err(e);
             }
         };
 
-        let mut discovered = HashSet::new();
-        let mut consecutive_errors = 0;
-        const MAX_ERRORS: usize = 5;
-
-        loop {
-            match receiver.recv() {
-                Ok(ServiceEvent::ServiceResolved(info)) => {
-                    consecutive_errors = 0;
-
-                    let props = info.get_properties();
-                    let os = props
-                        .get("os")
-                        .map(|p| p.val_str().to_string())
-                        .unwrap_or_else(|| "unknown".into());
-                    let hostname = props
-                        .get("hostname")
-                        .map(|p| p.val_str().to_string())
-                        .unwrap_or_else(|| "unknown".into());
-
-                    let id = props
-                        .get("id")
-                        .map(|p| p.val_str().to_string())
-                        .unwrap_or_else(|| "unknown".into());
-
-                    for ip in info.get_addresses() {
-                        if ip.is_ipv4() {
-                            let peer = Peer {
-                                name: info.get_fullname().to_string(),
-                                ip: *ip,
-                                port: info.get_port(),
-                                hostname: hostname.clone(),
-                                service_type: info.get_type().to_string(),
-                                os: os.clone(),
-                                id: id.clone(),
-                            };
-
-                            if discovered.insert(peer.clone()) {
-                                debug_log!("🗺️ Discovered peer: {:?}", peer);
-                                if let Err(err) = app_handle.emit("mdns-peer-discovered", &peer) {
-                                    debug_log!("Failed to emit peer: {}", err);
-                                }
-                            }
-                        }
+        let tranzit = self.clone();
+        thread::spawn(move || {
+            debug_log!("👂 Passive mDNS listener started.");
+
+            // the channel closes when the daemon shuts down
+            while let Ok(event) = receiver.recv() {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => tranzit.probe_service(info),
+                    // the peer said goodbye or its records expired, it's emitted again
+                    // once it announces itself again
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        tranzit.inner.mdns.probes.lock().unwrap().remove(&fullname);
+                        tranzit.peer_removed(&fullname);
                     }
+                    _ => {}
                 }
-                Ok(_) => {
-                    consecutive_errors = 0;
-                }
-                Err(e) => {
-                    consecutive_errors += 1;
-                    debug_log!("Receiver error #{}: {}", consecutive_errors, e);
+            }
 
-                    if consecutive_errors >= MAX_ERRORS {
-                        debug_log!("Exceeded max consecutive errors, stopping listener.");
-                        break;
-                    }
+            tranzit.inner.mdns.browsing.store(false, Ordering::SeqCst);
+            debug_log!("mDNS listener exited.");
+        });
+
+        Ok(())
+    }
 
-                    std::thread::sleep(Duration::from_millis(50));
+    // Find out which address of a resolved service to reach the peer at. Probing takes
+    // a moment, it's done on a thread of its own to keep the events coming.
+    fn probe_service(&self, info: ServiceInfo) {
+        let fullname = info.get_fullname().to_string();
+        let probe = self.inner.mdns.next_probe.fetch_add(1, Ordering::SeqCst);
+        self.inner
+            .mdns
+            .probes
+            .lock()
+            .unwrap()
+            .insert(fullname.clone(), probe);
+
+        let tranzit = self.clone();
+        thread::spawn(move || {
+            let peer = resolved_peer(&info);
+
+            // held until the peer is in, so a removal can't slip in between
+            let mut probes = tranzit.inner.mdns.probes.lock().unwrap();
+            if probes.get(&fullname) != Some(&probe) {
+                return;
+            }
+            probes.remove(&fullname);
+
+            if let Some(peer) = peer {
+                tranzit.peer_resolved(peer);
+            }
+        });
+    }
+
+    // Remember a resolved peer, known devices are emitted again when their addresses change
+    fn peer_resolved(&self, peer: Peer) {
+        if peer.id == self.identity().device_id {
+            return;
+        }
+
+        let peer = {
+            let mut peers = self.inner.mdns.peers.lock().unwrap();
+            match peers.get_mut(&peer.key()) {
+                Some(known) => known.merge(&peer).then(|| known.clone()),
+                None => {
+                    peers.insert(peer.key(), peer.clone());
+                    Some(peer)
                 }
             }
+        };
+
+        if let Some(peer) = peer {
+            debug_log!("🗺️ Discovered peer: {:?}", peer);
+            self.emit("mdns-peer-discovered", &peer);
+        }
+    }
+
+    fn peer_removed(&self, fullname: &str) {
+        let removed: Vec<Peer> = {
+            let mut peers = self.inner.mdns.peers.lock().unwrap();
+            let keys: Vec<String> = peers
+                .iter()
+                .filter(|(_, peer)| peer.name == fullname)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.iter().filter_map(|key| peers.remove(key)).collect()
+        };
+
+        for peer in removed {
+            debug_log!("👋 Peer removed: {:?}", peer);
+            self.emit("mdns-peer-removed", &peer);
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use crate::test_support::instance;
+    use std::collections::HashSet;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    fn peer(id: &str, ip: &str, port: u16) -> Peer {
+        Peer {
+            name: format!("{}.{}", id, SERVICE_TYPE),
+            ip: ip.to_string(),
+            addresses: vec![ip.to_string()],
+            port,
+            hostname: "laptop".into(),
+            service_type: SERVICE_TYPE.into(),
+            os: "linux".into(),
+            id: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn same_device_across_addresses() {
+        let wifi = peer("device-1", "192.168.1.20", 21212);
+        let ethernet = peer("device-1", "10.0.0.5", 21213);
+
+        assert_eq!(wifi.key(), "device-1");
+        assert_eq!(wifi, ethernet);
+        assert_ne!(wifi, peer("device-2", "192.168.1.20", 21212));
+        assert_eq!(HashSet::from([wifi, ethernet]).len(), 1);
+    }
+
+    #[test]
+    fn unknown_devices_by_address() {
+        let first = peer("unknown", "192.168.1.20", 21212);
+        let second = peer("unknown", "192.168.1.20", 21213);
+
+        assert_eq!(first.key(), "192.168.1.20:21212");
+        assert_ne!(first, second);
+        assert_eq!(first, peer("unknown", "192.168.1.20", 21212));
+        assert_eq!(HashSet::from([first, second]).len(), 2);
+    }
 
-        debug_log!("mDNS listener exited.");
-    });
+    #[test]
+    fn merge_puts_recent_addresses_first() {
+        let mut known = peer("device-1", "192.168.1.20", 21212);
+        known.addresses.push("10.0.0.5".into());
+        let mut seen = peer("device-1", "10.0.0.5", 21212);
+        seen.hostname = "renamed".into();
+
+        assert!(known.merge(&seen));
+        assert_eq!(known.ip, "10.0.0.5");
+        assert_eq!(known.addresses, ["10.0.0.5", "192.168.1.20"]);
+        assert_eq!(known.hostname, "renamed");
+
+        // seeing it again the same way changes nothing
+        let again = known.clone();
+        assert!(!known.merge(&again));
+        assert_eq!(known.addresses, ["10.0.0.5", "192.168.1.20"]);
+    }
+
+    fn next_event(events: &mut UnboundedReceiver<Event>) -> (String, String) {
+        let event = events.try_recv().unwrap();
+        (
+            event.name,
+            event.payload["id"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn removed_peers_come_back() {
+        let (tranzit, mut events, _dir) = instance();
+        let device = peer("device-1", "192.168.1.20", 21212);
+
+        tranzit.peer_resolved(device.clone());
+        assert_eq!(
+            next_event(&mut events),
+            ("mdns-peer-discovered".into(), "device-1".into())
+        );
+
+        tranzit.peer_removed(&device.name);
+        assert_eq!(
+            next_event(&mut events),
+            ("mdns-peer-removed".into(), "device-1".into())
+        );
+        assert!(tranzit.inner.mdns.peers.lock().unwrap().is_empty());
+
+        // other services going away leave it alone
+        tranzit.peer_removed(&format!("someone-else.{}", SERVICE_TYPE));
+        assert!(events.try_recv().is_err());
+
+        // it's a new device again once it announces itself
+        tranzit.peer_resolved(device);
+        assert_eq!(
+            next_event(&mut events),
+            ("mdns-peer-discovered".into(), "device-1".into())
+        );
+        assert!(events.try_recv().is_err());
+    }
 }
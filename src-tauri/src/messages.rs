@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+// Messages bigger than this are refused, images that big are better sent as files
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Text,
+    Url,
+    Image,
+}
+
+// Text, a link or an image sent to a peer instead of files
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Message {
+    pub kind: MessageKind,
+    // the text or the link, images are a base64 encoded png
+    pub content: String,
+    // it was copied on the sending device, the receiver puts it on its own clipboard
+    #[serde(default)]
+    pub clipboard: bool,
+}
+
+impl Message {
+    // Text typed or copied by the user, a lone http(s) link is sent as a url
+    pub fn text(text: &str, clipboard: bool) -> Self {
+        let trimmed = text.trim();
+        let is_url = !trimmed.contains(char::is_whitespace)
+            && url::Url::parse(trimmed).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+
+        if is_url {
+            Self {
+                kind: MessageKind::Url,
+                content: trimmed.to_string(),
+                clipboard,
+            }
+        } else {
+            Self {
+                kind: MessageKind::Text,
+                content: text.to_string(),
+                clipboard,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lone_web_links_are_sent_as_urls() {
+        let message = Message::text("  https://example.com/a?b=c\n", true);
+        assert_eq!(message.kind, MessageKind::Url);
+        assert_eq!(message.content, "https://example.com/a?b=c");
+        assert!(message.clipboard);
+    }
+
+    #[test]
+    fn anything_else_is_sent_as_typed() {
+        for text in [
+            "see https://example.com",
+            "ftp://example.com/file",
+            "example.com",
+            " hello ",
+        ] {
+            let message = Message::text(text, false);
+            assert_eq!(message.kind, MessageKind::Text, "{}", text);
+            assert_eq!(message.content, text);
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use crate::debug_log;
+use if_addrs::{get_if_addrs, IfAddr, Interface};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, UdpSocket};
+use std::time::Duration;
+
+// Interfaces of containers, VMs and VPNs, peers on the local network are rarely
+// reached through them even when they advertise addresses there
+const VIRTUAL_INTERFACES: &[&str] = &[
+    "docker",
+    "br-",
+    "veth",
+    "virbr",
+    "vboxnet",
+    "vmnet",
+    "vEthernet",
+    "lxc",
+    "lxd",
+    "podman",
+    "cni",
+    "flannel",
+    "tun",
+    "tap",
+    "wg",
+    "utun",
+    "tailscale",
+    "zt",
+];
+
+// How long a candidate address gets to accept a connection when picking one
+const PROBE_TIMEOUT: Duration = Duration::from_millis(800);
+
+// Addresses servers listen on. Where an IPv6 socket takes IPv4 connections as well,
+// e.g. on Linux, binding the IPv4 one fails and isn't needed.
+pub const UNSPECIFIED: [IpAddr; 2] = [
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+];
+
+// Socket address of a peer from an ip as the app passes it around, IPv6 link-local
+// addresses carry the scope id of the interface they're reached on, e.g. "fe80::1%2"
+// or "fe80::1%eth0"
+pub fn socket_addr(ip: &str, port: u16) -> Result<SocketAddr, String> {
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    let (addr, scope) = ip
+        .split_once('%')
+        .ok_or_else(|| format!("Invalid address {}", ip))?;
+    let addr: Ipv6Addr = addr
+        .parse()
+        .map_err(|e| format!("Invalid address {}: {}", ip, e))?;
+    let scope_id = match scope.parse::<u32>() {
+        Ok(scope_id) => scope_id,
+        Err(_) => interface_index(scope).ok_or_else(|| format!("Unknown interface {}", scope))?,
+    };
+
+    Ok(SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope_id)))
+}
+
+// The other way round, IPv4 peers connecting to an IPv6 socket show up as ::ffff:a.b.c.d
+pub fn ip_string(addr: &SocketAddr) -> String {
+    match canonical(*addr) {
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            format!("{}%{}", addr.ip(), addr.scope_id())
+        }
+        addr => addr.ip().to_string(),
+    }
+}
+
+// Address of an IPv4 peer that connected to an IPv6 socket as a plain IPv4 one
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+// Local address packets to a peer leave from, the routing table picks the interface.
+// Nothing is sent, connecting a udp socket only looks up the route.
+pub fn local_ip_for(peer: &SocketAddr) -> Option<String> {
+    let unspecified = match peer {
+        SocketAddr::V4(_) => UNSPECIFIED[1],
+        SocketAddr::V6(_) => UNSPECIFIED[0],
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).ok()?;
+    socket.connect(peer).ok()?;
+    let mut local = socket.local_addr().ok()?;
+    if let (SocketAddr::V6(local), SocketAddr::V6(peer)) = (&mut local, peer) {
+        if is_link_local(&IpAddr::V6(*local.ip())) {
+            local.set_scope_id(peer.scope_id());
+        }
+    }
+
+    Some(ip_string(&local))
+}
+
+fn interface_index(name: &str) -> Option<u32> {
+    get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|iface| iface.name == name)
+        .and_then(|iface| iface.index)
+}
+
+fn is_virtual(iface: &Interface) -> bool {
+    VIRTUAL_INTERFACES
+        .iter()
+        .any(|prefix| iface.name.starts_with(prefix))
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+// Whether an address is in the subnet of an interface
+fn in_subnet(ip: &IpAddr, iface: &Interface) -> bool {
+    match (ip, &iface.addr) {
+        (IpAddr::V4(ip), IfAddr::V4(addr)) => {
+            let mask = u32::from(addr.netmask);
+            u32::from(*ip) & mask == u32::from(addr.ip) & mask
+        }
+        (IpAddr::V6(ip), IfAddr::V6(addr)) => {
+            let mask = u128::from(addr.netmask);
+            u128::from(*ip) & mask == u128::from(addr.ip) & mask
+        }
+        _ => false,
+    }
+}
+
+// How likely a peer is reached at an address, higher is better. Addresses on the
+// network of a physical interface win, IPv4 over IPv6 as it's the more likely to
+// get through firewalls.
+fn rank(ip: &IpAddr, scope_iface: Option<&Interface>, interfaces: &[Interface]) -> u8 {
+    if ip.is_loopback() {
+        return 0;
+    }
+    if is_link_local(ip) {
+        return match (ip, scope_iface) {
+            (IpAddr::V6(_), Some(iface)) if !is_virtual(iface) => 2,
+            (IpAddr::V6(_), Some(_)) => 1,
+            _ => 0,
+        };
+    }
+
+    match interfaces.iter().find(|iface| in_subnet(ip, iface)) {
+        Some(iface) if !is_virtual(iface) => match ip {
+            IpAddr::V4(_) => 5,
+            IpAddr::V6(_) => 4,
+        },
+        Some(_) => 1,
+        // routed somewhere else, e.g. another subnet of the same network
+        None => 3,
+    }
+}
+
+// Every address a peer can be tried at, best first. IPv6 link-local addresses don't
+// say which link they are on, so there is one for every interface that has one.
+pub fn candidates(addresses: impl IntoIterator<Item = IpAddr>) -> Vec<String> {
+    let interfaces: Vec<Interface> = get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|iface| !iface.is_loopback() && iface.is_oper_up())
+        .collect();
+
+    candidates_on(addresses, &interfaces)
+}
+
+fn candidates_on(
+    addresses: impl IntoIterator<Item = IpAddr>,
+    interfaces: &[Interface],
+) -> Vec<String> {
+    let mut ranked: Vec<(u8, String)> = Vec::new();
+    for ip in addresses {
+        if ip.is_unspecified() {
+            continue;
+        }
+
+        if let IpAddr::V6(v6) = ip {
+            if is_link_local(&ip) {
+                for iface in interfaces {
+                    let (IfAddr::V6(local), Some(index)) = (&iface.addr, iface.index) else {
+                        continue;
+                    };
+                    if !is_link_local(&IpAddr::V6(local.ip)) {
+                        continue;
+                    }
+                    let addr = SocketAddr::V6(SocketAddrV6::new(v6, 0, 0, index));
+                    let rank = rank(&ip, Some(iface), interfaces);
+                    push_unique(&mut ranked, rank, ip_string(&addr));
+                }
+                continue;
+            }
+        }
+
+        push_unique(&mut ranked, rank(&ip, None, interfaces), ip.to_string());
+    }
+
+    // ties go by address so the same peer doesn't flip between equally good ones
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    ranked.into_iter().map(|(_, ip)| ip).collect()
+}
+
+// Addresses a device was seen at lately followed by the ones known from before
+pub fn merge_addresses<'a>(
+    recent: impl IntoIterator<Item = &'a String>,
+    older: &'a [String],
+) -> Vec<String> {
+    let mut addresses: Vec<String> = Vec::new();
+    for ip in recent.into_iter().chain(older) {
+        if !addresses.contains(ip) {
+            addresses.push(ip.clone());
+        }
+    }
+    addresses
+}
+
+// an interface can hold several link-local addresses, the peer is only tried once per interface
+fn push_unique(ranked: &mut Vec<(u8, String)>, rank: u8, ip: String) {
+    if !ranked.iter().any(|(_, known)| *known == ip) {
+        ranked.push((rank, ip));
+    }
+}
+
+// The best of the candidate addresses that accepts connections on a port, the
+// best ranked one when none does. Blocks while the addresses are tried.
+pub fn pick_reachable(candidates: &[String], port: u16) -> Option<String> {
+    if candidates.len() <= 1 {
+        return candidates.first().cloned();
+    }
+
+    let reachable: Vec<bool> = std::thread::scope(|scope| {
+        let probes: Vec<_> = candidates
+            .iter()
+            .map(|ip| {
+                scope.spawn(move || {
+                    socket_addr(ip, port)
+                        .is_ok_and(|addr| TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).is_ok())
+                })
+            })
+            .collect();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().unwrap_or(false))
+            .collect()
+    });
+
+    let picked = candidates
+        .iter()
+        .zip(reachable)
+        .find(|(_, reachable)| *reachable)
+        .map(|(ip, _)| ip.clone());
+    if picked.is_none() {
+        debug_log!("None of {:?} accepted a connection", candidates);
+    }
+
+    picked.or_else(|| candidates.first().cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use if_addrs::{IfOperStatus, Ifv4Addr, Ifv6Addr};
+
+    fn iface(name: &str, index: u32, addr: &str, prefixlen: u8) -> Interface {
+        let addr = match addr.parse().unwrap() {
+            IpAddr::V4(ip) => IfAddr::V4(Ifv4Addr {
+                ip,
+                netmask: Ipv4Addr::from(u32::MAX << (32 - prefixlen)),
+                prefixlen,
+                broadcast: None,
+            }),
+            IpAddr::V6(ip) => IfAddr::V6(Ifv6Addr {
+                ip,
+                netmask: Ipv6Addr::from(u128::MAX << (128 - prefixlen)),
+                prefixlen,
+                broadcast: None,
+            }),
+        };
+
+        Interface {
+            name: name.into(),
+            addr,
+            index: Some(index),
+            oper_status: IfOperStatus::Up,
+            #[cfg(windows)]
+            adapter_name: name.into(),
+        }
+    }
+
+    // a wired interface on a dual stack network and a container bridge
+    fn interfaces() -> Vec<Interface> {
+        vec![
+            iface("eth0", 2, "192.168.1.10", 24),
+            iface("eth0", 2, "2001:db8::10", 64),
+            iface("eth0", 2, "fe80::1", 64),
+            iface("docker0", 3, "172.17.0.1", 16),
+            iface("docker0", 3, "fe80::42", 64),
+        ]
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn link_local_addresses_are_tried_on_every_link() {
+        assert_eq!(
+            candidates_on(ips(&["fe80::99"]), &interfaces()),
+            ["fe80::99%2", "fe80::99%3"]
+        );
+        // no interface with a link-local address, nowhere to reach it
+        let ipv4_only = [iface("eth0", 2, "192.168.1.10", 24)];
+        assert!(candidates_on(ips(&["fe80::99"]), &ipv4_only).is_empty());
+    }
+
+    #[test]
+    fn candidates_are_ranked() {
+        let candidates = candidates_on(
+            ips(&[
+                "127.0.0.1",
+                "fe80::99",
+                "172.17.0.5",
+                "10.0.0.5",
+                "2001:db8::5",
+                "192.168.1.20",
+            ]),
+            &interfaces(),
+        );
+
+        assert_eq!(
+            candidates,
+            [
+                // on the network of eth0, IPv4 first
+                "192.168.1.20",
+                "2001:db8::5",
+                // routed somewhere else
+                "10.0.0.5",
+                "fe80::99%2",
+                // behind the container bridge
+                "172.17.0.5",
+                "fe80::99%3",
+                "127.0.0.1",
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_go_by_address() {
+        let interfaces = interfaces();
+        let forward = candidates_on(ips(&["192.168.1.30", "192.168.1.20"]), &interfaces);
+        let backward = candidates_on(ips(&["192.168.1.20", "192.168.1.30"]), &interfaces);
+        assert_eq!(forward, ["192.168.1.20", "192.168.1.30"]);
+        assert_eq!(forward, backward);
+    }
+
+    #[test]
+    fn duplicates_and_unspecified_addresses_are_dropped() {
+        let candidates = candidates_on(
+            ips(&["192.168.1.20", "0.0.0.0", "::", "192.168.1.20"]),
+            &interfaces(),
+        );
+        assert_eq!(candidates, ["192.168.1.20"]);
+    }
+
+    #[test]
+    fn recent_addresses_come_first() {
+        let older = vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()];
+        let recent = ["10.0.0.2".to_string(), "10.0.0.3".to_string()];
+
+        assert_eq!(
+            merge_addresses(&recent, &older),
+            ["10.0.0.2", "10.0.0.3", "10.0.0.1"]
+        );
+        assert_eq!(merge_addresses(&[], &older), older);
+        assert_eq!(merge_addresses(&recent, &[]), recent);
+    }
+
+    #[test]
+    fn merged_addresses_are_unique() {
+        let recent = ["10.0.0.1".to_string(), "10.0.0.1".to_string()];
+        assert_eq!(
+            merge_addresses(&recent, &["10.0.0.1".to_string()]),
+            ["10.0.0.1"]
+        );
+    }
+
+    #[test]
+    fn scoped_addresses_round_trip() {
+        let addr = socket_addr("fe80::1%2", 21212).unwrap();
+        assert_eq!(ip_string(&addr), "fe80::1%2");
+        assert_eq!(
+            ip_string(&"[::ffff:192.168.1.2]:80".parse().unwrap()),
+            "192.168.1.2"
+        );
+        assert!(socket_addr("fe80::1%no-such-interface", 1).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::debug_log;
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// A device whose certificate fingerprint was pinned during pairing
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PairedDevice {
+    pub device_id: String,
+    pub hostname: String,
+    pub fingerprint: String,
+    pub paired_at: u64,
+}
+
+// Paired devices keyed on device id, persisted to a json file in the data directory
+pub struct PairedDevices {
+    path: PathBuf,
+    devices: Mutex<HashMap<String, PairedDevice>>,
+}
+
+impl PairedDevices {
+    // Load previously paired devices from disk
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join("paired_devices.json");
+
+        let devices: Vec<PairedDevice> = storage::load_json(&path)
+            .unwrap_or_else(|e| {
+                debug_log!("{}", e);
+                None
+            })
+            .unwrap_or_default();
+
+        debug_log!("🤝 Loaded {} paired device(s)", devices.len());
+
+        Self {
+            path,
+            devices: Mutex::new(
+                devices
+                    .into_iter()
+                    .map(|d| (d.device_id.clone(), d))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn persist(&self, devices: &HashMap<String, PairedDevice>) {
+        let list: Vec<&PairedDevice> = devices.values().collect();
+        match serde_json::to_string_pretty(&list) {
+            Ok(json) => {
+                if let Err(e) = storage::write_atomic(&self.path, json.as_bytes()) {
+                    debug_log!("Failed to persist paired devices: {}", e);
+                }
+            }
+            Err(e) => debug_log!("Failed to serialize paired devices: {}", e),
+        }
+    }
+
+    // Pin the certificate fingerprint of a device, replacing any earlier pin
+    pub fn pin(&self, device_id: &str, hostname: &str, fingerprint: &str) {
+        let paired_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if let Ok(mut paired) = self.devices.lock() {
+            paired.insert(
+                device_id.to_string(),
+                PairedDevice {
+                    device_id: device_id.to_string(),
+                    hostname: hostname.to_string(),
+                    fingerprint: fingerprint.to_string(),
+                    paired_at,
+                },
+            );
+            self.persist(&paired);
+        }
+
+        debug_log!("📌 Pinned {} ({}) to {}", hostname, device_id, fingerprint);
+    }
+
+    // Get the pinned certificate fingerprint of a device, if it was paired
+    pub fn pinned_fingerprint(&self, device_id: &str) -> Option<String> {
+        self.devices
+            .lock()
+            .ok()
+            .and_then(|paired| paired.get(device_id).map(|d| d.fingerprint.clone()))
+    }
+
+    // List all paired devices
+    pub fn list(&self) -> Vec<PairedDevice> {
+        self.devices
+            .lock()
+            .map(|paired| paired.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Forget a paired device, its certificate will no longer be checked
+    pub fn unpair(&self, device_id: &str) {
+        if let Ok(mut paired) = self.devices.lock() {
+            if paired.remove(device_id).is_some() {
+                self.persist(&paired);
+                debug_log!("Unpaired {}", device_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use crate::tranzit::Tranzit;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Progress is emitted at most this often per file and per transfer,
+// an event for every chunk floods the webview on a fast network
+const EMIT_INTERVAL: Duration = Duration::from_millis(250);
+// weight of the latest measurement in the current speed
+const SPEED_SMOOTHING: f64 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+// Progress of a file or of a whole transfer, sent to the front-end
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub transfer_id: String,
+    pub direction: Direction,
+    // only set for files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    pub bytes: u64,
+    pub total: u64,
+    pub percent: f64,
+    // bytes per second, over the last moments and since the first byte
+    pub speed: f64,
+    pub average_speed: f64,
+    // seconds left at the current speed, unknown until something went through
+    pub eta: Option<f64>,
+}
+
+// Bytes of a file or transfer that went through and how fast
+struct Meter {
+    bytes: u64,
+    total: u64,
+    // when the first byte went through and how many there were already, e.g. of a resumed file
+    started: Option<(Instant, u64)>,
+    sampled: (Instant, u64),
+    speed: f64,
+    emitted: Option<Instant>,
+    finished: bool,
+}
+
+impl Meter {
+    fn new(total: u64) -> Self {
+        Self {
+            bytes: 0,
+            total,
+            started: None,
+            sampled: (Instant::now(), 0),
+            speed: 0.0,
+            emitted: None,
+            finished: false,
+        }
+    }
+
+    fn set(&mut self, bytes: u64, now: Instant) {
+        if self.started.is_none() && bytes != self.bytes {
+            self.started = Some((now, self.bytes));
+            self.sampled = (now, self.bytes);
+        }
+        self.bytes = bytes.min(self.total);
+    }
+
+    // Whether it's time for another event, measures the speed when it is
+    fn due(&mut self, now: Instant) -> bool {
+        if self.bytes >= self.total {
+            if self.finished {
+                return false;
+            }
+            self.finished = true;
+        } else {
+            self.finished = false;
+            if self
+                .emitted
+                .is_some_and(|emitted| now.duration_since(emitted) < EMIT_INTERVAL)
+            {
+                return false;
+            }
+        }
+
+        let (sampled_at, sampled_bytes) = self.sampled;
+        let elapsed = now.duration_since(sampled_at).as_secs_f64();
+        if elapsed > 0.0 {
+            let speed = self.bytes.saturating_sub(sampled_bytes) as f64 / elapsed;
+            self.speed = if self.speed == 0.0 {
+                speed
+            } else {
+                SPEED_SMOOTHING * speed + (1.0 - SPEED_SMOOTHING) * self.speed
+            };
+        }
+        self.sampled = (now, self.bytes);
+        self.emitted = Some(now);
+
+        true
+    }
+
+    fn progress(&self, transfer_id: &str, direction: Direction, now: Instant) -> Progress {
+        let average_speed = match self.started {
+            Some((started, started_bytes)) => {
+                let elapsed = now.duration_since(started).as_secs_f64();
+                if elapsed > 0.0 {
+                    self.bytes.saturating_sub(started_bytes) as f64 / elapsed
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        let percent = if self.total == 0 {
+            100.0
+        } else {
+            // not rounded up, 100% is only shown once everything went through
+            (self.bytes as f64 / self.total as f64 * 100.0).floor()
+        };
+        let eta = if self.bytes >= self.total {
+            Some(0.0)
+        } else if self.speed > 0.0 {
+            Some((self.total - self.bytes) as f64 / self.speed)
+        } else {
+            None
+        };
+
+        Progress {
+            transfer_id: transfer_id.to_string(),
+            direction,
+            uuid: None,
+            filename: None,
+            bytes: self.bytes,
+            total: self.total,
+            percent,
+            speed: self.speed,
+            average_speed,
+            eta,
+        }
+    }
+}
+
+// Progress of hashing the files of a transfer request, they are read in full before
+// the request goes out. There is no transfer id yet, it's sent as "hashing-progress"
+// with the bytes of all files.
+pub struct HashingProgress {
+    meter: Meter,
+}
+
+impl HashingProgress {
+    pub fn new(total: u64) -> Self {
+        Self {
+            meter: Meter::new(total),
+        }
+    }
+
+    // Count more bytes as hashed, progress is emitted every now and then
+    pub fn advance(&mut self, tranzit: &Tranzit, bytes: u64) {
+        let now = Instant::now();
+        self.meter.set(self.meter.bytes + bytes, now);
+        if self.meter.due(now) {
+            let progress = self.meter.progress("", Direction::Send, now);
+            tranzit.emit(
+                "hashing-progress",
+                json!({
+                    "bytes": progress.bytes,
+                    "total": progress.total,
+                    "percent": progress.percent,
+                    "eta": progress.eta,
+                }),
+            );
+        }
+    }
+}
+
+struct ProgressState {
+    transfer: Meter,
+    // name and meter per file id
+    files: HashMap<String, (String, Meter)>,
+}
+
+// Progress of the files of a transfer, shared by everything sending or receiving them
+pub struct TransferProgress {
+    transfer_id: String,
+    direction: Direction,
+    state: Mutex<ProgressState>,
+}
+
+impl TransferProgress {
+    pub fn new(transfer_id: &str, direction: Direction) -> Self {
+        Self {
+            transfer_id: transfer_id.to_string(),
+            direction,
+            state: Mutex::new(ProgressState {
+                transfer: Meter::new(0),
+                files: HashMap::new(),
+            }),
+        }
+    }
+
+    // Count a file towards the transfer, files that were added already are left alone
+    pub fn add_file(&self, file_id: &str, name: &str, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        if !state.files.contains_key(file_id) {
+            state
+                .files
+                .insert(file_id.to_string(), (name.to_string(), Meter::new(size)));
+            state.transfer.total += size;
+            state.transfer.finished = false;
+        }
+    }
+
+    // Bytes of the whole transfer that went through so far
+    pub fn bytes(&self) -> u64 {
+        self.state.lock().unwrap().transfer.bytes
+    }
+
+    // Record how many bytes of a file went through, progress of the file and the
+    // transfer is emitted every now and then
+    pub fn update(&self, tranzit: &Tranzit, file_id: &str, bytes: u64) {
+        self.update_with(tranzit, file_id, |_| bytes);
+    }
+
+    // Record more bytes of a file, for files coming in over several connections
+    pub fn advance(&self, tranzit: &Tranzit, file_id: &str, bytes: u64) {
+        self.update_with(tranzit, file_id, |current| current + bytes);
+    }
+
+    // Record the bytes of a file given the ones recorded before. Counters shared by
+    // several connections are read in here, so their updates can't arrive out of order.
+    pub fn update_with(&self, tranzit: &Tranzit, file_id: &str, bytes: impl FnOnce(u64) -> u64) {
+        let now = Instant::now();
+
+        let (file_progress, transfer_progress) = {
+            let mut state = self.state.lock().unwrap();
+            let ProgressState { transfer, files } = &mut *state;
+            let Some((name, file)) = files.get_mut(file_id) else {
+                return;
+            };
+
+            let previous = file.bytes;
+            file.set(bytes(previous), now);
+            let file_progress = file.due(now).then(|| Progress {
+                uuid: Some(file_id.to_string()),
+                filename: Some(name.clone()),
+                ..file.progress(&self.transfer_id, self.direction, now)
+            });
+
+            transfer.set((transfer.bytes + file.bytes).saturating_sub(previous), now);
+            let transfer_progress = transfer
+                .due(now)
+                .then(|| transfer.progress(&self.transfer_id, self.direction, now));
+
+            (file_progress, transfer_progress)
+        };
+
+        if let Some(progress) = file_progress {
+            let event = match self.direction {
+                Direction::Send => "upload-progress",
+                Direction::Receive => "download-progress",
+            };
+            tranzit.emit(event, progress);
+        }
+        if let Some(progress) = transfer_progress {
+            tranzit.emit("transfer-progress", progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn events_are_throttled() {
+        let start = Instant::now();
+        let mut meter = Meter::new(1000);
+
+        meter.set(10, start);
+        assert!(meter.due(start));
+        meter.set(20, start + 100 * MS);
+        assert!(!meter.due(start + 100 * MS));
+        meter.set(30, start + 249 * MS);
+        assert!(!meter.due(start + 249 * MS));
+        meter.set(40, start + 250 * MS);
+        assert!(meter.due(start + 250 * MS));
+    }
+
+    #[test]
+    fn last_event_is_always_sent_once() {
+        let start = Instant::now();
+        let mut meter = Meter::new(1000);
+
+        meter.set(500, start);
+        assert!(meter.due(start));
+        meter.set(1000, start + 10 * MS);
+        assert!(meter.due(start + 10 * MS));
+        assert_eq!(
+            meter
+                .progress("t", Direction::Receive, start + 10 * MS)
+                .percent,
+            100.0
+        );
+        assert!(!meter.due(start + 500 * MS));
+    }
+
+    #[test]
+    fn speed_and_eta() {
+        let start = Instant::now();
+        let mut meter = Meter::new(1000);
+
+        // nothing measured yet
+        meter.set(100, start);
+        assert!(meter.due(start));
+        let progress = meter.progress("t", Direction::Send, start);
+        assert_eq!(progress.speed, 0.0);
+        assert_eq!(progress.eta, None);
+
+        meter.set(200, start + 1000 * MS);
+        assert!(meter.due(start + 1000 * MS));
+        let progress = meter.progress("t", Direction::Send, start + 1000 * MS);
+        assert_eq!(progress.speed, 100.0);
+        assert_eq!(progress.eta, Some(8.0));
+        assert_eq!(progress.average_speed, 200.0);
+
+        // the latest measurement only counts for part of the speed
+        meter.set(600, start + 2000 * MS);
+        assert!(meter.due(start + 2000 * MS));
+        let progress = meter.progress("t", Direction::Send, start + 2000 * MS);
+        assert!((progress.speed - 190.0).abs() < 1e-9);
+        assert!((progress.eta.unwrap() - 400.0 / 190.0).abs() < 1e-9);
+        assert_eq!(progress.average_speed, 300.0);
+    }
+
+    #[test]
+    fn percent_is_not_rounded_up() {
+        let start = Instant::now();
+        let mut meter = Meter::new(1000);
+
+        meter.set(999, start);
+        assert_eq!(meter.progress("t", Direction::Send, start).percent, 99.0);
+    }
+}
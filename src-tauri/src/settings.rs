@@ -0,0 +1,394 @@
+use crate::debug_log;
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Sends matching files to another directory, e.g. images from one device to a project folder
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DestinationRule {
+    // device id of the sender, any device when not set
+    #[serde(default)]
+    pub device_id: Option<String>,
+    // mime type like "image/png" or a whole kind like "image/*", any type when not set
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    // on Android this is a folder inside Downloads
+    pub dir: PathBuf,
+}
+
+impl DestinationRule {
+    fn matches(&self, device_id: Option<&str>, mime_type: &str) -> bool {
+        let device_matches = match &self.device_id {
+            Some(id) => device_id == Some(id.as_str()),
+            None => true,
+        };
+
+        let type_matches = match self.mime_type.as_deref() {
+            Some(pattern) => mime_type_matches(pattern, mime_type),
+            None => true,
+        };
+
+        device_matches && type_matches
+    }
+}
+
+// Whether a mime type like "image/png" matches a pattern like "image/png", "image/*"
+// or "*/*", case doesn't matter in either
+fn mime_type_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(kind) => mime_type
+            .split_once('/')
+            .is_some_and(|(mime_kind, _)| mime_kind.eq_ignore_ascii_case(kind)),
+        None => pattern.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+// What happens to transfer requests of a trusted device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoAcceptPolicy {
+    #[default]
+    Ask,
+    Accept,
+    Reject,
+}
+
+// A paired device whose transfer requests are answered without asking
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TrustedDevice {
+    pub device_id: String,
+    #[serde(default)]
+    pub policy: AutoAcceptPolicy,
+    // only transfers up to this many bytes in total are accepted, bigger ones are asked about
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    // only transfers of these types like "image/png" or "image/*" are accepted, any type when empty
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+}
+
+impl TrustedDevice {
+    // Answer to a transfer of files with the given mime types, none means ask the user
+    pub fn answer(&self, total_size: u64, mime_types: &[String]) -> Option<bool> {
+        match self.policy {
+            AutoAcceptPolicy::Ask => None,
+            AutoAcceptPolicy::Reject => Some(false),
+            AutoAcceptPolicy::Accept => {
+                let size_ok = self.max_size.is_none_or(|max| total_size <= max);
+                let types_ok = self.mime_types.is_empty()
+                    || mime_types.iter().all(|mime_type| {
+                        self.mime_types
+                            .iter()
+                            .any(|pattern| mime_type_matches(pattern, mime_type))
+                    });
+
+                (size_ok && types_ok).then_some(true)
+            }
+        }
+    }
+}
+
+// Speed limits for a single device, on top of the global ones
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PeerRateLimit {
+    pub device_id: String,
+    // bytes per second, no limit when not set
+    #[serde(default)]
+    pub upload_rate_limit: Option<u64>,
+    #[serde(default)]
+    pub download_rate_limit: Option<u64>,
+}
+
+// Order files of a transfer are sent in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOrder {
+    // the order the user picked them in
+    #[default]
+    Selection,
+    SmallestFirst,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Settings {
+    // received files are saved here, the OS downloads directory is used when not set
+    #[serde(default)]
+    pub receive_dir: Option<PathBuf>,
+    // checked in order, the first matching rule wins over the receive directory
+    #[serde(default)]
+    pub destination_rules: Vec<DestinationRule>,
+    // how many files of a transfer are sent at the same time, the rest wait in a queue
+    #[serde(default = "default_upload_concurrency")]
+    pub upload_concurrency: usize,
+    #[serde(default)]
+    pub queue_order: QueueOrder,
+    // small files go out together as one stream instead of one request each
+    #[serde(default = "default_batch_small_files")]
+    pub batch_small_files: bool,
+    // bytes per second over all transfers, no limit when not set
+    #[serde(default)]
+    pub upload_rate_limit: Option<u64>,
+    #[serde(default)]
+    pub download_rate_limit: Option<u64>,
+    #[serde(default)]
+    pub peer_rate_limits: Vec<PeerRateLimit>,
+    // paired devices whose transfer requests are answered without asking
+    #[serde(default)]
+    pub trusted_devices: Vec<TrustedDevice>,
+}
+
+fn default_upload_concurrency() -> usize {
+    4
+}
+
+fn default_batch_small_files() -> bool {
+    true
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            receive_dir: None,
+            destination_rules: Vec::new(),
+            upload_concurrency: default_upload_concurrency(),
+            queue_order: QueueOrder::default(),
+            batch_small_files: default_batch_small_files(),
+            upload_rate_limit: None,
+            download_rate_limit: None,
+            peer_rate_limits: Vec::new(),
+            trusted_devices: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    // Directory of the first rule matching a received file
+    pub fn matching_rule_dir(&self, device_id: Option<&str>, mime_type: &str) -> Option<&Path> {
+        self.destination_rules
+            .iter()
+            .find(|rule| rule.matches(device_id, mime_type))
+            .map(|rule| rule.dir.as_path())
+    }
+
+    pub fn peer_rate_limit(&self, device_id: &str) -> Option<&PeerRateLimit> {
+        self.peer_rate_limits
+            .iter()
+            .find(|limit| limit.device_id == device_id)
+    }
+
+    pub fn trusted_device(&self, device_id: &str) -> Option<&TrustedDevice> {
+        self.trusted_devices
+            .iter()
+            .find(|device| device.device_id == device_id)
+    }
+}
+
+// Settings shared by the app and the CLI, persisted to a json file in the data directory
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: Mutex<Settings>,
+    // why the saved settings couldn't be loaded, shown to the user
+    load_error: Option<String>,
+}
+
+impl SettingsStore {
+    // Load the settings from disk, defaults are used if there are none yet
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join("settings.json");
+
+        // settings that can't be parsed are set aside and reported, defaults are used instead
+        let (settings, load_error) = match storage::load_json(&path) {
+            Ok(settings) => (settings.unwrap_or_default(), None),
+            Err(e) => {
+                debug_log!("{}", e);
+                (Settings::default(), Some(e))
+            }
+        };
+
+        Self {
+            path,
+            settings: Mutex::new(settings),
+            load_error,
+        }
+    }
+
+    // Set when the saved settings were unreadable and defaults are used instead
+    pub fn load_error(&self) -> Option<&str> {
+        self.load_error.as_deref()
+    }
+
+    pub fn get(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    // Replace the settings and write them to disk
+    pub fn set(&self, settings: Settings) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(&settings)
+            .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+        storage::write_atomic(&self.path, json.as_bytes())
+            .map_err(|e| format!("Failed to save settings: {}", e))?;
+
+        debug_log!("⚙️ Saved settings to {}", self.path.display());
+        *self.settings.lock().unwrap() = settings;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mime_type_patterns() {
+        let cases = [
+            ("image/png", "image/png", true),
+            ("image/png", "IMAGE/PNG", true),
+            ("image/png", "image/jpeg", false),
+            ("image/*", "image/png", true),
+            ("image/*", "Image/PNG", true),
+            ("IMAGE/*", "image/png", true),
+            ("image/*", "video/mp4", false),
+            ("image/*", "imagex/png", false),
+            ("image/*", "image", false),
+            ("*/*", "application/pdf", true),
+            ("image", "image/png", false),
+            ("", "image/png", false),
+        ];
+
+        for (pattern, mime_type, expected) in cases {
+            assert_eq!(
+                mime_type_matches(pattern, mime_type),
+                expected,
+                "{} against {}",
+                pattern,
+                mime_type
+            );
+        }
+    }
+
+    #[test]
+    fn trusted_device_answers() {
+        use AutoAcceptPolicy::*;
+
+        let types =
+            |types: &[&str]| -> Vec<String> { types.iter().map(|t| t.to_string()).collect() };
+        let device = |policy, max_size, mime_types: &[&str]| TrustedDevice {
+            device_id: "device".into(),
+            policy,
+            max_size,
+            mime_types: types(mime_types),
+        };
+
+        let cases = [
+            // policy, max size, allowed types, transfer size, transfer types, answer
+            (Ask, None, &[][..], 10, &["image/png"][..], None),
+            (Reject, None, &[], 10, &["image/png"], Some(false)),
+            (
+                Reject,
+                Some(100),
+                &["image/*"],
+                10,
+                &["image/png"],
+                Some(false),
+            ),
+            (Accept, None, &[], 10, &["image/png"], Some(true)),
+            (Accept, None, &[], 0, &[], Some(true)),
+            // too big, the user decides
+            (Accept, Some(100), &[], 100, &["image/png"], Some(true)),
+            (Accept, Some(100), &[], 101, &["image/png"], None),
+            (
+                Accept,
+                None,
+                &["image/*"],
+                10,
+                &["image/png", "image/jpeg"],
+                Some(true),
+            ),
+            // every file has to be of an allowed type
+            (
+                Accept,
+                None,
+                &["image/*"],
+                10,
+                &["image/png", "video/mp4"],
+                None,
+            ),
+            (
+                Accept,
+                None,
+                &["image/*", "video/mp4"],
+                10,
+                &["image/png", "video/mp4"],
+                Some(true),
+            ),
+            (
+                Accept,
+                None,
+                &["application/pdf"],
+                10,
+                &["application/zip"],
+                None,
+            ),
+            (Accept, Some(100), &["image/*"], 1000, &["image/png"], None),
+        ];
+
+        for (policy, max_size, allowed, size, mime_types, expected) in cases {
+            assert_eq!(
+                device(policy, max_size, allowed).answer(size, &types(mime_types)),
+                expected,
+                "{:?} up to {:?} of {:?}, {} bytes of {:?}",
+                policy,
+                max_size,
+                allowed,
+                size,
+                mime_types
+            );
+        }
+    }
+
+    #[test]
+    fn destination_rules_match_device_and_type() {
+        let rule = |device_id: Option<&str>, mime_type: Option<&str>| DestinationRule {
+            device_id: device_id.map(String::from),
+            mime_type: mime_type.map(String::from),
+            dir: PathBuf::from("/rule"),
+        };
+
+        let cases = [
+            (rule(None, None), None, "image/png", true),
+            (rule(Some("a"), None), Some("a"), "image/png", true),
+            (rule(Some("a"), None), Some("b"), "image/png", false),
+            (rule(Some("a"), None), None, "image/png", false),
+            (rule(None, Some("image/*")), None, "image/png", true),
+            (rule(None, Some("image/*")), Some("a"), "text/plain", false),
+            (
+                rule(Some("a"), Some("image/*")),
+                Some("a"),
+                "image/png",
+                true,
+            ),
+            (
+                rule(Some("a"), Some("image/*")),
+                Some("b"),
+                "image/png",
+                false,
+            ),
+        ];
+
+        for (rule, device_id, mime_type, expected) in cases {
+            assert_eq!(
+                rule.matches(device_id, mime_type),
+                expected,
+                "{:?} against {:?} {}",
+                rule,
+                device_id,
+                mime_type
+            );
+        }
+    }
+}
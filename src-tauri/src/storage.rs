@@ -0,0 +1,70 @@
+use crate::debug_log;
+use serde::de::DeserializeOwned;
+use std::{io, path::Path};
+
+// Read a json file from the data directory, None if there is none yet. A file
+// that can't be parsed is kept next to it as "<name>.corrupt" rather than being
+// overwritten by the next save.
+pub(crate) fn load_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, String> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    serde_json::from_str(&json).map(Some).map_err(|e| {
+        let corrupt = path.with_extension("json.corrupt");
+        if let Err(e) = std::fs::rename(path, &corrupt) {
+            debug_log!("Failed to keep {}: {}", path.display(), e);
+        }
+        format!(
+            "Failed to parse {}, it was kept as {}: {}",
+            path.display(),
+            corrupt.display(),
+            e
+        )
+    })
+}
+
+// Replace a file through a temp file next to it, so a crash halfway leaves
+// either the old or the new contents
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::File::open(&tmp)?.sync_all()?;
+    std::fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn writes_and_loads() {
+        let dir = TempDir::new();
+        let path = dir.join("list.json");
+
+        assert_eq!(load_json::<Vec<u32>>(&path), Ok(None));
+        write_atomic(&path, b"[1,2]").unwrap();
+        write_atomic(&path, b"[3]").unwrap();
+        assert_eq!(load_json::<Vec<u32>>(&path), Ok(Some(vec![3])));
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn keeps_corrupt_files() {
+        let dir = TempDir::new();
+        let path = dir.join("list.json");
+        std::fs::write(&path, "[1,").unwrap();
+
+        assert!(load_json::<Vec<u32>>(&path).is_err());
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("list.json.corrupt")).unwrap(),
+            "[1,"
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use serde::Serialize;
 use sysinfo::System;
 
-use crate::mdns::APP_UUID;
+use crate::tranzit::Tranzit;
 
 #[derive(Debug, Serialize)]
 pub struct SysInfo {
@@ -10,11 +10,12 @@ pub struct SysInfo {
     pub app_id: String,
 }
 
-#[tauri::command]
-pub fn get_sys_info() -> SysInfo {
-    SysInfo {
-        os_type: System::long_os_version(),
-        hostname: System::host_name(),
-        app_id: APP_UUID.to_string(),
+impl Tranzit {
+    pub fn sys_info(&self) -> SysInfo {
+        SysInfo {
+            os_type: System::long_os_version(),
+            hostname: System::host_name(),
+            app_id: self.identity().device_id.clone(),
+        }
     }
 }
@@ -1,208 +1,306 @@
 use crate::debug_log;
-use once_cell::sync::Lazy;
+use crate::mdns::Peer;
+use crate::network;
+use crate::tranzit::Tranzit;
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
 use std::{collections::HashMap, net::SocketAddr, time::Duration};
-use tauri::async_runtime::spawn;
-use tauri::{AppHandle, Emitter};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
-use tokio::time::timeout;
+use tokio::time::{timeout_at, Instant};
 
 /// Represents a device tracked by heartbeat server.
 #[derive(Debug)]
-struct TrackedDevice {
+pub(crate) struct TrackedDevice {
+    // key of the peer, events about the device are sent with it
+    id: String,
+    // address the device answers heartbeats at
     ip: String,
+    // every address it was seen at, most recent first
+    addresses: Vec<String>,
     is_online: bool,
     stream: Option<TcpStream>, // Async TcpStream wrapped in Option
 }
 
-// Shared device map protected by async Mutex.
-type DiscoveredDevices = Lazy<Mutex<HashMap<String, TrackedDevice>>>;
+// Shared device map keyed on peer key, protected by async Mutex.
+pub(crate) type TrackedDevices = Mutex<HashMap<String, TrackedDevice>>;
 
-// Shared global device list.
-static DEVICES: DiscoveredDevices = Lazy::new(|| Mutex::new(HashMap::new()));
+#[derive(Serialize)]
+struct AddressChange<'a> {
+    id: &'a str,
+    ip: &'a str,
+}
 
-// TCP heartbeat server port.
-const PORT: u16 = 21112;
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+// other addresses of a device that stopped answering get less time each
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(3);
+// pinging a device and trying its addresses share this much time, a device that
+// didn't answer by then counts as offline until the next round
+const DEVICE_CHECK_TIMEOUT: Duration = Duration::from_secs(20);
+// devices checked at the same time
+const MAX_CONCURRENT_CHECKS: usize = 16;
 
-// add a device to global device list
-// front-end will call add device when it discovers any device
-#[tauri::command]
-pub async fn add_device(ip: String) {
-    let mut devices = DEVICES.lock().await;
-    devices.insert(
-        ip.clone(),
-        TrackedDevice {
-            ip,
-            is_online: false,
-            stream: None,
-        },
-    );
-}
+impl Tranzit {
+    // add a device to the tracked device list, or the addresses it was seen at to the
+    // device when it's tracked already
+    // front-end will call add device when it discovers any device
+    pub async fn add_device(&self, peer: &Peer) {
+        let mut devices = self.inner.devices.lock().await;
+        let recent = std::iter::once(&peer.ip).chain(&peer.addresses);
 
-// remove a device from the global device list
-#[tauri::command]
-pub async fn remove_device(ip: String) {
-    let mut devices = DEVICES.lock().await;
-    devices.remove(&ip);
-}
+        match devices.get_mut(&peer.key()) {
+            Some(dev) => {
+                dev.addresses = network::merge_addresses(recent, &dev.addresses);
+                // one that isn't answering is tried where it was just seen first
+                if !dev.is_online {
+                    dev.ip = peer.ip.clone();
+                }
+            }
+            None => {
+                devices.insert(
+                    peer.key(),
+                    TrackedDevice {
+                        id: peer.key(),
+                        ip: peer.ip.clone(),
+                        addresses: network::merge_addresses(recent, &[]),
+                        is_online: false,
+                        stream: None,
+                    },
+                );
+            }
+        }
+    }
 
-#[tauri::command]
-pub async fn start_heartbeat(app: AppHandle) {
-    debug_log!("💓 Started sending heartbeats to discovered devices.");
+    // remove a device from the tracked device list
+    pub async fn remove_device(&self, id: &str) {
+        let mut devices = self.inner.devices.lock().await;
+        devices.remove(id);
+    }
 
-    let app_instance_clone = app.clone();
+    pub fn start_heartbeat(&self) {
+        debug_log!("💓 Started sending heartbeats to discovered devices.");
 
-    spawn(async move {
-        loop {
-            let ips: Vec<String> = {
-                let devices = DEVICES.lock().await;
-                devices.keys().cloned().collect()
-            };
-
-            for ip in ips {
-                let stream = {
-                    let mut devices = DEVICES.lock().await;
-                    match devices.get_mut(&ip) {
-                        Some(dev) => dev.stream.take(),
-                        None => continue,
-                    }
+        let tranzit = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let ids: Vec<String> = {
+                    let devices = tranzit.inner.devices.lock().await;
+                    devices.keys().cloned().collect()
                 };
 
-                let mut stream = match stream {
-                    Some(s) => s,
-                    None => {
-                        let addr_str = format!("{}:{}", ip, PORT);
-                        let addrs: Vec<SocketAddr> = match lookup_host(addr_str.as_str()).await {
-                            Ok(iter) => iter.collect(),
-                            Err(e) => {
-                                debug_log!("Could not resolve {}: {}", ip, e);
-                                mark_device_offline(&app_instance_clone, &ip).await;
-                                continue;
-                            }
-                        };
-
-                        let socket_addr = match addrs.first() {
-                            Some(a) => *a,
-                            None => {
-                                debug_log!("No valid socket address for {}", ip);
-                                mark_device_offline(&app_instance_clone, &ip).await;
-                                continue;
-                            }
-                        };
-
-                        match timeout(HEARTBEAT_TIMEOUT, TcpStream::connect(socket_addr)).await {
-                            Ok(Ok(s)) => s,
-                            Ok(Err(e)) => {
-                                debug_log!("Connection error to {}: {}", ip, e);
-                                mark_device_offline(&app_instance_clone, &ip).await;
-                                continue;
-                            }
-                            Err(_) => {
-                                debug_log!("Connection to {} timed out", ip);
-                                mark_device_offline(&app_instance_clone, &ip).await;
-                                continue;
-                            }
+                // devices are checked side by side, so ones that went away don't hold
+                // up the heartbeats of the others
+                stream::iter(ids)
+                    .for_each_concurrent(MAX_CONCURRENT_CHECKS, |id| {
+                        let tranzit = &tranzit;
+                        async move {
+                            let deadline = Instant::now() + DEVICE_CHECK_TIMEOUT;
+                            tranzit.check_device(&id, deadline).await;
                         }
-                    }
-                };
-
-                let mut stream_ok = true;
-                let mut saw_pong = false;
+                    })
+                    .await;
 
-                if let Err(e) = stream.write_all(b"ping\n").await {
-                    debug_log!("Write failed to {}: {}", ip, e);
-                    stream_ok = false;
+                tokio::select! {
+                    _ = tranzit.inner.shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => {}
                 }
+            }
+        });
+    }
 
-                if stream_ok {
-                    let mut reader = BufReader::new(&mut stream);
-                    let mut response = String::new();
+    // Ping a device over its heartbeat connection and mark it online or offline,
+    // giving up on it at the deadline
+    async fn check_device(&self, id: &str, deadline: Instant) {
+        let mut stream = {
+            let mut devices = self.inner.devices.lock().await;
+            match devices.get_mut(id) {
+                Some(dev) => dev.stream.take(),
+                None => return,
+            }
+        };
 
-                    match timeout(HEARTBEAT_TIMEOUT, reader.read_line(&mut response)).await {
-                        Ok(Ok(0)) => {
-                            debug_log!("Connection closed by {}", ip);
-                            stream_ok = false;
-                        }
-                        Ok(Ok(_)) => {
-                            if response.trim().starts_with("pong") {
-                                saw_pong = true;
-                            } else {
-                                debug_log!("Unexpected response from {}: {}", ip, response.trim());
-                                stream_ok = false;
-                            }
-                        }
-                        Ok(Err(e)) => {
-                            debug_log!("Read error from {}: {}", ip, e);
-                            stream_ok = false;
-                        }
-                        Err(_) => {
-                            debug_log!("Read timed out from {}", ip);
-                            stream_ok = false;
-                        }
-                    }
+        let mut alive = match &mut stream {
+            Some(s) => ping(s, id, deadline).await,
+            None => false,
+        };
+        // a broken connection is opened again before the device counts as
+        // offline, possibly at another of its addresses
+        if !alive {
+            stream = self.connect_device(id, deadline).await;
+            alive = match &mut stream {
+                Some(s) => ping(s, id, deadline).await,
+                None => false,
+            };
+        }
+
+        if !alive {
+            self.mark_device_offline(id).await;
+            return;
+        }
+
+        let mut devices = self.inner.devices.lock().await;
+        if let Some(dev) = devices.get_mut(id) {
+            dev.stream = stream;
+            if !dev.is_online {
+                dev.is_online = true;
+                self.emit("device-online", &dev.id);
+            }
+        }
+    }
+
+    // mark the device as offline in the list of devices and emit event to front-end so it can
+    // remove it from listed devices
+    async fn mark_device_offline(&self, id: &str) {
+        let mut devices = self.inner.devices.lock().await;
+        if let Some(dev) = devices.get_mut(id) {
+            dev.stream = None;
+            if dev.is_online {
+                dev.is_online = false;
+                self.emit("device-offline", &dev.id);
+            }
+        }
+    }
+
+    // Connect to the address a device answered at last, or the first of its other
+    // addresses that answers. A device that moved emits its new address rather than
+    // going offline and coming back. Nothing is tried past the deadline.
+    async fn connect_device(&self, id: &str, deadline: Instant) -> Option<TcpStream> {
+        let (current, addresses) = {
+            let devices = self.inner.devices.lock().await;
+            let dev = devices.get(id)?;
+            (dev.ip.clone(), dev.addresses.clone())
+        };
+        let fallbacks = addresses.into_iter().filter(|ip| *ip != current);
+        let port = self.config().heartbeat_port;
+
+        for (i, ip) in std::iter::once(current.clone())
+            .chain(fallbacks)
+            .enumerate()
+        {
+            let addr = match network::socket_addr(&ip, port) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug_log!("Could not resolve {}: {}", ip, e);
+                    continue;
                 }
+            };
 
-                {
-                    let mut devices = DEVICES.lock().await;
-                    if let Some(dev) = devices.get_mut(&ip) {
-                        if stream_ok {
-                            dev.stream = Some(stream);
-                            if !dev.is_online && saw_pong {
-                                dev.is_online = true;
-                                let _ = app_instance_clone.emit("device-online", dev.ip.clone());
-                            }
-                        } else {
-                            dev.stream = None;
-                            if dev.is_online {
-                                dev.is_online = false;
-                                let _ = app_instance_clone.emit("device-offline", dev.ip.clone());
-                            }
-                        }
-                    }
+            let wait = if i == 0 {
+                HEARTBEAT_TIMEOUT
+            } else {
+                FALLBACK_TIMEOUT
+            };
+            let wait = deadline.min(Instant::now() + wait);
+            let stream = match timeout_at(wait, TcpStream::connect(addr)).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => {
+                    debug_log!("Connection error to {} at {}: {}", id, ip, e);
+                    continue;
                 }
+                Err(_) => {
+                    debug_log!("Connection to {} at {} timed out", id, ip);
+                    continue;
+                }
+            };
+
+            if ip != current {
+                let mut devices = self.inner.devices.lock().await;
+                let dev = devices.get_mut(id)?;
+                dev.ip = ip.clone();
+                debug_log!("🔀 {} moved to {}", id, ip);
+                self.emit("device-address-changed", AddressChange { id, ip: &ip });
             }
 
-            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            return Some(stream);
         }
-    });
-}
 
-// mark the device as offline in the global list of devices and emit event to front-end so it can
-// remove it from listed devices
-async fn mark_device_offline(app: &AppHandle, ip: &str) {
-    let mut devices = DEVICES.lock().await;
-    if let Some(dev) = devices.get_mut(ip) {
-        dev.stream = None;
-        if dev.is_online {
-            dev.is_online = false;
-            let _ = app.emit("device-offline", dev.ip.clone());
+        None
+    }
+
+    // start the tcp heartbeat responder, it will reply with "pong" for every "ping" it receives
+    // ping/pong messages will be terminated by \n
+    pub async fn start_heartbeat_responder(&self) -> Result<(), String> {
+        let port = self.config().heartbeat_port;
+        // listen on both IPv6 and IPv4, binding the second fails where the first takes both
+        let mut listeners = Vec::new();
+        let mut bind_error = None;
+        for ip in network::UNSPECIFIED {
+            // both on the same port, also when the first one got a random one
+            let port = match listeners.first().map(TcpListener::local_addr) {
+                Some(Ok(addr)) => addr.port(),
+                _ => port,
+            };
+            match TcpListener::bind(SocketAddr::new(ip, port)).await {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => bind_error = Some(e),
+            }
+        }
+        if listeners.is_empty() {
+            let e = bind_error.map(|e| e.to_string()).unwrap_or_default();
+            return Err(format!("Failed to bind TCP listener: {}", e));
+        }
+
+        debug_log!("💓 Heartbeat responder listening on port {}", port);
+
+        for listener in listeners {
+            let shutdown = self.inner.shutdown.clone();
+            tokio::spawn(async move {
+                loop {
+                    let accepted = tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        accepted = listener.accept() => accepted,
+                    };
+
+                    match accepted {
+                        Ok((stream, peer)) => {
+                            debug_log!("Incoming connection from {}", peer);
+                            tokio::spawn(handle_connection(stream));
+                        }
+                        Err(e) => debug_log!("Error accepting connection: {}", e),
+                    }
+                }
+            });
         }
+
+        Ok(())
     }
 }
 
-// start the tcp heartbeat responder, it will reply with "pong" for every "ping" it receives
-// ping/pong messages will be terminated by \n
-pub fn start_heartbeat_responder() {
-    spawn(async move {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", PORT))
-            .await
-            .expect("Failed to bind TCP listener");
-
-        debug_log!("💓 Heartbeat responder listening on port {}", PORT);
-
-        loop {
-            match listener.accept().await {
-                Ok((stream, peer)) => {
-                    debug_log!("Incoming connection from {}", peer);
-                    spawn(handle_connection(stream));
-                }
-                Err(e) => debug_log!("Error accepting connection: {}", e),
+// Send a ping over a heartbeat connection, true when the device answered it in time
+async fn ping(stream: &mut TcpStream, id: &str, deadline: Instant) -> bool {
+    if let Err(e) = stream.write_all(b"ping\n").await {
+        debug_log!("Write failed to {}: {}", id, e);
+        return false;
+    }
+
+    let mut reader = BufReader::new(stream);
+    let mut response = String::new();
+
+    let deadline = deadline.min(Instant::now() + HEARTBEAT_TIMEOUT);
+    match timeout_at(deadline, reader.read_line(&mut response)).await {
+        Ok(Ok(0)) => {
+            debug_log!("Connection closed by {}", id);
+            false
+        }
+        Ok(Ok(_)) => {
+            if response.trim().starts_with("pong") {
+                true
+            } else {
+                debug_log!("Unexpected response from {}: {}", id, response.trim());
+                false
             }
         }
-    });
+        Ok(Err(e)) => {
+            debug_log!("Read error from {}: {}", id, e);
+            false
+        }
+        Err(_) => {
+            debug_log!("Read timed out from {}", id);
+            false
+        }
+    }
 }
 
 async fn handle_connection(mut stream: TcpStream) {
@@ -239,3 +337,53 @@ async fn handle_connection(mut stream: TcpStream) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{instance_with, TempDir};
+
+    #[tokio::test]
+    async fn device_is_found_at_another_address() {
+        // only answers on 127.0.0.1, connecting to 127.0.0.2 is refused
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream));
+            }
+        });
+
+        let dir = TempDir::new();
+        let (tranzit, mut events) = instance_with(&dir.0, |config| config.heartbeat_port = port);
+        let peer = Peer {
+            name: "device-1._localdrop._tcp.local.".into(),
+            ip: "127.0.0.2".into(),
+            addresses: vec!["127.0.0.2".into(), "127.0.0.1".into()],
+            port: 21212,
+            hostname: "laptop".into(),
+            service_type: "_localdrop._tcp.local.".into(),
+            os: "linux".into(),
+            id: "device-1".into(),
+        };
+        tranzit.add_device(&peer).await;
+
+        let started = Instant::now();
+        tranzit
+            .check_device("device-1", started + DEVICE_CHECK_TIMEOUT)
+            .await;
+        assert!(started.elapsed() < DEVICE_CHECK_TIMEOUT);
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.name, "device-address-changed");
+        assert_eq!(event.payload["ip"], "127.0.0.1");
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.name, "device-online");
+        assert_eq!(event.payload, "device-1");
+
+        let devices = tranzit.inner.devices.lock().await;
+        let device = &devices["device-1"];
+        assert!(device.is_online);
+        assert_eq!(device.ip, "127.0.0.1");
+    }
+}
@@ -0,0 +1,83 @@
+// Helpers shared by the unit tests
+use crate::events::{ChannelSink, Event};
+use crate::tranzit::{Tranzit, TranzitConfig};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedReceiver;
+use uuid::Uuid;
+
+// Scratch directory of a test, removed when dropped so a failing test doesn't leave it behind
+pub(crate) struct TempDir(pub(crate) PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("tranzit-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub(crate) fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// An instance keeping everything in the given directory and listening on any free
+// port, along with the events it emits
+pub(crate) fn instance_in(dir: &Path) -> (Tranzit, UnboundedReceiver<Event>) {
+    instance_with(dir, |_| {})
+}
+
+// Same as instance_in, with the config changed before the instance is set up
+pub(crate) fn instance_with(
+    dir: &Path,
+    configure: impl FnOnce(&mut TranzitConfig),
+) -> (Tranzit, UnboundedReceiver<Event>) {
+    let mut config = TranzitConfig {
+        download_dir: Some(dir.join("received")),
+        partials_dir: dir.join("partial"),
+        https_port: 0,
+        heartbeat_port: 0,
+        ..TranzitConfig::new(dir.join("data"))
+    };
+    configure(&mut config);
+    std::fs::create_dir_all(dir.join("received")).unwrap();
+
+    let (sink, events) = ChannelSink::new();
+    (Tranzit::new(config, Arc::new(sink)).unwrap(), events)
+}
+
+// An instance in a scratch directory of its own
+pub(crate) fn instance() -> (Tranzit, UnboundedReceiver<Event>, TempDir) {
+    let dir = TempDir::new();
+    let (tranzit, events) = instance_in(&dir.0);
+    (tranzit, events, dir)
+}
+
+// Answer every request sent as the given event, returns all events seen so far
+pub(crate) fn answer_requests(
+    tranzit: &Tranzit,
+    mut events: UnboundedReceiver<Event>,
+    request: &'static str,
+    answer: &'static str,
+) -> Arc<Mutex<Vec<Event>>> {
+    let seen: Arc<Mutex<Vec<Event>>> = Default::default();
+    let tranzit = tranzit.clone();
+    let collected = seen.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if event.name == request {
+                let id = event.payload["id"].as_str().unwrap().to_string();
+                tranzit.respond_to_request(&id, answer.to_string(), None, None);
+            }
+            collected.lock().unwrap().push(event);
+        }
+    });
+
+    seen
+}
@@ -0,0 +1,295 @@
+use crate::settings::Settings;
+use crate::tranzit::Tranzit;
+use bytes::Buf;
+use futures_util::stream::{self, Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// After being idle a limiter lets this much worth of bytes through at once
+const BURST: Duration = Duration::from_millis(250);
+
+// Token bucket holding every stream that shares it to a number of bytes per second
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // bytes per second, no limit when not set
+    rate: Option<u64>,
+    // goes below zero while streams wait for the bytes they already took
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    // Change the limit, streams going through the limiter slow down or speed up right away
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate != rate {
+            bucket.rate = rate;
+            bucket.tokens = 0.0;
+            bucket.refilled = Instant::now();
+        }
+    }
+
+    // Take bytes out of the bucket, returns how long to wait before sending them
+    fn reserve_at(&self, bytes: u64, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let Some(rate) = bucket.rate.filter(|&rate| rate > 0) else {
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate * BURST.as_secs_f64());
+        bucket.refilled = now;
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+// All limits a stream is held to, e.g. the global one, the one of the peer and
+// the one of the transfer
+#[derive(Clone)]
+pub struct Throttle(Vec<Arc<RateLimiter>>);
+
+impl Throttle {
+    fn reserve(&self, bytes: u64) -> Duration {
+        self.reserve_at(bytes, Instant::now())
+    }
+
+    fn reserve_at(&self, bytes: u64, now: Instant) -> Duration {
+        self.0
+            .iter()
+            .map(|limiter| limiter.reserve_at(bytes, now))
+            .max()
+            .unwrap_or_default()
+    }
+
+    // Hold a stream to the limits, every piece of it waits until all of them let it through
+    pub fn limit<S, B, E>(self, stream: S) -> impl Stream<Item = Result<B, E>> + Unpin
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: Buf,
+    {
+        Box::pin(stream::unfold(
+            (stream, self),
+            |(mut stream, throttle)| async move {
+                let item = stream.next().await?;
+                if let Ok(data) = &item {
+                    let wait = throttle.reserve(data.remaining() as u64);
+                    if !wait.is_zero() {
+                        tokio::time::sleep(wait).await;
+                    }
+                }
+                Some((item, (stream, throttle)))
+            },
+        ))
+    }
+}
+
+// Upload and download limiters of a single device
+#[derive(Clone)]
+struct PeerLimiters {
+    upload: Arc<RateLimiter>,
+    download: Arc<RateLimiter>,
+}
+
+// Limits from the settings, shared by every transfer and kept up to date while they run
+pub struct RateLimits {
+    upload: Arc<RateLimiter>,
+    download: Arc<RateLimiter>,
+    // limiters per device id, there are some for every peer so a limit
+    // added for it later applies to running transfers too
+    peers: Mutex<HashMap<String, PeerLimiters>>,
+}
+
+impl RateLimits {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            upload: Arc::new(RateLimiter::new(settings.upload_rate_limit)),
+            download: Arc::new(RateLimiter::new(settings.download_rate_limit)),
+            peers: Default::default(),
+        }
+    }
+
+    // Pick up changed settings
+    pub fn apply(&self, settings: &Settings) {
+        self.upload.set_rate(settings.upload_rate_limit);
+        self.download.set_rate(settings.download_rate_limit);
+
+        for (device_id, limiters) in self.peers.lock().unwrap().iter() {
+            let limit = settings.peer_rate_limit(device_id);
+            limiters
+                .upload
+                .set_rate(limit.and_then(|limit| limit.upload_rate_limit));
+            limiters
+                .download
+                .set_rate(limit.and_then(|limit| limit.download_rate_limit));
+        }
+    }
+
+    fn peer(&self, device_id: &str, settings: &Settings) -> PeerLimiters {
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_insert_with(|| {
+                let limit = settings.peer_rate_limit(device_id);
+                PeerLimiters {
+                    upload: Arc::new(RateLimiter::new(
+                        limit.and_then(|limit| limit.upload_rate_limit),
+                    )),
+                    download: Arc::new(RateLimiter::new(
+                        limit.and_then(|limit| limit.download_rate_limit),
+                    )),
+                }
+            })
+            .clone()
+    }
+}
+
+impl Tranzit {
+    // Limits for the bytes we send in a transfer
+    pub(crate) fn upload_throttle(&self, transfer_id: &str, peer_id: Option<&str>) -> Throttle {
+        self.throttle(transfer_id, peer_id, true)
+    }
+
+    // Limits for the bytes we receive in a transfer
+    pub(crate) fn download_throttle(&self, transfer_id: &str, peer_id: Option<&str>) -> Throttle {
+        self.throttle(transfer_id, peer_id, false)
+    }
+
+    fn throttle(&self, transfer_id: &str, peer_id: Option<&str>, upload: bool) -> Throttle {
+        let rate_limits = &self.inner.rate_limits;
+        let mut limiters = vec![if upload {
+            rate_limits.upload.clone()
+        } else {
+            rate_limits.download.clone()
+        }];
+
+        if let Some(peer_id) = peer_id {
+            let peer = rate_limits.peer(peer_id, &self.settings().get());
+            limiters.push(if upload { peer.upload } else { peer.download });
+        }
+        limiters.push(self.transfer_rate_limiter(transfer_id));
+
+        Throttle(limiters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A limiter whose bucket was last refilled at a known instant
+    fn limiter(rate: Option<u64>, start: Instant) -> RateLimiter {
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                refilled: start,
+            }),
+        }
+    }
+
+    fn assert_wait(wait: Duration, millis: u64) {
+        let expected = Duration::from_millis(millis);
+        let off = wait.abs_diff(expected);
+        assert!(
+            off < Duration::from_micros(1),
+            "waited {:?} instead of {:?}",
+            wait,
+            expected
+        );
+    }
+
+    #[test]
+    fn no_limit_never_waits() {
+        let start = Instant::now();
+        for rate in [None, Some(0)] {
+            let limiter = limiter(rate, start);
+            assert_wait(limiter.reserve_at(u64::MAX, start), 0);
+        }
+    }
+
+    #[test]
+    fn bytes_wait_for_the_rate() {
+        let start = Instant::now();
+        let limiter = limiter(Some(1000), start);
+
+        assert_wait(limiter.reserve_at(500, start), 500);
+        // the next bytes queue up behind the ones taken already
+        assert_wait(limiter.reserve_at(500, start), 1000);
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let start = Instant::now();
+        let limiter = limiter(Some(1000), start);
+
+        assert_wait(limiter.reserve_at(1000, start), 1000);
+        // the debt is paid off after a second, the next bytes wait for themselves only
+        let later = start + Duration::from_secs(1);
+        assert_wait(limiter.reserve_at(100, later), 100);
+        assert_wait(
+            limiter.reserve_at(100, later + Duration::from_millis(200)),
+            0,
+        );
+    }
+
+    #[test]
+    fn idle_limiter_bursts_a_little() {
+        let start = Instant::now();
+        let limiter = limiter(Some(1000), start);
+
+        // idle for a long time, but only the burst worth of bytes goes through at once
+        let later = start + Duration::from_secs(60);
+        assert_wait(limiter.reserve_at(250, later), 0);
+        assert_wait(limiter.reserve_at(250, later), 250);
+    }
+
+    #[test]
+    fn changed_rate_starts_over() {
+        let start = Instant::now();
+        let limiter = limiter(Some(1000), start);
+        assert_wait(limiter.reserve_at(5000, start), 5000);
+
+        limiter.set_rate(Some(2000));
+        let now = limiter.bucket.lock().unwrap().refilled;
+        assert_wait(limiter.reserve_at(1000, now), 500);
+
+        // unchanged rates leave the bucket alone
+        limiter.set_rate(Some(2000));
+        assert_wait(limiter.reserve_at(1000, now), 1000);
+    }
+
+    #[test]
+    fn throttle_waits_for_the_slowest_limit() {
+        let start = Instant::now();
+        let throttle = Throttle(vec![
+            Arc::new(limiter(Some(1000), start)),
+            Arc::new(limiter(None, start)),
+            Arc::new(limiter(Some(4000), start)),
+        ]);
+
+        assert_wait(throttle.reserve_at(1000, start), 1000);
+        assert_wait(Throttle(Vec::new()).reserve_at(1000, start), 0);
+    }
+}
@@ -0,0 +1,290 @@
+use crate::debug_log;
+use crate::http_requests::{TransferResult, TransferStatus};
+use crate::http_server;
+use crate::progress::{Direction, TransferProgress};
+use crate::throttle::RateLimiter;
+use crate::tranzit::Tranzit;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+// Device on the other end of a transfer we are sending
+#[derive(Debug, Clone)]
+pub(crate) struct TransferPeer {
+    pub(crate) ip: String,
+    pub(crate) port: u16,
+    pub(crate) peer_id: Option<String>,
+    // token of the transfer, the receiver only accepts a cancel with it
+    pub(crate) token: String,
+}
+
+pub(crate) struct ActiveTransfer {
+    token: CancellationToken,
+    // only known when sending, the peer is told when we cancel
+    peer: Option<TransferPeer>,
+    // no more queued files are started while a transfer we are sending is paused
+    paused: watch::Sender<bool>,
+    // speed limit of this transfer alone, none unless the user sets one
+    rate_limit: Arc<RateLimiter>,
+    // set up by whichever side starts tracking the transfer first
+    progress: Option<Arc<TransferProgress>>,
+}
+
+// Transfers being sent or received by this instance, keyed on transfer id.
+// Cancelled transfers are kept around for a while so later uploads of them are refused.
+pub(crate) type ActiveTransfers = Mutex<HashMap<String, ActiveTransfer>>;
+
+impl Tranzit {
+    fn new_transfer(&self) -> ActiveTransfer {
+        ActiveTransfer {
+            token: self.inner.shutdown.child_token(),
+            peer: None,
+            paused: watch::channel(false).0,
+            rate_limit: Arc::new(RateLimiter::new(None)),
+            progress: None,
+        }
+    }
+
+    // Register a transfer we are about to send, the returned token is cancelled
+    // when either side cancels it and the receiver tells whether it is paused
+    pub(crate) fn start_sending(
+        &self,
+        transfer_id: &str,
+        peer: TransferPeer,
+    ) -> (CancellationToken, watch::Receiver<bool>) {
+        let mut transfers = self.inner.transfers.lock().unwrap();
+        let transfer = transfers
+            .entry(transfer_id.to_string())
+            .or_insert_with(|| self.new_transfer());
+        transfer.peer = Some(peer);
+
+        (transfer.token.clone(), transfer.paused.subscribe())
+    }
+
+    // Token of a transfer we are receiving, registered on its first upload
+    pub(crate) fn receiving_token(&self, transfer_id: &str) -> CancellationToken {
+        self.inner
+            .transfers
+            .lock()
+            .unwrap()
+            .entry(transfer_id.to_string())
+            .or_insert_with(|| self.new_transfer())
+            .token
+            .clone()
+    }
+
+    // Limiter of a transfer, its uploads share it
+    pub(crate) fn transfer_rate_limiter(&self, transfer_id: &str) -> Arc<RateLimiter> {
+        self.inner
+            .transfers
+            .lock()
+            .unwrap()
+            .entry(transfer_id.to_string())
+            .or_insert_with(|| self.new_transfer())
+            .rate_limit
+            .clone()
+    }
+
+    // Progress of a transfer, files are added to it before they are sent or received
+    pub(crate) fn transfer_progress(
+        &self,
+        transfer_id: &str,
+        direction: Direction,
+    ) -> Arc<TransferProgress> {
+        self.inner
+            .transfers
+            .lock()
+            .unwrap()
+            .entry(transfer_id.to_string())
+            .or_insert_with(|| self.new_transfer())
+            .progress
+            .get_or_insert_with(|| Arc::new(TransferProgress::new(transfer_id, direction)))
+            .clone()
+    }
+
+    pub(crate) fn is_cancelled(&self, transfer_id: &str) -> bool {
+        self.inner
+            .transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .is_some_and(|t| t.token.is_cancelled())
+    }
+
+    // Forget a transfer once all of its files are sent or received
+    pub(crate) fn finish_transfer(&self, transfer_id: &str) {
+        let mut transfers = self.inner.transfers.lock().unwrap();
+        if transfers
+            .get(transfer_id)
+            .is_some_and(|t| !t.token.is_cancelled())
+        {
+            transfers.remove(transfer_id);
+        }
+    }
+
+    // Cancel a transfer without telling the peer, returns the peer if we were sending it
+    pub(crate) async fn cancel_locally(&self, transfer_id: &str) -> Option<TransferPeer> {
+        let peer = {
+            let mut transfers = self.inner.transfers.lock().unwrap();
+            let transfer = transfers
+                .entry(transfer_id.to_string())
+                .or_insert_with(|| self.new_transfer());
+            transfer.token.cancel();
+            transfer.peer.take()
+        };
+
+        debug_log!("🛑 Transfer {} cancelled", transfer_id);
+        self.record_cancelled(transfer_id);
+        self.forget_transfer_later(transfer_id);
+        self.remove_partials(transfer_id).await;
+        self.emit("transfer-cancelled", json!({ "transfer_id": transfer_id }));
+
+        peer
+    }
+
+    // The receiver cancelled a transfer we are sending, it's cleaned up like one
+    // cancelled here without telling the receiver back. Whoever cancels first takes
+    // the peer, so the other files of the transfer failing the same way do nothing.
+    pub(crate) fn cancelled_by_receiver(&self, transfer_id: &str) {
+        let first = match self.inner.transfers.lock().unwrap().get_mut(transfer_id) {
+            Some(transfer) => {
+                transfer.token.cancel();
+                transfer.peer.take().is_some()
+            }
+            None => false,
+        };
+        if !first {
+            return;
+        }
+
+        debug_log!("🛑 Transfer {} cancelled by the receiver", transfer_id);
+        self.record_cancelled(transfer_id);
+        self.forget_transfer_later(transfer_id);
+        self.emit("transfer-cancelled", json!({ "transfer_id": transfer_id }));
+    }
+
+    // Stop sending or receiving a transfer and drop whatever was received of it.
+    // Works on both ends, the sender lets the receiver know right away while the
+    // receiver refuses any further uploads, which stops the sender.
+    pub async fn cancel_transfer(&self, transfer_id: &str) -> Result<(), String> {
+        if !http_server::is_valid_id(transfer_id) {
+            return Err(format!("Invalid transfer id {}", transfer_id));
+        }
+
+        if let Some(peer) = self.cancel_locally(transfer_id).await {
+            if let Err(e) = self.notify_cancelled(&peer, transfer_id).await {
+                debug_log!(
+                    "Failed to tell {} about the cancelled transfer: {}",
+                    peer.ip,
+                    e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // Stop starting queued files of a transfer we are sending, the ones already
+    // being sent go on
+    pub fn pause_transfer(&self, transfer_id: &str) -> Result<(), String> {
+        self.set_paused(transfer_id, true)
+    }
+
+    // Start sending the queued files of a paused transfer again
+    pub fn resume_transfer(&self, transfer_id: &str) -> Result<(), String> {
+        self.set_paused(transfer_id, false)
+    }
+
+    // Limit the bytes per second of a transfer we send or receive, on top of the
+    // limits from the settings. None lifts the limit.
+    pub fn set_transfer_rate_limit(
+        &self,
+        transfer_id: &str,
+        limit: Option<u64>,
+    ) -> Result<(), String> {
+        self.inner
+            .transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .filter(|t| !t.token.is_cancelled())
+            .ok_or_else(|| format!("Unknown transfer {}", transfer_id))?
+            .rate_limit
+            .set_rate(limit);
+
+        match limit {
+            Some(limit) => debug_log!("🐢 Transfer {} limited to {} B/s", transfer_id, limit),
+            None => debug_log!("🐇 Transfer {} no longer limited", transfer_id),
+        }
+
+        Ok(())
+    }
+
+    fn set_paused(&self, transfer_id: &str, paused: bool) -> Result<(), String> {
+        {
+            let transfers = self.inner.transfers.lock().unwrap();
+            let transfer = transfers
+                .get(transfer_id)
+                .filter(|t| t.peer.is_some() && !t.token.is_cancelled())
+                .ok_or_else(|| format!("Not sending transfer {}", transfer_id))?;
+            transfer.paused.send_replace(paused);
+        }
+
+        debug_log!(
+            "{} Transfer {} {}",
+            if paused { "⏸️" } else { "▶️" },
+            transfer_id,
+            if paused { "paused" } else { "resumed" }
+        );
+        self.emit(
+            "transfer-status",
+            TransferResult {
+                transfer_id: transfer_id.to_string(),
+                status: if paused {
+                    TransferStatus::Paused
+                } else {
+                    TransferStatus::Sending
+                },
+                files: Vec::new(),
+            },
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::instance;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn transfer_cancelled_by_receiver_is_forgotten() {
+        let (tranzit, mut events, _dir) = instance();
+        let transfer_id = uuid::Uuid::new_v4().to_string();
+        let peer = TransferPeer {
+            ip: "192.168.1.20".into(),
+            port: 21212,
+            peer_id: None,
+            token: "token".into(),
+        };
+
+        let (cancelled, _paused) = tranzit.start_sending(&transfer_id, peer);
+        // every file still being sent hears about it
+        tranzit.cancelled_by_receiver(&transfer_id);
+        tranzit.cancelled_by_receiver(&transfer_id);
+        assert!(cancelled.is_cancelled());
+        assert_eq!(events.try_recv().unwrap().name, "transfer-cancelled");
+        assert!(events.try_recv().is_err());
+
+        tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+        assert!(tranzit.inner.transfers.lock().unwrap().is_empty());
+
+        tranzit.shutdown();
+    }
+}
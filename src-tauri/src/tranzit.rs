@@ -0,0 +1,161 @@
+use crate::debug_log;
+use crate::events::EventSink;
+use crate::history::TransferHistory;
+use crate::http_server::{ApprovedTransfers, PairingSessions, PendingRequests};
+use crate::identity::{self, Identity};
+use crate::mdns::MdnsState;
+use crate::pairing::PairedDevices;
+use crate::settings::SettingsStore;
+use crate::tcp_heartbeat::TrackedDevices;
+use crate::throttle::RateLimits;
+use crate::transfers::ActiveTransfers;
+use serde::Serialize;
+use std::env;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_HTTPS_PORT: u16 = 21212;
+pub const DEFAULT_HEARTBEAT_PORT: u16 = 21112;
+
+// Where an instance keeps its files and which ports it listens on
+#[derive(Debug, Clone)]
+pub struct TranzitConfig {
+    // identity and paired devices are stored here
+    pub data_dir: PathBuf,
+    // received files are saved here instead of the directory from the settings,
+    // destination rules and a directory picked when accepting still apply
+    pub download_dir: Option<PathBuf>,
+    // partially received files are kept here until they are complete
+    pub partials_dir: PathBuf,
+    pub https_port: u16,
+    // heartbeats are sent to the same port on peers
+    pub heartbeat_port: u16,
+}
+
+impl TranzitConfig {
+    pub fn new(data_dir: PathBuf) -> Self {
+        let https_port = match env::var("HTTPS_PORT") {
+            Ok(val) => val.parse().unwrap_or(DEFAULT_HTTPS_PORT),
+            Err(_) => {
+                debug_log!("HTTPS_PORT environment variable not set, falling back to 21212");
+                DEFAULT_HTTPS_PORT
+            }
+        };
+
+        Self {
+            data_dir,
+            download_dir: None,
+            partials_dir: dirs_next::cache_dir()
+                .unwrap_or_else(env::temp_dir)
+                .join("tranzit")
+                .join("partial"),
+            https_port,
+            heartbeat_port: DEFAULT_HEARTBEAT_PORT,
+        }
+    }
+}
+
+// One tranzit instance: its identity, the devices it tracks and the requests
+// waiting for an answer. Cheap to clone, all clones share the same state.
+// Events go to the given sink, the GUI forwards them to the webview.
+#[derive(Clone)]
+pub struct Tranzit {
+    pub(crate) inner: Arc<Inner>,
+}
+
+pub(crate) struct Inner {
+    pub(crate) config: TranzitConfig,
+    pub(crate) identity: Identity,
+    pub(crate) events: Arc<dyn EventSink>,
+    pub(crate) paired_devices: PairedDevices,
+    pub(crate) settings: SettingsStore,
+    pub(crate) rate_limits: RateLimits,
+    pub(crate) history: TransferHistory,
+    pub(crate) devices: TrackedDevices,
+    pub(crate) pending_requests: PendingRequests,
+    pub(crate) pairings: PairingSessions,
+    pub(crate) approved_transfers: ApprovedTransfers,
+    pub(crate) transfers: ActiveTransfers,
+    pub(crate) mdns: MdnsState,
+    // port the https server listens on, the configured one until it's started
+    pub(crate) https_port: AtomicU16,
+    // cancelled on shutdown, stops the servers and background loops
+    pub(crate) shutdown: CancellationToken,
+}
+
+impl Tranzit {
+    // Load or create the identity in the data directory and set up an instance
+    pub fn new(config: TranzitConfig, events: Arc<dyn EventSink>) -> Result<Self, String> {
+        let identity = identity::load_or_create(&config.data_dir)?;
+        let paired_devices = PairedDevices::load(&config.data_dir);
+        let settings = SettingsStore::load(&config.data_dir);
+        let rate_limits = RateLimits::new(&settings.get());
+        let history = TransferHistory::load(&config.data_dir);
+        let https_port = config.https_port;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                config,
+                identity,
+                events,
+                paired_devices,
+                settings,
+                rate_limits,
+                history,
+                devices: Default::default(),
+                pending_requests: Default::default(),
+                pairings: Default::default(),
+                approved_transfers: Default::default(),
+                transfers: Default::default(),
+                mdns: Default::default(),
+                https_port: AtomicU16::new(https_port),
+                shutdown: CancellationToken::new(),
+            }),
+        })
+    }
+
+    pub fn config(&self) -> &TranzitConfig {
+        &self.inner.config
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.inner.identity
+    }
+
+    pub fn paired_devices(&self) -> &PairedDevices {
+        &self.inner.paired_devices
+    }
+
+    pub fn settings(&self) -> &SettingsStore {
+        &self.inner.settings
+    }
+
+    // Port peers reach this instance on, e.g. when the server picked a free one
+    pub fn https_port(&self) -> u16 {
+        self.inner.https_port.load(Ordering::Relaxed)
+    }
+
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.inner.rate_limits
+    }
+
+    pub fn transfer_history(&self) -> &TransferHistory {
+        &self.inner.history
+    }
+
+    // Stop the https server, heartbeats and mDNS daemon of this instance
+    pub fn shutdown(&self) {
+        self.inner.shutdown.cancel();
+        self.shutdown_mdns();
+    }
+
+    // Emit an event to the sink of this instance
+    pub(crate) fn emit<S: Serialize>(&self, event: &str, payload: S) {
+        match serde_json::to_value(payload) {
+            Ok(payload) => self.inner.events.emit(event, payload),
+            Err(e) => debug_log!("Failed to serialize {} event: {}", event, e),
+        }
+    }
+}
@@ -1,39 +1,10 @@
-use crate::debug_log;
+use crate::http_requests;
 use base64::{engine::general_purpose, Engine as _};
-use if_addrs::get_if_addrs;
 use image::{codecs::jpeg::JpegEncoder, DynamicImage, ExtendedColorType, ImageReader};
-use infer;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
-use std::net::IpAddr;
 use std::{fs, path::PathBuf};
-
-// Get primary ipv4
-// TODO: Get all local ips and do a mdns discovery on all local ips instead of just the first found
-// address (should virtual interfaces be ignored? is there a reliable way to detect virtual
-// interfaces?)
-#[tauri::command]
-pub fn get_primary_ipv4() -> Result<IpAddr, String> {
-    let interfaces = get_if_addrs().map_err(|e| format!("Failed to get interfaces: {}", e))?;
-
-    for iface in interfaces {
-        if iface.is_loopback() {
-            continue;
-        }
-
-        if let IpAddr::V4(ipv4) = iface.ip() {
-            let octets = ipv4.octets();
-            if octets[0] == 169 && octets[1] == 254 {
-                continue; // Skip link-local IPs
-            }
-
-            debug_log!("✅ Chosen interface: {} | IP: {}", iface.name, ipv4);
-            return Ok(IpAddr::V4(ipv4));
-        }
-    }
-
-    Err("No suitable non-loopback IPv4 address found.".into())
-}
+use uuid::Uuid;
 
 #[derive(Serialize)]
 pub struct FilePreview {
@@ -43,6 +14,8 @@ pub struct FilePreview {
     pub size: u64,
     pub mime_type: String,
     pub preview_base64: Option<String>,
+    // set for files inside a selected folder, e.g. "project/src/main.rs"
+    pub relative_path: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -60,53 +33,77 @@ pub async fn get_file_infos_with_previews(
     let mut result = Vec::new();
 
     for input in paths {
-        let file_uuid = input.file_uuid.clone();
-        let path_str = input.file_path;
-        let path = PathBuf::from(&path_str);
-        let name = path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("Unknown")
-            .to_string();
-
-        let metadata = fs::metadata(&path)
-            .map_err(|e| format!("Failed to get metadata for {}: {}", name, e))?;
-        let size = metadata.len();
-
-        let mime_type = infer::get_from_path(&path)
-            .ok()
-            .flatten()
-            .map(|kind| kind.mime_type().to_string())
-            .or_else(|| {
-                mime_guess::from_path(&path)
-                    .first()
-                    .map(|m| m.essence_str().to_string())
-            })
-            .unwrap_or_else(|| "application/octet-stream".to_string());
-
-        let preview_base64 = if mime_type.starts_with("image/") {
-            ImageReader::open(&path)
-                .ok()
-                .and_then(|reader| reader.decode().ok())
-                .map(|image| generate_base64_thumbnail(&image))
-                .transpose()?
+        let path = PathBuf::from(&input.file_path);
+
+        // a selected folder is sent as all the files in it. Those aren't given
+        // thumbnails, a folder of photos would be decoded in full before it's listed.
+        if path.is_dir() {
+            for (file_path, relative_path) in http_requests::files_in_dir(&path)? {
+                result.push(file_preview(
+                    file_path.to_string_lossy().to_string(),
+                    Uuid::new_v4().to_string(),
+                    Some(relative_path),
+                    false,
+                )?);
+            }
         } else {
-            None
-        };
-
-        result.push(FilePreview {
-            file_path: path_str,
-            name,
-            size,
-            mime_type,
-            preview_base64,
-            file_uuid,
-        });
+            result.push(file_preview(input.file_path, input.file_uuid, None, true)?);
+        }
     }
 
     Ok(result)
 }
 
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+fn file_preview(
+    path_str: String,
+    file_uuid: String,
+    relative_path: Option<String>,
+    with_thumbnail: bool,
+) -> Result<FilePreview, String> {
+    let path = PathBuf::from(&path_str);
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("Unknown")
+        .to_string();
+
+    let metadata =
+        fs::metadata(&path).map_err(|e| format!("Failed to get metadata for {}: {}", name, e))?;
+    let size = metadata.len();
+
+    let mime_type = infer::get_from_path(&path)
+        .ok()
+        .flatten()
+        .map(|kind| kind.mime_type().to_string())
+        .or_else(|| {
+            mime_guess::from_path(&path)
+                .first()
+                .map(|m| m.essence_str().to_string())
+        })
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let preview_base64 = if with_thumbnail && mime_type.starts_with("image/") {
+        ImageReader::open(&path)
+            .ok()
+            .and_then(|reader| reader.decode().ok())
+            .map(|image| generate_base64_thumbnail(&image))
+            .transpose()?
+    } else {
+        None
+    };
+
+    Ok(FilePreview {
+        file_path: path_str,
+        name,
+        size,
+        mime_type,
+        preview_base64,
+        file_uuid,
+        relative_path,
+    })
+}
+
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
 fn generate_base64_thumbnail(image: &DynamicImage) -> Result<String, String> {
     let thumbnail = image.thumbnail(200, 200);
@@ -9,7 +9,9 @@ use uuid::Uuid;
 pub async fn get_file_infos_with_previews(
     app: tauri::AppHandle,
 ) -> Result<Vec<FilePreview>, String> {
-    use tauri_plugin_android_fs::{AndroidFsExt, ImageFormat as FsImageFormat, Size};
+    use tauri_plugin_android_fs::{
+        AndroidFsExt, FileAccessMode, ImageFormat as FsImageFormat, Size,
+    };
 
     let app = app.clone(); // clone app handle to own it
 
@@ -54,20 +56,23 @@ pub async fn get_file_infos_with_previews(
             .unwrap_or_default()
         };
 
-        let bytes = {
+        // size comes from the file metadata, reading the whole file just for it
+        // would not work for files larger than the free memory
+        let size = {
             let app = app.clone();
             let uri = uri.clone();
             tauri::async_runtime::spawn_blocking(move || {
                 let fs = app.android_fs();
-                fs.read(&uri)
+                fs.open_file(&uri, FileAccessMode::Read)
+                    .map_err(|e| format!("FS error: {e}"))?
+                    .metadata()
+                    .map(|metadata| metadata.len())
+                    .map_err(|e| format!("File metadata error: {e}"))
             })
             .await
-            .map_err(|e| format!("Task join error: {e}"))?
-            .map_err(|e| format!("FS error: {e}"))?
+            .map_err(|e| format!("Task join error: {e}"))??
         };
 
-        let size = bytes.len() as u64;
-
         let preview_base64 = if mime.starts_with("image/") {
             let app = app.clone();
             let uri = uri.clone();
@@ -100,6 +105,7 @@ pub async fn get_file_infos_with_previews(
             size,
             mime_type: mime,
             preview_base64,
+            relative_path: None,
         });
     }
 
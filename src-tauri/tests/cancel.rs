@@ -0,0 +1,136 @@
+// Transfers cancelled by either side
+mod common;
+
+use common::{answer_requests, instance, TempDir};
+use reqwest::{Client, StatusCode};
+use std::time::Duration;
+use tranzit_lib::http_requests::{
+    AcceptedTransfer, DeviceInfo, FileUploadInfo, TransferResponse, TransferStatus,
+};
+use tranzit_lib::Tranzit;
+use uuid::Uuid;
+
+fn upload_info(dir: &TempDir) -> FileUploadInfo {
+    let path = dir.0.join("cancelled.txt");
+    std::fs::write(&path, b"never arrives").unwrap();
+    FileUploadInfo {
+        file_path: path.to_string_lossy().to_string(),
+        file_uuid: Uuid::new_v4().to_string(),
+        name: "cancelled.txt".into(),
+        relative_path: None,
+    }
+}
+
+async fn request(sender: &Tranzit, port: u16, file: &FileUploadInfo) -> AcceptedTransfer {
+    let device = DeviceInfo {
+        hostname: "sender".into(),
+        os_type: "test".into(),
+    };
+    let response = sender
+        .file_transfer_request("127.0.0.1", port, std::slice::from_ref(file), device, None)
+        .await
+        .unwrap();
+    let TransferResponse::Accepted(transfer) = response else {
+        panic!("transfer request was rejected");
+    };
+
+    transfer
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sender_stops_once_the_receiver_cancelled() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (sender, mut sender_events) = instance(&sender_dir.0);
+    let (receiver, receiver_events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    answer_requests(
+        &receiver,
+        receiver_events,
+        "file-transfer-request",
+        "accepted",
+    );
+
+    let file = upload_info(&sender_dir);
+    let transfer = request(&sender, port, &file).await;
+    receiver
+        .cancel_transfer(&transfer.transfer_id)
+        .await
+        .unwrap();
+
+    // the receiver answers 410 Gone, which isn't retried
+    let result = tokio::time::timeout(
+        Duration::from_secs(30),
+        sender.upload_files(vec![file], "127.0.0.1".into(), port, transfer, None),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.status, TransferStatus::Cancelled);
+    assert_eq!(result.files[0].status, TransferStatus::Cancelled);
+    assert!(!receiver_dir.0.join("received/cancelled.txt").exists());
+
+    let mut cancelled = false;
+    while let Ok(event) = sender_events.try_recv() {
+        cancelled |= event.name == "transfer-cancelled";
+    }
+    assert!(cancelled, "the sender wasn't told");
+
+    receiver.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancel_route_takes_the_token_of_the_transfer() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (sender, _) = instance(&sender_dir.0);
+    let (receiver, receiver_events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    let seen = answer_requests(
+        &receiver,
+        receiver_events,
+        "file-transfer-request",
+        "accepted",
+    );
+
+    let file = upload_info(&sender_dir);
+    let transfer = request(&sender, port, &file).await;
+
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let base = format!("https://127.0.0.1:{}", port);
+    let cancel = |token: String| {
+        client
+            .post(format!("{}/cancel/{}", base, transfer.transfer_id))
+            .query(&[("token", token)])
+            .send()
+    };
+    let upload_offset = || {
+        client
+            .get(format!(
+                "{}/upload-offset/{}/{}",
+                base, transfer.transfer_id, file.file_uuid
+            ))
+            .query(&[("token", &transfer.token)])
+            .send()
+    };
+
+    // someone who doesn't know the token can't cancel it
+    let refused = cancel(Uuid::new_v4().simple().to_string()).await.unwrap();
+    assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+    assert_eq!(upload_offset().await.unwrap().status(), StatusCode::OK);
+
+    let cancelled = cancel(transfer.token.clone()).await.unwrap();
+    assert_eq!(cancelled.status(), StatusCode::OK);
+    // later uploads of it are refused, which stops the sender
+    assert_eq!(upload_offset().await.unwrap().status(), StatusCode::GONE);
+    assert!(seen
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|e| e.name == "transfer-cancelled"
+            && e.payload["transfer_id"] == transfer.transfer_id.as_str()));
+
+    receiver.shutdown();
+}
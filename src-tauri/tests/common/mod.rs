@@ -0,0 +1,61 @@
+// Helpers shared by the tests running several instances on localhost
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tranzit_lib::events::{ChannelSink, Event};
+use tranzit_lib::{Tranzit, TranzitConfig};
+use uuid::Uuid;
+
+// Scratch directory of a test, removed when dropped
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("tranzit-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn instance(dir: &Path) -> (Tranzit, UnboundedReceiver<Event>) {
+    let config = TranzitConfig {
+        download_dir: Some(dir.join("received")),
+        partials_dir: dir.join("partial"),
+        // any free port, the server tells which one it got
+        https_port: 0,
+        ..TranzitConfig::new(dir.join("data"))
+    };
+    std::fs::create_dir_all(dir.join("received")).unwrap();
+
+    let (sink, events) = ChannelSink::new();
+    (Tranzit::new(config, Arc::new(sink)).unwrap(), events)
+}
+
+// Answer every request sent as the given event, returns all events seen so far
+pub fn answer_requests(
+    tranzit: &Tranzit,
+    mut events: UnboundedReceiver<Event>,
+    request: &'static str,
+    answer: &'static str,
+) -> Arc<Mutex<Vec<Event>>> {
+    let seen: Arc<Mutex<Vec<Event>>> = Default::default();
+    let tranzit = tranzit.clone();
+    let collected = seen.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if event.name == request {
+                let id = event.payload["id"].as_str().unwrap().to_string();
+                tranzit.respond_to_request(&id, answer.to_string(), None, None);
+            }
+            collected.lock().unwrap().push(event);
+        }
+    });
+
+    seen
+}
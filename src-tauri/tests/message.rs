@@ -0,0 +1,107 @@
+// Text, links and images sent from one instance to another
+mod common;
+
+use common::{answer_requests, instance, TempDir};
+use reqwest::{Client, StatusCode};
+use tranzit_lib::http_requests::DeviceInfo;
+use tranzit_lib::messages::{Message, MessageKind, MAX_MESSAGE_SIZE};
+
+fn device() -> DeviceInfo {
+    DeviceInfo {
+        hostname: "sender".into(),
+        os_type: "test".into(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn accepted_message_reaches_the_receiver() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (sender, _) = instance(&sender_dir.0);
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    let seen = answer_requests(&receiver, events, "message-request", "accepted");
+
+    let message = Message::text(" https://example.com/a?b=c ", false);
+    let accepted = sender
+        .send_message("127.0.0.1", port, message, device(), None)
+        .await
+        .unwrap();
+    assert!(accepted);
+
+    // the receiver is shown what the sender decided the message is
+    let seen = seen.lock().unwrap();
+    let request = seen.iter().find(|e| e.name == "message-request").unwrap();
+    assert_eq!(request.payload["data"]["message"]["kind"], "url");
+    assert_eq!(
+        request.payload["data"]["message"]["content"],
+        "https://example.com/a?b=c"
+    );
+    assert_eq!(request.payload["data"]["sys_info"]["hostname"], "sender");
+
+    receiver.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejected_message_is_reported_to_the_sender() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (sender, _) = instance(&sender_dir.0);
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    answer_requests(&receiver, events, "message-request", "rejected");
+
+    let accepted = sender
+        .send_message(
+            "127.0.0.1",
+            port,
+            Message::text("hello", false),
+            device(),
+            None,
+        )
+        .await
+        .unwrap();
+    assert!(!accepted);
+
+    receiver.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_over_the_limit_are_refused() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (sender, _) = instance(&sender_dir.0);
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    let seen = answer_requests(&receiver, events, "message-request", "accepted");
+
+    let image = Message {
+        kind: MessageKind::Image,
+        content: "A".repeat(MAX_MESSAGE_SIZE as usize),
+        clipboard: false,
+    };
+    // the sender doesn't even try
+    let sent = sender
+        .send_message("127.0.0.1", port, image.clone(), device(), None)
+        .await;
+    assert!(sent.is_err());
+
+    // and the receiver refuses it before reading it all when someone does
+    let response = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap()
+        .post(format!("https://127.0.0.1:{}/message", port))
+        .json(&serde_json::json!({ "message": image, "sys_info": device() }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(!seen
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|e| e.name == "message-request"));
+
+    receiver.shutdown();
+}
@@ -0,0 +1,129 @@
+// Two instances on localhost pairing with each other
+mod common;
+
+use common::{answer_requests, instance, TempDir};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pairs_once_both_users_confirm() {
+    let initiator_dir = TempDir::new();
+    let responder_dir = TempDir::new();
+    let (initiator, initiator_events) = instance(&initiator_dir.0);
+    let (responder, responder_events) = instance(&responder_dir.0);
+
+    // the responder connects back to the initiator to check its certificate
+    initiator.start_http_server().await.unwrap();
+    let port = responder.start_http_server().await.unwrap().port();
+
+    let initiator_seen = answer_requests(&initiator, initiator_events, "pair-request", "accepted");
+    let responder_seen = answer_requests(&responder, responder_events, "pair-request", "accepted");
+
+    initiator.pair_device("127.0.0.1", port).await.unwrap();
+
+    // both pinned the certificate of the other
+    let pinned = |tranzit: &tranzit_lib::Tranzit, other: &tranzit_lib::Tranzit| {
+        tranzit
+            .paired_devices()
+            .pinned_fingerprint(&other.identity().device_id)
+    };
+    assert_eq!(
+        pinned(&initiator, &responder),
+        Some(responder.identity().fingerprint.clone())
+    );
+    assert_eq!(
+        pinned(&responder, &initiator),
+        Some(initiator.identity().fingerprint.clone())
+    );
+
+    // and showed their users the same code
+    let code = |seen: &[tranzit_lib::events::Event]| {
+        seen.iter()
+            .find(|e| e.name == "pair-request")
+            .map(|e| e.payload["code"].as_str().unwrap().to_string())
+    };
+    let initiator_code = code(&initiator_seen.lock().unwrap());
+    assert!(initiator_code.is_some());
+    assert_eq!(initiator_code, code(&responder_seen.lock().unwrap()));
+
+    initiator.shutdown();
+    responder.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nothing_is_pinned_when_either_user_rejects() {
+    for (initiator_answer, responder_answer) in [("rejected", "accepted"), ("accepted", "rejected")]
+    {
+        let initiator_dir = TempDir::new();
+        let responder_dir = TempDir::new();
+        let (initiator, initiator_events) = instance(&initiator_dir.0);
+        let (responder, responder_events) = instance(&responder_dir.0);
+
+        initiator.start_http_server().await.unwrap();
+        let port = responder.start_http_server().await.unwrap().port();
+
+        answer_requests(
+            &initiator,
+            initiator_events,
+            "pair-request",
+            initiator_answer,
+        );
+        answer_requests(
+            &responder,
+            responder_events,
+            "pair-request",
+            responder_answer,
+        );
+
+        assert!(initiator.pair_device("127.0.0.1", port).await.is_err());
+        assert!(initiator.paired_devices().list().is_empty());
+        if responder_answer == "rejected" {
+            assert!(responder.paired_devices().list().is_empty());
+        }
+
+        initiator.shutdown();
+        responder.shutdown();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn claimed_fingerprints_have_to_be_presented() {
+    let victim_dir = TempDir::new();
+    let mallory_dir = TempDir::new();
+    let responder_dir = TempDir::new();
+    let (victim, _victim_events) = instance(&victim_dir.0);
+    let (mallory, _mallory_events) = instance(&mallory_dir.0);
+    let (responder, responder_events) = instance(&responder_dir.0);
+
+    let mallory_port = mallory.start_http_server().await.unwrap().port();
+    let port = responder.start_http_server().await.unwrap().port();
+    let responder_seen = answer_requests(&responder, responder_events, "pair-request", "accepted");
+
+    // mallory asks to be paired as the victim, but serves its own certificate
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response = client
+        .post(format!("https://127.0.0.1:{}/pair-request", port))
+        .json(&serde_json::json!({
+            "device_id": victim.identity().device_id,
+            "hostname": "victim",
+            "fingerprint": victim.identity().fingerprint,
+            "port": mallory_port,
+            "commitment": "0".repeat(64),
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert!(!response.status().is_success());
+    assert!(responder.paired_devices().list().is_empty());
+    // the user was never asked
+    assert!(!responder_seen
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|e| e.name == "pair-request"));
+
+    mallory.shutdown();
+    responder.shutdown();
+}
@@ -0,0 +1,231 @@
+// Uploads that break off and are resumed from where the receiver got to
+mod common;
+
+use common::{answer_requests, instance, TempDir};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tranzit_lib::http_requests::{AcceptedTransfer, DeviceInfo, FileUploadInfo, TransferResponse};
+use uuid::Uuid;
+
+const FILE_NAME: &str = "resumed.bin";
+const FILE_SIZE: usize = 64 * 1024;
+
+// A file the receiver accepted, uploaded by hand so uploads can break off anywhere
+struct Upload {
+    client: Client,
+    base: String,
+    transfer: AcceptedTransfer,
+    file_id: String,
+    contents: Vec<u8>,
+}
+
+impl Upload {
+    // Upload the bytes of the file from `start` to `end`, announcing the whole file
+    async fn send(&self, start: usize, end: usize) -> StatusCode {
+        let part = Part::bytes(self.contents[start..end].to_vec()).file_name(FILE_NAME);
+        let offset = start.to_string();
+        let size = self.contents.len().to_string();
+        self.client
+            .post(format!("{}/upload", self.base))
+            .query(&[
+                ("transfer_id", self.transfer.transfer_id.as_str()),
+                ("token", self.transfer.token.as_str()),
+                ("file_id", self.file_id.as_str()),
+                ("offset", offset.as_str()),
+                ("size", size.as_str()),
+            ])
+            .multipart(Form::new().part("file", part))
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+
+    async fn status(&self) -> Response {
+        self.client
+            .get(format!(
+                "{}/upload-offset/{}/{}",
+                self.base, self.transfer.transfer_id, self.file_id
+            ))
+            .query(&[("token", self.transfer.token.as_str())])
+            .send()
+            .await
+            .unwrap()
+    }
+
+    // Offset the receiver reports and whether it has the whole file
+    async fn offset(&self) -> (u64, bool) {
+        let status: serde_json::Value = self.status().await.json().await.unwrap();
+        (
+            status["offset"].as_u64().unwrap(),
+            status["complete"].as_bool().unwrap(),
+        )
+    }
+}
+
+fn client() -> Client {
+    Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap()
+}
+
+// Have the receiver accept a file sent from the sender directory
+async fn accepted_upload(sender_dir: &TempDir, port: u16) -> Upload {
+    let (sender, _) = instance(&sender_dir.0);
+    let contents: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    let path = sender_dir.0.join(FILE_NAME);
+    std::fs::write(&path, &contents).unwrap();
+
+    let file = FileUploadInfo {
+        file_path: path.to_string_lossy().to_string(),
+        file_uuid: Uuid::new_v4().to_string(),
+        name: FILE_NAME.into(),
+        relative_path: None,
+    };
+    let device = DeviceInfo {
+        hostname: "sender".into(),
+        os_type: "test".into(),
+    };
+    let response = sender
+        .file_transfer_request("127.0.0.1", port, std::slice::from_ref(&file), device, None)
+        .await
+        .unwrap();
+    let TransferResponse::Accepted(transfer) = response else {
+        panic!("transfer request was rejected");
+    };
+    assert!(transfer.accepts(&file.file_uuid));
+
+    Upload {
+        client: client(),
+        base: format!("https://127.0.0.1:{}", port),
+        transfer,
+        file_id: file.file_uuid,
+        contents,
+    }
+}
+
+fn received_hash(receiver_dir: &TempDir) -> String {
+    let bytes = std::fs::read(receiver_dir.0.join("received").join(FILE_NAME)).unwrap();
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn partial_path(receiver_dir: &TempDir, upload: &Upload) -> std::path::PathBuf {
+    receiver_dir
+        .0
+        .join("partial")
+        .join(&upload.transfer.transfer_id)
+        .join(format!("{}.part", upload.file_id))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn short_upload_is_resumed() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    answer_requests(&receiver, events, "file-transfer-request", "accepted");
+    let upload = accepted_upload(&sender_dir, port).await;
+
+    // the body ends halfway, which isn't taken for the whole file
+    let half = FILE_SIZE / 2;
+    assert_eq!(upload.send(0, half).await, StatusCode::CONFLICT);
+    assert_eq!(upload.offset().await, (half as u64, false));
+    assert!(partial_path(&receiver_dir, &upload).is_file());
+
+    assert_eq!(upload.send(half, FILE_SIZE).await, StatusCode::CREATED);
+    assert_eq!(
+        received_hash(&receiver_dir),
+        format!("{:x}", Sha256::digest(&upload.contents))
+    );
+
+    receiver.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_past_the_received_bytes_is_refused() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    answer_requests(&receiver, events, "file-transfer-request", "accepted");
+    let upload = accepted_upload(&sender_dir, port).await;
+
+    let quarter = FILE_SIZE / 4;
+    assert_eq!(upload.send(0, quarter).await, StatusCode::CONFLICT);
+    // a gap between what was received and the offset would go unnoticed otherwise
+    let resumed = upload.send(3 * quarter, 3 * quarter + 1024).await;
+    assert_eq!(resumed, StatusCode::CONFLICT);
+    assert_eq!(upload.offset().await, (quarter as u64, false));
+
+    receiver.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resume_from_an_earlier_offset_keeps_the_hash() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    answer_requests(&receiver, events, "file-transfer-request", "accepted");
+    let upload = accepted_upload(&sender_dir, port).await;
+
+    // the sender resumes before the end of what arrived, the rest is written again
+    // and what was kept is hashed along with it
+    let quarter = FILE_SIZE / 4;
+    assert_eq!(upload.send(0, 2 * quarter).await, StatusCode::CONFLICT);
+    assert_eq!(upload.send(quarter, FILE_SIZE).await, StatusCode::CREATED);
+    assert_eq!(
+        received_hash(&receiver_dir),
+        format!("{:x}", Sha256::digest(&upload.contents))
+    );
+
+    receiver.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn received_file_is_reported_complete() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    answer_requests(&receiver, events, "file-transfer-request", "accepted");
+    let upload = accepted_upload(&sender_dir, port).await;
+
+    assert_eq!(upload.send(0, FILE_SIZE).await, StatusCode::CREATED);
+    assert!(partial_path(&receiver_dir, &upload)
+        .with_extension("done")
+        .is_file());
+
+    // a sender that missed the response learns the file is in, and can't send it again
+    assert_eq!(upload.offset().await, (0, true));
+    assert_eq!(upload.send(0, 1024).await, StatusCode::FORBIDDEN);
+
+    receiver.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn partial_files_are_dropped_on_restart() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (receiver, events) = instance(&receiver_dir.0);
+    let port = receiver.start_http_server().await.unwrap().port();
+    answer_requests(&receiver, events, "file-transfer-request", "accepted");
+    let mut upload = accepted_upload(&sender_dir, port).await;
+
+    assert_eq!(upload.send(0, FILE_SIZE / 2).await, StatusCode::CONFLICT);
+    let partial = partial_path(&receiver_dir, &upload);
+    assert!(partial.is_file());
+    receiver.shutdown();
+
+    // approvals aren't kept across restarts, so nothing could resume the partial file
+    let (restarted, _events) = instance(&receiver_dir.0);
+    let port = restarted.start_http_server().await.unwrap().port();
+    assert!(!partial.exists());
+
+    upload.base = format!("https://127.0.0.1:{}", port);
+    assert_eq!(upload.status().await.status(), StatusCode::FORBIDDEN);
+
+    restarted.shutdown();
+}
@@ -0,0 +1,147 @@
+// Two instances on localhost, one sending files to the other
+mod common;
+
+use common::{answer_requests, instance, TempDir};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::Duration;
+use tranzit_lib::events::Event;
+use tranzit_lib::http_requests::{DeviceInfo, FileUploadInfo, TransferResponse, TransferStatus};
+use uuid::Uuid;
+
+// Bytes that don't compress, so uploads really carry their size
+fn write_file(path: &Path, size: usize) {
+    let mut state = size as u64 | 1;
+    let bytes: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, bytes).unwrap();
+}
+
+fn hash(path: &Path) -> String {
+    let bytes = std::fs::read(path).unwrap();
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn upload_info(path: &Path, relative_path: Option<&str>) -> FileUploadInfo {
+    FileUploadInfo {
+        file_path: path.to_string_lossy().to_string(),
+        file_uuid: Uuid::new_v4().to_string(),
+        name: path.file_name().unwrap().to_string_lossy().to_string(),
+        relative_path: relative_path.map(String::from),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_files_to_another_instance() {
+    let sender_dir = TempDir::new();
+    let receiver_dir = TempDir::new();
+    let (sender, mut sender_events) = instance(&sender_dir.0);
+    let (receiver, receiver_events) = instance(&receiver_dir.0);
+
+    let port = receiver.start_http_server().await.unwrap().port();
+
+    // a file uploaded on its own, one uploaded in chunks and a folder of small ones sent in a batch
+    let source = sender_dir.0.join("source");
+    let files = [
+        (source.join("single.bin"), None, 2 * 1024 * 1024),
+        (source.join("chunked.bin"), None, 40 * 1024 * 1024),
+        (source.join("folder/a.txt"), Some("folder/a.txt"), 1000),
+        (source.join("folder/b.txt"), Some("folder/b.txt"), 2000),
+    ];
+    for (path, _, size) in &files {
+        write_file(path, *size);
+    }
+    let uploads: Vec<FileUploadInfo> = files
+        .iter()
+        .map(|(path, relative_path, _)| upload_info(path, *relative_path))
+        .collect();
+
+    // the receiver approves whatever comes in
+    let seen = answer_requests(
+        &receiver,
+        receiver_events,
+        "file-transfer-request",
+        "accepted",
+    );
+
+    let device = DeviceInfo {
+        hostname: "sender".into(),
+        os_type: "test".into(),
+    };
+    let response = sender
+        .file_transfer_request("127.0.0.1", port, &uploads, device, None)
+        .await
+        .unwrap();
+    let TransferResponse::Accepted(transfer) = response else {
+        panic!("transfer request was rejected");
+    };
+    assert_eq!(transfer.files.as_ref().map(Vec::len), Some(files.len()));
+
+    // the files were hashed up to their last byte before the request went out
+    let total: usize = files.iter().map(|(_, _, size)| size).sum();
+    let mut hashed = None;
+    while let Ok(event) = sender_events.try_recv() {
+        if event.name == "hashing-progress" {
+            hashed = event.payload["bytes"].as_u64();
+        }
+    }
+    assert_eq!(hashed, Some(total as u64));
+
+    let result = tokio::time::timeout(
+        Duration::from_secs(120),
+        sender.upload_files(uploads, "127.0.0.1".into(), port, transfer, None),
+    )
+    .await
+    .unwrap();
+    assert!(
+        matches!(result.status, TransferStatus::Completed),
+        "transfer ended as {:?}",
+        result.status
+    );
+
+    let received = receiver_dir.0.join("received");
+    for (path, relative_path, _) in &files {
+        let target = match relative_path {
+            Some(relative_path) => received.join(relative_path),
+            None => received.join(path.file_name().unwrap()),
+        };
+        assert!(target.is_file(), "{} wasn't received", target.display());
+        assert_eq!(hash(&target), hash(path), "{} differs", target.display());
+    }
+
+    // the sink saw the request and the files coming in up to their last byte
+    let finished = |events: &[Event], name: &str, size: u64| {
+        events.iter().any(|e| {
+            e.name == "download-progress"
+                && e.payload["filename"] == name
+                && e.payload["bytes"] == size
+        })
+    };
+    for (path, _, size) in &files {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let mut waited = Duration::ZERO;
+        while !finished(&seen.lock().unwrap(), name, *size as u64) {
+            assert!(
+                waited < Duration::from_secs(10),
+                "no final progress of {}",
+                name
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            waited += Duration::from_millis(50);
+        }
+    }
+    assert!(seen
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|e| e.name == "file-transfer-request"));
+
+    receiver.shutdown();
+}
@@ -0,0 +1,41 @@
+// How a past transfer or one of its files ended up
+export type Outcome =
+  | { status: "in_progress" }
+  | { status: "completed" }
+  | { status: "failed"; reason: string }
+  | { status: "cancelled" }
+  | { status: "declined" };
+
+export type HistoryFile = Outcome & {
+  file_id: string;
+  name: string;
+  relative_path?: string | null;
+  size: number;
+  hash: string; // SHA-256 as lowercase hex
+  path?: string | null; // sent from or saved to
+};
+
+// A transfer sent or received, kept by the backend
+export type HistoryEntry = Outcome & {
+  transfer_id: string;
+  direction: "send" | "receive";
+  peer: {
+    device_id?: string | null;
+    hostname?: string | null;
+    ip?: string | null;
+  };
+  files: HistoryFile[];
+  started_at: number; // unix time in milliseconds
+  finished_at?: number | null;
+  duration_ms?: number | null;
+};
+
+// Filters for past transfers, everything matches when nothing is set
+export type HistoryQuery = {
+  search?: string | null; // file name or path, device name or id, or start of a hash
+  direction?: "send" | "receive" | null;
+  device_id?: string | null;
+  since?: number | null;
+  offset?: number;
+  limit?: number | null;
+};
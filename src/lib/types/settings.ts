@@ -0,0 +1,35 @@
+// Sends matching received files to another folder
+export type DestinationRule = {
+  device_id?: string | null; // any device when not set
+  mime_type?: string | null; // e.g. "image/*", any type when not set
+  dir: string;
+};
+
+// Speed limits for one device in bytes per second, no limit when not set
+export type PeerRateLimit = {
+  device_id: string;
+  upload_rate_limit?: number | null;
+  download_rate_limit?: number | null;
+};
+
+// Transfer requests of a trusted paired device are answered without asking,
+// accepted ones only when they fit the size and types
+export type TrustedDevice = {
+  device_id: string;
+  policy: "ask" | "accept" | "reject";
+  max_size?: number | null; // bytes in total, any size when not set
+  mime_types: string[]; // e.g. "image/*", any type when empty
+};
+
+// Settings stored by the backend, shared with the command line client
+export type Settings = {
+  receive_dir?: string | null;
+  destination_rules: DestinationRule[];
+  upload_concurrency: number; // files sent at the same time, the rest are queued
+  queue_order: "selection" | "smallest_first";
+  batch_small_files: boolean; // small files go out together over one connection
+  upload_rate_limit?: number | null; // bytes per second over all transfers
+  download_rate_limit?: number | null;
+  peer_rate_limits: PeerRateLimit[];
+  trusted_devices: TrustedDevice[];
+};
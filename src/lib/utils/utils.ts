@@ -18,6 +18,22 @@ export function calculateHumanReadableFileSize(
   return `${size.toFixed(decimals)} ${sizes[i]}`;
 }
 
+/**
+ * Convert a number of seconds into a short duration
+ * @param seconds - Number of seconds
+ * @returns         The short duration (example : 1m 5s)
+ */
+export function formatDuration(seconds: number): string {
+  const total = Math.ceil(seconds);
+  const hours = Math.floor(total / 3600);
+  const minutes = Math.floor((total % 3600) / 60);
+  const secs = total % 60;
+
+  if (hours > 0) return `${hours}h ${minutes}m`;
+  if (minutes > 0) return `${minutes}m ${secs}s`;
+  return `${secs}s`;
+}
+
 /**
  * Apply theme if one is already saved in localStorage,
  * else set it to default theme and colorscheme.
@@ -2,14 +2,33 @@ import type { SysInfo } from "$lib/types/sysInfo";
 
 export type Device = {
   name: string;
+  // best reachable address, `addresses` has every one the device was seen at
   ip: string;
+  addresses: string[];
   port: number;
   hostname: string;
   service_type: string;
   os: string;
   id: string;
+  // what the back-end tells the device apart by, see deviceKey
+  key?: string;
 };
 
+// Devices are told apart by their id, versions that don't advertise one only by the
+// address they were found at. Same as the key heartbeats report devices under.
+export function deviceKey(device: Device): string {
+  return device.id === "unknown" ? `${device.ip}:${device.port}` : device.id;
+}
+
+export type TransferStatus =
+  | "queued"
+  | "sending"
+  | "verifying"
+  | "completed"
+  | "failed"
+  | "cancelled"
+  | "declined"; // the receiver didn't pick the file
+
 export type SelectedFiles = {
   file_uuid: string;
   file_path: string;
@@ -18,9 +37,29 @@ export type SelectedFiles = {
   preview_base64?: string | null;
   mime_type: string;
   progress?: number;
+  status?: TransferStatus;
+  error?: string | null; // reason the upload failed
+  relative_path?: string | null; // set for files inside a selected folder
 };
 
-type FileInfo = { name: string; size: number };
+// progress of a whole transfer, sent a few times a second while it runs
+export type TransferProgress = {
+  transfer_id: string;
+  direction: "send" | "receive";
+  bytes: number;
+  total: number;
+  percent: number;
+  speed: number; // bytes per second
+  average_speed: number;
+  eta: number | null; // seconds left, unknown until something went through
+};
+
+export type FileInfo = {
+  name: string;
+  size: number;
+  relative_path?: string;
+  file_id: string;
+};
 
 type FileTransferRequestQueue = {
   id: string;
@@ -31,6 +70,29 @@ type FileTransferRequestQueue = {
   };
 } | null;
 
+// text, a link or an image sent instead of files, images are a base64 encoded png
+export type Message = {
+  kind: "text" | "url" | "image";
+  content: string;
+  clipboard: boolean; // copied on the sending device
+};
+
+type MessageRequest = {
+  id: string;
+  data: {
+    message: Message;
+    sys_info: SysInfo;
+  };
+} | null;
+
+type PairRequest = {
+  id: string;
+  device_id: string;
+  hostname: string;
+  code: string;
+  outgoing?: boolean; // set when this device started the pairing
+} | null;
+
 export const store = $state<{
   isFocused: boolean; // tracks whether main app window is focused or not
   areDevicesRefreshing: boolean;
@@ -38,10 +100,20 @@ export const store = $state<{
   showFileTransferRequestPopup: boolean;
   showTransferProgressPopup: boolean;
   showGenericPopup: boolean;
+  showPairRequestPopup: boolean;
+  pairRequest: PairRequest; // pending pairing request from another device
+  showMessageRequestPopup: boolean;
+  messageRequest: MessageRequest; // pending message from another device
+  messageDevice: Device | null; // device a message is being written to
   genericPopupMessage: string; // popup message for the generic popup
   fileTransferRequestQueue: FileTransferRequestQueue;
   waitingToAcceptTransferRequest: boolean;
   selectedFiles: SelectedFiles[];
+  sendingTransferId: string | null; // id of the transfer being sent, used to cancel it
+  sendingPaused: boolean; // queued files of the transfer being sent wait while paused
+  sendingProgress: TransferProgress | null;
+  receivingProgress: TransferProgress | null;
+  receivingFile: string | null; // name of the file coming in right now
   sysInfo: SysInfo;
 }>({
   isFocused: false,
@@ -50,9 +122,19 @@ export const store = $state<{
   showFileTransferRequestPopup: false,
   showTransferProgressPopup: false,
   showGenericPopup: false,
+  showPairRequestPopup: false,
+  pairRequest: null,
+  showMessageRequestPopup: false,
+  messageRequest: null,
+  messageDevice: null,
   genericPopupMessage: "",
   fileTransferRequestQueue: null,
   waitingToAcceptTransferRequest: false,
   selectedFiles: [],
+  sendingTransferId: null,
+  sendingPaused: false,
+  sendingProgress: null,
+  receivingProgress: null,
+  receivingFile: null,
   sysInfo: { hostname: "", os_type: "", app_id: "" },
 });